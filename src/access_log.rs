@@ -0,0 +1,333 @@
+//! access_log.rs
+//!
+//! Access log of the server.
+//! Every answered request is recorded in Common Log Format, Combined Log Format
+//! or as JSON lines, either on the standard output or in a file.
+//!
+//! Log files are rotated once they reach their maximum size: `access.log`
+//! becomes `access.log.1`, `access.log.1` becomes `access.log.2`, and so on.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::OnceCell;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::LogFormat;
+
+/// Access log parameters of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessLogParams {
+    /// File the lines are written to, the standard output if `None`
+    pub path: Option<PathBuf>,
+
+    /// Format of the lines
+    pub format: LogFormat,
+
+    /// Size of the file above which it is rotated
+    pub max_size: u64,
+
+    /// Number of rotated files kept
+    pub max_files: usize,
+}
+
+/// Destination of the access log.
+enum Output {
+    Stdout,
+    File { file: File, size: u64 },
+}
+
+/// The access log of the server.
+struct AccessLog {
+    params: AccessLogParams,
+    output: Mutex<Output>,
+}
+
+/// Access log of the running server, set once at startup.
+static ACCESS_LOG: OnceCell<AccessLog> = OnceCell::new();
+
+/// Information recorded about an answered request.
+struct Entry {
+    client_ip: Option<IpAddr>,
+    scheme: String,
+    host: Option<String>,
+    user: Option<String>,
+    request_id: Option<String>,
+    time: SystemTime,
+    method: String,
+    target: String,
+    version: String,
+    status: u16,
+    bytes: Option<usize>,
+    duration: Duration,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// Start recording the requests answered by the server.
+///
+/// # Arguments
+///
+/// * `params` - Access log parameters of the server.
+pub fn init(params: &AccessLogParams) -> io::Result<()> {
+    let output = match &params.path {
+        Some(path) => {
+            let file = open(path)?;
+            let size = file.metadata()?.len();
+            Output::File { file, size }
+        }
+        None => Output::Stdout,
+    };
+
+    let _ = ACCESS_LOG.set(AccessLog {
+        params: params.clone(),
+        output: Mutex::new(output),
+    });
+    Ok(())
+}
+
+/// Wrap a request handler so that each answered request is recorded.
+///
+/// The user is the one authenticated by an auth rule, or else the common name
+/// of the client certificate. The request ID is the `X-Request-Id` of the response.
+///
+/// # Arguments
+///
+/// * `respond` - Generates the response to each request.
+pub fn logged<F>(respond: F) -> impl Fn(Request) -> Response
+where
+    F: Fn(Request) -> Response,
+{
+    move |request: Request| {
+        let log = match ACCESS_LOG.get() {
+            Some(log) => log,
+            None => return respond(request),
+        };
+
+        let header = |name: &str| {
+            request
+                .headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.clone())
+        };
+
+        let time = SystemTime::now();
+        let start = Instant::now();
+        let common_name = request.peer_identity.as_ref().and_then(|id| id.common_name.clone());
+        let mut entry = Entry {
+            client_ip: request.client_ip(),
+            scheme: request.scheme(),
+            host: request.host(),
+            user: None,
+            request_id: None,
+            time,
+            method: request.method.to_str().to_string(),
+            target: request.target.clone(),
+            version: request.http_version.to_str().to_string(),
+            status: 0,
+            bytes: None,
+            duration: Duration::ZERO,
+            referer: header("Referer"),
+            user_agent: header("User-Agent"),
+        };
+
+        let response = respond(request);
+
+        // Streaming bodies are still unknown when the response starts
+        entry.user = response.user().map(str::to_string).or(common_name);
+        entry.request_id = response
+            .headers
+            .iter()
+            .find(|(k, _)| k == "X-Request-Id")
+            .map(|(_, v)| v.clone());
+        entry.status = response.status_code();
+        entry.bytes = (!response.is_streaming()).then(|| response.body().len());
+        entry.duration = start.elapsed();
+        log.record(&entry);
+
+        response
+    }
+}
+
+impl AccessLog {
+    /// Write the line of an entry, rotating the file if needed.
+    fn record(&self, entry: &Entry) {
+        let mut line = match self.params.format {
+            LogFormat::Common => common_line(entry),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\" \"{}\"",
+                common_line(entry),
+                escape_quoted(entry.referer.as_deref().unwrap_or("-")),
+                escape_quoted(entry.user_agent.as_deref().unwrap_or("-")),
+                escape_quoted(entry.request_id.as_deref().unwrap_or("-"))
+            ),
+            LogFormat::Json => json_line(entry),
+        };
+        line.push('\n');
+
+        let mut output = match self.output.lock() {
+            Ok(o) => o,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let result = match &mut *output {
+            Output::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Output::File { file, size } => {
+                if *size > 0 && *size + line.len() as u64 > self.params.max_size {
+                    match self.rotate() {
+                        Ok(new_file) => {
+                            *file = new_file;
+                            *size = 0;
+                        }
+                        Err(e) => tracing::error!("Error rotating access log: {}", e),
+                    }
+                }
+                *size += line.len() as u64;
+                file.write_all(line.as_bytes())
+            }
+        };
+
+        if let Err(e) = result {
+            tracing::error!("Error writing access log: {}", e);
+        }
+    }
+
+    /// Shift the rotated files, move the current one to `.1` and open a new one.
+    fn rotate(&self) -> io::Result<File> {
+        let path = match &self.params.path {
+            Some(p) => p,
+            None => return Err(io::Error::other("The access log is not a file")),
+        };
+
+        if self.params.max_files == 0 {
+            fs::remove_file(path)?;
+            return open(path);
+        }
+
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        let _ = fs::remove_file(rotated(self.params.max_files));
+        for n in (1..self.params.max_files).rev() {
+            let from = rotated(n);
+            if from.exists() {
+                fs::rename(&from, rotated(n + 1))?;
+            }
+        }
+        fs::rename(path, rotated(1))?;
+        open(path)
+    }
+}
+
+/// Open a log file for appending.
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Format an entry in Common Log Format.
+fn common_line(entry: &Entry) -> String {
+    format!(
+        "{} - {} [{}] \"{} {} {}\" {} {}",
+        entry.client_ip.map_or("-".to_string(), |ip| ip.to_string()),
+        entry.user.as_deref().map_or("-".to_string(), |u| u.replace(' ', "_")),
+        clf_time(entry.time),
+        entry.method,
+        escape_quoted(&entry.target),
+        entry.version,
+        entry.status,
+        entry.bytes.map_or("-".to_string(), |b| b.to_string())
+    )
+}
+
+/// Format an entry as a JSON object.
+fn json_line(entry: &Entry) -> String {
+    let string = |value: Option<&str>| value.map_or("null".to_string(), |v| format!("\"{}\"", escape_json(v)));
+
+    format!(
+        "{{\"time\":\"{}\",\"remote_addr\":{},\"user\":{},\"scheme\":\"{}\",\"host\":{},\"method\":\"{}\",\"target\":\"{}\",\"protocol\":\"{}\",\"status\":{},\"bytes_sent\":{},\"duration_ms\":{:.3},\"referer\":{},\"user_agent\":{},\"request_id\":{}}}",
+        rfc3339_time(entry.time),
+        string(entry.client_ip.map(|ip| ip.to_string()).as_deref()),
+        string(entry.user.as_deref()),
+        entry.scheme,
+        string(entry.host.as_deref()),
+        entry.method,
+        escape_json(&entry.target),
+        entry.version,
+        entry.status,
+        entry.bytes.map_or("null".to_string(), |b| b.to_string()),
+        entry.duration.as_secs_f64() * 1000.0,
+        string(entry.referer.as_deref()),
+        string(entry.user_agent.as_deref()),
+        string(entry.request_id.as_deref())
+    )
+}
+
+/// Escape a value written between double quotes in a log line.
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escape a value written as a JSON string.
+fn escape_json(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Split a time into its UTC date and time of day.
+///
+/// Returns the year, month, day, hours, minutes, seconds and milliseconds.
+fn utc_fields(time: SystemTime) -> (i64, u32, u32, u64, u64, u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86_400) as i64;
+    let seconds_of_day = secs % 86_400;
+
+    // Civil date from the number of days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        year,
+        month,
+        day,
+        seconds_of_day / 3_600,
+        seconds_of_day % 3_600 / 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// Format a time as in Common Log Format (e.g. "10/Oct/2000:13:55:36 +0000").
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hours, minutes, seconds, _) = utc_fields(time);
+    format!("{:02}/{}/{}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, hours, minutes, seconds)
+}
+
+/// Format a time as RFC 3339 in UTC (e.g. "2000-10-10T13:55:36.000Z").
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hours, minutes, seconds, millis) = utc_fields(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, hours, minutes, seconds, millis)
+}
@@ -0,0 +1,473 @@
+//! auth.rs
+//!
+//! Authentication of the clients, and the rules protecting the routes.
+//! Clients authenticate with HTTP Basic credentials checked against an
+//! htpasswd file of bcrypt or argon2 hashes, or with a Bearer token, static
+//! or a JWT.
+//!
+//! Each rule protects the targets under a prefix, for all methods or some of
+//! them (e.g. writes only). Requests without valid credentials are answered
+//! with `401 Unauthorized` and a `WWW-Authenticate` challenge per accepted
+//! scheme, and users a rule does not allow with `403 Forbidden`.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::jwt::{self, Claims, JwtParams};
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::{HTTPMethod, ServerParams};
+
+/// Authentication parameters of the server.
+#[derive(Clone)]
+pub struct AuthParams {
+    /// Realm announced in the challenges
+    pub realm: String,
+
+    /// File of the users and their password hashes, as `USER:HASH` lines
+    pub htpasswd: Option<PathBuf>,
+
+    /// Users and their password hashes, read from the htpasswd file
+    users: Vec<(String, String)>,
+
+    /// File of the Bearer tokens, as `NAME:TOKEN` lines
+    pub tokens_file: Option<PathBuf>,
+
+    /// Names and Bearer tokens, read from the tokens file
+    tokens: Vec<(String, String)>,
+
+    /// Validation of the JWTs sent as Bearer tokens
+    pub jwt: Option<JwtParams>,
+
+    /// Rules protecting the routes, the longest matching prefix wins
+    pub rules: Vec<AuthRule>,
+}
+
+// The hashes and tokens are kept out of debug output
+impl fmt::Debug for AuthParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthParams")
+            .field("realm", &self.realm)
+            .field("htpasswd", &self.htpasswd)
+            .field("users", &self.users.len())
+            .field("tokens_file", &self.tokens_file)
+            .field("tokens", &self.tokens.len())
+            .field("jwt", &self.jwt)
+            .field("rules", &self.rules)
+            .finish()
+    }
+}
+
+/// Rule protecting the targets under a prefix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthRule {
+    /// Prefix of the targets of the rule (e.g. "/files/")
+    pub prefix: String,
+
+    /// Methods the rule applies to, all if empty
+    pub methods: Vec<String>,
+
+    /// Whether the targets are open to everyone, instead of authenticated users
+    pub public: bool,
+
+    /// Users allowed, every authenticated user if empty
+    pub users: Vec<String>,
+}
+
+impl AuthRule {
+    /// Parse a rule entry, e.g. `/files/,methods=POST|PUT,users=alice|bob`.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The prefix followed by its settings, separated by commas.
+    pub fn parse(entry: &str) -> Result<AuthRule, String> {
+        let invalid = |reason: &str| format!("Invalid --auth-rule entry {}: {}", entry, reason);
+
+        let mut settings = entry.split(',');
+        let prefix = settings.next().unwrap_or_default().trim();
+        if !prefix.starts_with('/') {
+            return Err(invalid("expected a /PREFIX first"));
+        }
+
+        let mut rule = AuthRule {
+            prefix: prefix.to_string(),
+            methods: Vec::new(),
+            public: false,
+            users: Vec::new(),
+        };
+
+        for setting in settings {
+            let (key, value) = setting.split_once('=').ok_or_else(|| invalid("expected KEY=VALUE settings"))?;
+            match key.trim() {
+                "methods" => {
+                    rule.methods = value.split('|').filter(|m| !m.is_empty()).map(str::to_ascii_uppercase).collect();
+                    if let Some(unknown) = rule.methods.iter().find(|m| HTTPMethod::from_str(m).is_none()) {
+                        return Err(invalid(&format!("unknown method {}", unknown)));
+                    }
+                }
+                "access" => {
+                    rule.public = match value {
+                        "public" => true,
+                        "authenticated" => false,
+                        _ => return Err(invalid("access must be public or authenticated")),
+                    }
+                }
+                "users" => rule.users = value.split('|').filter(|u| !u.is_empty()).map(str::to_string).collect(),
+                other => return Err(invalid(&format!("unknown setting {}", other))),
+            }
+        }
+
+        if rule.public && !rule.users.is_empty() {
+            return Err(invalid("users cannot be given for public access"));
+        }
+        Ok(rule)
+    }
+
+    /// Check if the rule applies to a request path and method.
+    fn matches(&self, path: &str, method: &str) -> bool {
+        path.starts_with(&self.prefix) && (self.methods.is_empty() || self.methods.iter().any(|m| m == method))
+    }
+}
+
+impl fmt::Display for AuthRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.prefix)?;
+        if !self.methods.is_empty() {
+            write!(f, ",methods={}", self.methods.join("|"))?;
+        }
+        write!(f, ",access={}", if self.public { "public" } else { "authenticated" })?;
+        if !self.users.is_empty() {
+            write!(f, ",users={}", self.users.join("|"))?;
+        }
+        Ok(())
+    }
+}
+
+impl AuthParams {
+    /// Build the authentication parameters, reading the htpasswd and tokens files.
+    ///
+    /// # Arguments
+    ///
+    /// * `realm` - Realm announced in the challenges.
+    /// * `htpasswd` - File of the users and their password hashes.
+    /// * `tokens_file` - File of the Bearer tokens.
+    /// * `jwt` - Validation of the JWTs.
+    /// * `rules` - Rules protecting the routes.
+    pub fn load(realm: String, htpasswd: Option<PathBuf>, tokens_file: Option<PathBuf>, jwt: Option<JwtParams>, rules: Vec<AuthRule>) -> Result<AuthParams, String> {
+        let users = match &htpasswd {
+            Some(path) => read_entries(path, "--htpasswd")?,
+            None => Vec::new(),
+        };
+        if let Some((user, _)) = users.iter().find(|(_, hash)| !is_supported_hash(hash)) {
+            return Err(format!("Unsupported password hash for {} in --htpasswd, only bcrypt and argon2 hashes are accepted", user));
+        }
+
+        let tokens = match &tokens_file {
+            Some(path) => read_entries(path, "--auth-tokens")?,
+            None => Vec::new(),
+        };
+
+        if htpasswd.is_none() && tokens_file.is_none() && jwt.is_none() && rules.iter().any(|rule| !rule.public) {
+            return Err("--auth-rule requires --htpasswd, --auth-tokens, --jwt-secret or --jwt-key".to_string());
+        }
+
+        Ok(AuthParams { realm, htpasswd, users, tokens_file, tokens, jwt, rules })
+    }
+
+    /// Get the rule applying to a request, if one matches.
+    fn rule(&self, request: &Request) -> Option<&AuthRule> {
+        let path = request.target.split('?').next().unwrap_or_default();
+        let method = request.method.to_str();
+        // The first of the rules with the longest prefix wins
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path, method))
+            .rev()
+            .max_by_key(|rule| rule.prefix.len())
+    }
+
+    /// Get the user named by the credentials of a request, with the claims of its JWT if it sent one.
+    fn authenticate(&self, request: &Request) -> Result<(String, Option<Claims>), Failure> {
+        let authorization = request.header("Authorization").ok_or(Failure::Missing)?;
+        let (scheme, credentials) = authorization.split_once(' ').ok_or(Failure::Missing)?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("Basic") && self.htpasswd.is_some() {
+            let decoded = STANDARD.decode(credentials).ok().and_then(|d| String::from_utf8(d).ok()).ok_or(Failure::Invalid)?;
+            let (user, password) = decoded.split_once(':').ok_or(Failure::Invalid)?;
+            match self.users.iter().find(|(name, _)| name == user) {
+                Some((_, hash)) if verify_password(password, hash) => Ok((user.to_string(), None)),
+                _ => {
+                    tracing::warn!("Invalid credentials for user {}", user);
+                    Err(Failure::Invalid)
+                }
+            }
+        } else if scheme.eq_ignore_ascii_case("Bearer") && (self.tokens_file.is_some() || self.jwt.is_some()) {
+            if let Some(jwt) = self.jwt.as_ref().filter(|_| jwt::is_jwt(credentials)) {
+                return match jwt.validate(credentials) {
+                    Ok((user, claims)) => Ok((user, Some(claims))),
+                    Err(reason) => {
+                        tracing::warn!("Invalid JWT: {}", reason);
+                        Err(Failure::InvalidToken)
+                    }
+                };
+            }
+            match self.tokens.iter().find(|(_, token)| constant_time_eq(token.as_bytes(), credentials.as_bytes())) {
+                Some((name, _)) => Ok((name.clone(), None)),
+                None => {
+                    tracing::warn!("Invalid Bearer token");
+                    Err(Failure::InvalidToken)
+                }
+            }
+        } else {
+            Err(Failure::Missing)
+        }
+    }
+
+    /// Get the `WWW-Authenticate` challenges of the accepted schemes.
+    fn challenges(&self, failure: Failure) -> Vec<String> {
+        let realm = self.realm.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut challenges = Vec::new();
+        if self.htpasswd.is_some() {
+            challenges.push(format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm));
+        }
+        if self.tokens_file.is_some() || self.jwt.is_some() {
+            challenges.push(match failure {
+                // Tell the client its token is the problem (RFC 6750, section 3)
+                Failure::InvalidToken => format!("Bearer realm=\"{}\", error=\"invalid_token\"", realm),
+                _ => format!("Bearer realm=\"{}\"", realm),
+            });
+        }
+        challenges
+    }
+}
+
+/// Reason why a request could not be authenticated.
+#[derive(Clone, Copy)]
+enum Failure {
+    /// No credentials of an accepted scheme
+    Missing,
+
+    /// Unknown user or wrong password
+    Invalid,
+
+    /// Unknown Bearer token, or invalid JWT
+    InvalidToken,
+}
+
+/// Wrap a request handler so that the requests are checked against the rules first.
+///
+/// Authenticated requests carry their user in `Request::user`, and the
+/// claims of their JWT in `Request::claims`.
+///
+/// # Arguments
+///
+/// * `respond` - Generates the response to each allowed request.
+pub fn guarded<F>(respond: F) -> impl Fn(Request) -> Response
+where
+    F: Fn(Request) -> Response,
+{
+    move |mut request: Request| {
+        let params = ServerParams::current();
+        let auth = &params.auth;
+        let rule = match auth.rule(&request) {
+            Some(rule) if !rule.public => rule,
+            _ => return respond(request),
+        };
+
+        let (user, claims) = match auth.authenticate(&request) {
+            Ok(authenticated) => authenticated,
+            Err(failure) => {
+                let mut response = Response::new(request, None, 401, None);
+                for challenge in auth.challenges(failure) {
+                    response.headers.push(("WWW-Authenticate".to_string(), challenge));
+                }
+                return response;
+            }
+        };
+
+        if !rule.users.is_empty() && !rule.users.contains(&user) {
+            tracing::warn!("User {} is not allowed by the rule of {}", user, rule.prefix);
+            return Response::new(request, None, 403, None);
+        }
+
+        request.user = Some(user);
+        request.claims = claims;
+        respond(request)
+    }
+}
+
+/// Read the `NAME:VALUE` lines of a credentials file, skipping blank lines and `#` comments.
+fn read_entries(path: &Path, option: &str) -> Result<Vec<(String, String)>, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Error reading {} file {}: {}", option, path.display(), e))?;
+
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for (number, line) in content.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !value.is_empty() => (name, value),
+            _ => return Err(format!("Invalid line {} of {} file {}: expected NAME:VALUE", number, option, path.display())),
+        };
+        if entries.iter().any(|(n, _)| n == name) {
+            return Err(format!("Duplicate entry for {} in {} file {}", name, option, path.display()));
+        }
+        entries.push((name.to_string(), value.to_string()));
+    }
+    Ok(entries)
+}
+
+/// Check if a password hash is in a supported format, bcrypt or argon2.
+fn is_supported_hash(hash: &str) -> bool {
+    let bcrypt = ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|p| hash.starts_with(p)) && hash.len() == 60;
+    bcrypt || (hash.starts_with("$argon2") && PasswordHash::new(hash).is_ok())
+}
+
+/// Check a password against its bcrypt or argon2 hash.
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Compare two secrets in a time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, target: &str, authorization: Option<&str>) -> Request {
+        let authorization = authorization.map_or(String::new(), |a| format!("Authorization: {}\r\n", a));
+        let buffer = format!("{} {} HTTP/1.1\r\nHost: a\r\n{}\r\n", method, target, authorization);
+        Request::from_bytes(buffer.as_bytes()).expect("valid request")
+    }
+
+    fn params(rules: &[&str]) -> AuthParams {
+        AuthParams {
+            realm: "test".to_string(),
+            htpasswd: Some(PathBuf::from("htpasswd")),
+            users: vec![("alice".to_string(), bcrypt::hash("secret", 4).unwrap())],
+            tokens_file: Some(PathBuf::from("tokens")),
+            tokens: vec![("ci".to_string(), "s3cr3t-token".to_string())],
+            jwt: None,
+            rules: rules.iter().map(|r| AuthRule::parse(r).unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn rule_entries() {
+        let rule = AuthRule::parse("/files/,methods=post|PUT,users=alice|bob").unwrap();
+        assert_eq!(rule.methods, ["POST", "PUT"]);
+        assert_eq!(rule.users, ["alice", "bob"]);
+        assert!(!rule.public);
+        assert_eq!(rule.to_string(), "/files/,methods=POST|PUT,access=authenticated,users=alice|bob");
+        assert!(AuthRule::parse("/public/,access=public").unwrap().public);
+
+        let malformed = [
+            "files/",
+            "",
+            "/files/,methods=PATCH",
+            "/files/,access=everyone",
+            "/files/,access=public,users=alice",
+            "/files/,owner=alice",
+            "/files/,methods",
+        ];
+        for entry in malformed {
+            assert!(AuthRule::parse(entry).is_err(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn longest_matching_prefix_wins() {
+        let auth = params(&["/,access=public", "/files/,methods=POST", "/files/", "/files/public/,access=public", "/admin"]);
+        let cases = [
+            ("GET", "/", Some("/")),
+            ("GET", "/files/a", Some("/files/")),
+            ("POST", "/files/a", Some("/files/")),
+            ("GET", "/files/public/a", Some("/files/public/")),
+            ("GET", "/files/public/a?x=/admin", Some("/files/public/")),
+            // The query string is not part of the path
+            ("GET", "/echo/a?/files/a", Some("/")),
+            // Prefixes are matched as written
+            ("GET", "/adminer", Some("/admin")),
+            ("GET", "/FILES/a", Some("/")),
+        ];
+        for (method, target, expected) in cases {
+            let rule = auth.rule(&request(method, target, None));
+            assert_eq!(rule.map(|r| r.prefix.as_str()), expected, "{} {}", method, target);
+        }
+
+        // Of the rules with the same prefix, the first one matching the method wins
+        let rule = auth.rule(&request("POST", "/files/a", None)).unwrap();
+        assert_eq!(rule.methods, ["POST"]);
+        let rule = auth.rule(&request("DELETE", "/files/a", None)).unwrap();
+        assert!(rule.methods.is_empty());
+
+        assert!(params(&["/files/"]).rule(&request("GET", "/echo/a", None)).is_none());
+    }
+
+    #[test]
+    fn credentials() {
+        let auth = params(&[]);
+        let basic = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        let cases = [
+            (Some(basic("alice:secret")), Ok("alice")),
+            (Some(basic("alice:secret").replace("Basic", "basic")), Ok("alice")),
+            (Some(basic("alice:wrong")), Err("invalid")),
+            (Some(basic("mallory:secret")), Err("invalid")),
+            (Some(basic("alice")), Err("invalid")),
+            (Some("Basic not-base64!".to_string()), Err("invalid")),
+            (Some("Bearer s3cr3t-token".to_string()), Ok("ci")),
+            (Some("Bearer s3cr3t-token2".to_string()), Err("invalid token")),
+            (Some("Bearer".to_string()), Err("missing")),
+            (Some("Digest username=alice".to_string()), Err("missing")),
+            (None, Err("missing")),
+        ];
+        for (authorization, expected) in cases {
+            let result = auth.authenticate(&request("GET", "/", authorization.as_deref()));
+            let result = match &result {
+                Ok((user, _)) => Ok(user.as_str()),
+                Err(Failure::Missing) => Err("missing"),
+                Err(Failure::Invalid) => Err("invalid"),
+                Err(Failure::InvalidToken) => Err("invalid token"),
+            };
+            assert_eq!(result, expected, "{:?}", authorization);
+        }
+
+        // Schemes without configured credentials are not accepted
+        let auth = AuthParams { htpasswd: None, tokens_file: None, ..params(&[]) };
+        assert!(matches!(auth.authenticate(&request("GET", "/", Some(&basic("alice:secret")))), Err(Failure::Missing)));
+        assert!(matches!(auth.authenticate(&request("GET", "/", Some("Bearer s3cr3t-token"))), Err(Failure::Missing)));
+    }
+
+    #[test]
+    fn password_hashes() {
+        let cases = [
+            (bcrypt::hash("secret", 4).unwrap(), true),
+            ("$2y$05$abcdefghijklmnopqrstuv".to_string(), false),
+            ("$apr1$salt$hash".to_string(), false),
+            ("{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(), false),
+            ("secret".to_string(), false),
+            ("$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$2e/Xj+Kw5QmvP5lXxB/7CA".to_string(), true),
+            ("$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$not base64!".to_string(), false),
+        ];
+        for (hash, supported) in cases {
+            assert_eq!(is_supported_hash(&hash), supported, "{}", hash);
+        }
+        assert!(constant_time_eq(b"token", b"token"));
+        assert!(!constant_time_eq(b"token", b"tokex"));
+        assert!(!constant_time_eq(b"token", b"token2"));
+    }
+}
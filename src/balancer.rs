@@ -0,0 +1,391 @@
+//! balancer.rs
+//!
+//! Load balancing of proxy routes across groups of upstream servers.
+//! Each request of a route mounting a group is sent to one of its servers,
+//! chosen in turn (round-robin), by fewest requests in progress
+//! (least-connections), or by a consistent hash of the client address or of
+//! a header, so that a client keeps reaching the same server.
+//!
+//! Servers failing several requests in a row are ejected for a while
+//! (passive health checking), and servers can be probed on a health path at
+//! regular intervals (active health checking). Requests only go to the
+//! servers considered healthy.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::request::Request;
+use crate::utils::ServerParams;
+
+/// Interval at which the groups are checked for due health probes.
+const PROBE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Strategy choosing the server of each request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// Each server in turn
+    RoundRobin,
+
+    /// The server with the fewest requests in progress
+    LeastConnections,
+
+    /// A server chosen by a consistent hash of the client address
+    IpHash,
+
+    /// A server chosen by a consistent hash of a request header, or of the client address without it
+    HeaderHash(String),
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::LeastConnections => write!(f, "least-connections"),
+            Strategy::IpHash => write!(f, "ip-hash"),
+            Strategy::HeaderHash(name) => write!(f, "header-hash:{}", name),
+        }
+    }
+}
+
+/// Group of upstream servers sharing the requests of proxy routes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamGroup {
+    /// Name of the group, used as upstream by the proxy routes
+    pub name: String,
+
+    /// Addresses of the servers, `HOST:PORT`
+    pub servers: Vec<String>,
+
+    /// Strategy choosing the server of each request
+    pub strategy: Strategy,
+
+    /// Failed requests in a row ejecting a server, 0 to never eject
+    pub max_fails: u32,
+
+    /// Time an ejected server stays out of the group
+    pub fail_timeout: Duration,
+
+    /// Path probed on each server, no active health checks if `None`
+    pub health_path: Option<String>,
+
+    /// Interval between two probes of each server
+    pub health_interval: Duration,
+}
+
+impl UpstreamGroup {
+    /// Parse a group entry, e.g. `backend,servers=127.0.0.1:9001|127.0.0.1:9002,strategy=least-connections`.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The group name followed by its settings, separated by commas.
+    pub fn parse(entry: &str) -> Result<UpstreamGroup, String> {
+        let invalid = |reason: &str| format!("Invalid --upstream-group entry {}: {}", entry, reason);
+
+        let mut settings = entry.split(',');
+        let name = settings.next().unwrap_or_default().trim().to_string();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid("expected a name made of letters, digits, - and _ first"));
+        }
+
+        let mut group = UpstreamGroup {
+            name,
+            servers: Vec::new(),
+            strategy: Strategy::RoundRobin,
+            max_fails: 3,
+            fail_timeout: Duration::from_secs(10),
+            health_path: None,
+            health_interval: Duration::from_secs(5),
+        };
+        let seconds = |value: &str| value.parse::<u64>().ok().filter(|s| *s > 0).map(Duration::from_secs);
+
+        for setting in settings {
+            let (key, value) = setting.split_once('=').ok_or_else(|| invalid("expected KEY=VALUE settings"))?;
+            match key.trim() {
+                "servers" => {
+                    group.servers = value.split('|').filter(|s| !s.is_empty()).map(str::to_string).collect();
+                    let valid = |server: &String| server.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+                    if let Some(server) = group.servers.iter().find(|s| !valid(s)) {
+                        return Err(invalid(&format!("expected HOST:PORT servers, got {}", server)));
+                    }
+                }
+                "strategy" => {
+                    group.strategy = match value {
+                        "round-robin" => Strategy::RoundRobin,
+                        "least-connections" => Strategy::LeastConnections,
+                        "ip-hash" => Strategy::IpHash,
+                        _ => match value.strip_prefix("header-hash:") {
+                            Some(header) if !header.is_empty() => Strategy::HeaderHash(header.to_string()),
+                            _ => return Err(invalid("strategy must be round-robin, least-connections, ip-hash or header-hash:HEADER")),
+                        },
+                    }
+                }
+                "max-fails" => group.max_fails = value.parse().map_err(|_| invalid("max-fails must be a number"))?,
+                "fail-timeout" => group.fail_timeout = seconds(value).ok_or_else(|| invalid("fail-timeout must be a number of seconds"))?,
+                "health-path" if value.starts_with('/') => group.health_path = Some(value.to_string()),
+                "health-path" => return Err(invalid("health-path must start with /")),
+                "health-interval" => group.health_interval = seconds(value).ok_or_else(|| invalid("health-interval must be a number of seconds"))?,
+                other => return Err(invalid(&format!("unknown setting {}", other))),
+            }
+        }
+
+        if group.servers.is_empty() {
+            return Err(invalid("servers=HOST:PORT|HOST:PORT... is required"));
+        }
+        Ok(group)
+    }
+}
+
+impl fmt::Display for UpstreamGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},servers={},strategy={},max-fails={},fail-timeout={}",
+            self.name,
+            self.servers.join("|"),
+            self.strategy,
+            self.max_fails,
+            self.fail_timeout.as_secs()
+        )?;
+        if let Some(path) = &self.health_path {
+            write!(f, ",health-path={},health-interval={}", path, self.health_interval.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// Health and load of a server.
+#[derive(Default)]
+struct ServerState {
+    /// Requests in progress
+    active: usize,
+
+    /// Failed requests in a row
+    fails: u32,
+
+    /// End of the ejection after too many failures
+    ejected_until: Option<Instant>,
+
+    /// Whether the last health probe failed
+    probe_failed: bool,
+}
+
+impl ServerState {
+    fn is_healthy(&self) -> bool {
+        !self.probe_failed && self.ejected_until.map_or(true, |until| Instant::now() >= until)
+    }
+}
+
+/// State of a group, kept across configuration reloads.
+#[derive(Default)]
+struct GroupState {
+    /// Turn of the next request, for round-robin
+    next: usize,
+
+    servers: HashMap<String, ServerState>,
+}
+
+/// State of the groups by name.
+static STATE: Lazy<Mutex<HashMap<String, GroupState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Server chosen for a request, counted as in progress until dropped.
+pub struct Lease {
+    group: String,
+
+    /// Address of the server
+    pub server: String,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut state = lock();
+        if let Some(server) = state.get_mut(&self.group).and_then(|g| g.servers.get_mut(&self.server)) {
+            server.active = server.active.saturating_sub(1);
+        }
+    }
+}
+
+/// Choose the server of a group answering a request.
+///
+/// Returns `None` when no healthy server is left.
+///
+/// # Arguments
+///
+/// * `group` - The group mounted by the route of the request.
+/// * `request` - The incoming HTTP request.
+/// * `excluded` - Servers already tried for this request.
+pub fn pick(group: &UpstreamGroup, request: &Request, excluded: &[String]) -> Option<Lease> {
+    let mut state = lock();
+    let group_state = state.entry(group.name.clone()).or_default();
+
+    let candidates = group
+        .servers
+        .iter()
+        .filter(|server| !excluded.contains(server))
+        .filter(|server| group_state.servers.get(*server).map_or(true, ServerState::is_healthy))
+        .collect::<Vec<&String>>();
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let active = |server: &String| group_state.servers.get(server).map_or(0, |s| s.active);
+    let server = match &group.strategy {
+        Strategy::RoundRobin => {
+            group_state.next = group_state.next.wrapping_add(1);
+            candidates[group_state.next % candidates.len()]
+        }
+        Strategy::LeastConnections => {
+            // Ties go to each server in turn
+            group_state.next = group_state.next.wrapping_add(1);
+            let start = group_state.next % candidates.len();
+            let rotated = candidates[start..].iter().chain(&candidates[..start]);
+            rotated.min_by_key(|server| active(server)).copied()?
+        }
+        Strategy::IpHash => rendezvous(&candidates, &client_key(request)),
+        Strategy::HeaderHash(name) => match request.header(name) {
+            Some(value) => rendezvous(&candidates, value),
+            None => rendezvous(&candidates, &client_key(request)),
+        },
+    }
+    .clone();
+
+    group_state.servers.entry(server.clone()).or_default().active += 1;
+    Some(Lease { group: group.name.clone(), server })
+}
+
+/// Record the outcome of a request sent to a server.
+///
+/// Failures in a row eject the server for the fail timeout of its group.
+///
+/// # Arguments
+///
+/// * `group` - The group of the server.
+/// * `server` - Address of the server.
+/// * `success` - Whether the server answered the request.
+pub fn report(group: &UpstreamGroup, server: &str, success: bool) {
+    let mut state = lock();
+    let server_state = state.entry(group.name.clone()).or_default().servers.entry(server.to_string()).or_default();
+
+    if success {
+        server_state.fails = 0;
+        return;
+    }
+
+    server_state.fails += 1;
+    if group.max_fails > 0 && server_state.fails >= group.max_fails {
+        tracing::warn!(
+            "Upstream server {} of {} ejected for {}s after {} failed requests",
+            server,
+            group.name,
+            group.fail_timeout.as_secs(),
+            server_state.fails
+        );
+        server_state.fails = 0;
+        server_state.ejected_until = Some(Instant::now() + group.fail_timeout);
+    }
+}
+
+/// Start probing the servers of the groups with a health path.
+///
+/// Servers failing a probe receive no requests until a later probe succeeds.
+/// Groups added or changed by a configuration reload are picked up.
+pub fn watch() {
+    thread::spawn(|| {
+        let mut last_probes: HashMap<String, Instant> = HashMap::new();
+        loop {
+            thread::sleep(PROBE_POLL_INTERVAL);
+
+            let params = ServerParams::current();
+            for group in &params.upstream.groups {
+                let path = match &group.health_path {
+                    Some(path) => path,
+                    None => continue,
+                };
+                if last_probes.get(&group.name).is_some_and(|last| last.elapsed() < group.health_interval) {
+                    continue;
+                }
+                last_probes.insert(group.name.clone(), Instant::now());
+
+                for server in &group.servers {
+                    let result = probe(server, path, params.upstream.connect_timeout);
+                    record_probe(group, server, result);
+                }
+            }
+        }
+    });
+}
+
+/// Record the result of a health probe, logging the changes of health.
+fn record_probe(group: &UpstreamGroup, server: &str, result: Result<(), String>) {
+    let mut state = lock();
+    let server_state = state.entry(group.name.clone()).or_default().servers.entry(server.to_string()).or_default();
+
+    match result {
+        Ok(()) if server_state.probe_failed => {
+            tracing::info!("Upstream server {} of {} is healthy again", server, group.name);
+            server_state.probe_failed = false;
+        }
+        Err(e) if !server_state.probe_failed => {
+            tracing::warn!("Upstream server {} of {} failed its health check: {}", server, group.name, e);
+            server_state.probe_failed = true;
+        }
+        _ => {}
+    }
+}
+
+/// Send a health probe to a server, healthy if it answers with a 2xx or 3xx status.
+fn probe(server: &str, path: &str, timeout: Duration) -> Result<(), String> {
+    let address = server
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| "no address".to_string())?;
+    let mut stream = TcpStream::connect_timeout(&address, timeout).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, server);
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).map_err(|e| e.to_string())?;
+    match status_line.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok()) {
+        Some(status) if (200..400).contains(&status) => Ok(()),
+        Some(status) => Err(format!("status {}", status)),
+        None => Err("invalid response".to_string()),
+    }
+}
+
+/// Get the hash key of the client of a request.
+fn client_key(request: &Request) -> String {
+    request.client_ip().map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+/// Choose the server with the highest hash of the key and its address.
+///
+/// A server leaving the candidates only moves the keys it was chosen for.
+fn rendezvous<'a>(candidates: &[&'a String], key: &str) -> &'a String {
+    candidates
+        .iter()
+        .max_by_key(|server| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            server.hash(&mut hasher);
+            hasher.finish()
+        })
+        .copied()
+        .unwrap_or(candidates[0])
+}
+
+/// Lock the state of the groups, even if a thread panicked while holding it.
+fn lock() -> std::sync::MutexGuard<'static, HashMap<String, GroupState>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,487 @@
+//! cgi.rs
+//!
+//! CGI routes, running programs to answer requests (RFC 3875).
+//! A route maps a path prefix to a directory of programs (e.g. `/cgi-bin/`
+//! to `/srv/cgi-bin`), or to a FastCGI application such as php-fpm. The
+//! request is described to the program in environment variables, its body
+//! is written to the standard input, and the response is read from the
+//! standard output: CGI headers, then the body, streamed to the client.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::fastcgi;
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::ServerParams;
+
+/// Longest head of CGI headers accepted from a program.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Interval at which a program whose output is closed is checked for its exit.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// CGI parameters of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgiParams {
+    /// Routes answered by programs, the longest matching prefix wins
+    pub routes: Vec<CgiRoute>,
+
+    /// Time given to a program to answer, or to a FastCGI application to send each read
+    pub timeout: Duration,
+}
+
+/// Program answering the requests of a CGI route.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CgiHandler {
+    /// Programs of a directory, run for each request
+    Programs(PathBuf),
+
+    /// FastCGI application at `HOST:PORT` or `unix:PATH`, with the directory of its scripts
+    FastCgi { address: String, root: Option<PathBuf> },
+}
+
+/// Path prefix answered by CGI programs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CgiRoute {
+    /// Prefix of the targets of the route (e.g. "/cgi-bin/")
+    pub prefix: String,
+
+    pub handler: CgiHandler,
+}
+
+impl CgiRoute {
+    /// Parse a `PREFIX=DIR` route of CGI programs.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The route, e.g. "/cgi-bin/=/srv/cgi-bin".
+    pub fn programs(entry: &str) -> Option<CgiRoute> {
+        let (prefix, dir) = entry.split_once('=')?;
+        if !prefix.starts_with('/') || dir.is_empty() {
+            return None;
+        }
+
+        Some(CgiRoute {
+            prefix: prefix.to_string(),
+            handler: CgiHandler::Programs(PathBuf::from(dir)),
+        })
+    }
+
+    /// Parse a `PREFIX=ADDRESS[,root=DIR]` route of a FastCGI application.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The route, e.g. "/php/=unix:/run/php-fpm.sock,root=/srv/php".
+    pub fn fastcgi(entry: &str) -> Option<CgiRoute> {
+        let (prefix, target) = entry.split_once('=')?;
+        let (address, root) = match target.split_once(",root=") {
+            Some((address, root)) if !root.is_empty() => (address, Some(PathBuf::from(root))),
+            Some(_) => return None,
+            None => (target, None),
+        };
+
+        let valid = match address.strip_prefix("unix:") {
+            Some(path) => !path.is_empty(),
+            None => address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()),
+        };
+        if !prefix.starts_with('/') || !valid {
+            return None;
+        }
+
+        Some(CgiRoute {
+            prefix: prefix.to_string(),
+            handler: CgiHandler::FastCgi { address: address.to_string(), root },
+        })
+    }
+}
+
+impl fmt::Display for CgiRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.handler {
+            CgiHandler::Programs(dir) => write!(f, "{}={}", self.prefix, dir.display()),
+            CgiHandler::FastCgi { address, root: Some(root) } => write!(f, "{}={},root={}", self.prefix, address, root.display()),
+            CgiHandler::FastCgi { address, root: None } => write!(f, "{}={}", self.prefix, address),
+        }
+    }
+}
+
+/// Script of a request, split from the rest of its path.
+pub struct Script {
+    /// Path of the script in the URL (e.g. "/cgi-bin/report.sh")
+    pub name: String,
+
+    /// Path following the script in the URL (e.g. "/2024/06"), empty if none
+    pub path_info: String,
+
+    /// Path of the script on the server, if known
+    pub filename: Option<PathBuf>,
+}
+
+/// Get the CGI route of a request target, if one matches.
+///
+/// # Arguments
+///
+/// * `target` - Target of the request (e.g. "/cgi-bin/report.sh?year=2024").
+pub fn route(target: &str) -> Option<CgiRoute> {
+    ServerParams::current()
+        .cgi
+        .routes
+        .iter()
+        .filter(|route| target.starts_with(&route.prefix))
+        .max_by_key(|route| route.prefix.len())
+        .cloned()
+}
+
+/// Answer a request with the program of its CGI route.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `route` - The CGI route matching the request target.
+pub fn respond(request: Request, route: &CgiRoute) -> Response {
+    match &route.handler {
+        CgiHandler::Programs(dir) => run(request, &route.prefix, dir),
+        CgiHandler::FastCgi { address, root } => fastcgi::respond(request, &route.prefix, address, root.as_deref()),
+    }
+}
+
+/// Find the script of a request path under a directory.
+///
+/// The script is the first existing regular file along the path, the rest
+/// of the path being its path info. Returns `None` if there is none or the
+/// path leaves the directory.
+///
+/// # Arguments
+///
+/// * `prefix` - Prefix of the route.
+/// * `path` - Path of the request, without the query.
+/// * `dir` - Directory of the scripts.
+pub fn find_script(prefix: &str, path: &str, dir: &Path) -> Option<Script> {
+    let base = dir.canonicalize().ok()?;
+    let relative = &path[prefix.len()..];
+
+    let mut candidate = base.clone();
+    let mut consumed = prefix.trim_end_matches('/').to_string();
+    let mut segments = relative.split('/').filter(|s| !s.is_empty()).peekable();
+
+    while let Some(segment) = segments.next() {
+        if segment == ".." || segment == "." {
+            tracing::warn!("Path traversal attempt: parent directory references not allowed");
+            return None;
+        }
+        candidate.push(segment);
+        consumed = format!("{}/{}", consumed, segment);
+
+        let canonical = candidate.canonicalize().ok()?;
+        if !canonical.starts_with(&base) {
+            tracing::warn!("Path traversal attempt: path escapes base directory");
+            return None;
+        }
+        if canonical.is_file() {
+            let rest = segments.collect::<Vec<&str>>();
+            return Some(Script {
+                name: consumed,
+                path_info: rest.iter().map(|s| format!("/{}", s)).collect(),
+                filename: Some(canonical),
+            });
+        }
+    }
+    None
+}
+
+/// Get the CGI environment of a request (RFC 3875, section 4.1).
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `script` - The script answering the request.
+pub fn environment(request: &Request, script: &Script) -> Vec<(String, String)> {
+    let query = request.target.split_once('?').map(|(_, q)| q).unwrap_or_default();
+    let scheme = request.scheme();
+    let host = request.host().unwrap_or_default();
+    let (server_name, server_port) = match host.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()) {
+        Some((name, port)) => (name.to_string(), port.to_string()),
+        None => (host.clone(), if scheme == "https" { "443" } else { "80" }.to_string()),
+    };
+    let remote_addr = request.client_ip().map(|ip| ip.to_string()).unwrap_or_default();
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+        ("SERVER_PROTOCOL", request.http_version.to_str().to_string()),
+        ("SERVER_NAME", server_name),
+        ("SERVER_PORT", server_port),
+        ("REQUEST_METHOD", request.method.to_str().to_string()),
+        ("REQUEST_URI", request.target.clone()),
+        ("REQUEST_SCHEME", scheme.clone()),
+        ("SCRIPT_NAME", script.name.clone()),
+        ("PATH_INFO", script.path_info.clone()),
+        ("QUERY_STRING", query.to_string()),
+        ("REMOTE_ADDR", remote_addr.clone()),
+        ("REMOTE_HOST", remote_addr),
+        ("CONTENT_LENGTH", request.body.len().to_string()),
+        // Needed by php-cgi to run scripts
+        ("REDIRECT_STATUS", "200".to_string()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect::<Vec<(String, String)>>();
+
+    if let Some(filename) = &script.filename {
+        env.push(("SCRIPT_FILENAME".to_string(), filename.display().to_string()));
+    }
+    if let Some(content_type) = request.header("Content-Type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+    if let Some((auth_type, _)) = request.header("Authorization").and_then(|a| a.split_once(' ')) {
+        env.push(("AUTH_TYPE".to_string(), auth_type.to_string()));
+    }
+    if let Some(user) = &request.user {
+        env.push(("REMOTE_USER".to_string(), user.clone()));
+    }
+    if scheme == "https" {
+        env.push(("HTTPS".to_string(), "on".to_string()));
+    }
+
+    // Other headers become HTTP_* variables, credentials are kept from the programs.
+    // `Proxy` would become HTTP_PROXY, read by HTTP clients as their proxy (httpoxy),
+    // and names with `_` could pass for the variable of another header.
+    for (name, value) in &request.headers {
+        if ["Content-Type", "Content-Length", "Authorization", "Proxy-Authorization", "Proxy"].iter().any(|h| h.eq_ignore_ascii_case(name))
+            || name.contains('_')
+        {
+            continue;
+        }
+        let variable = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match env.iter_mut().find(|(k, _)| *k == variable) {
+            Some((_, existing)) => existing.push_str(&format!(", {}", value)),
+            None => env.push((variable, value.clone())),
+        }
+    }
+    env
+}
+
+/// CGI headers of a program output.
+pub struct Head {
+    /// Status of the response, from the `Status` header or 302 for redirections
+    pub status: u16,
+
+    pub content_type: String,
+
+    /// Other headers, sent to the client
+    pub headers: Vec<(String, String)>,
+}
+
+/// Read the CGI headers of a program output, up to the empty line ending them.
+///
+/// # Arguments
+///
+/// * `output` - The output of the program.
+pub fn read_head(output: &mut impl BufRead) -> io::Result<Head> {
+    let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, format!("Invalid CGI response: {}", reason));
+
+    let mut headers = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let n = output.read_line(&mut line)?;
+        if n == 0 {
+            return Err(invalid("no end of headers"));
+        }
+        size += n;
+        if size > MAX_HEAD_SIZE {
+            return Err(invalid("headers too large"));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let header = |name: &str| headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+    let status = match header("Status") {
+        Some(status) => status
+            .split(' ')
+            .next()
+            .and_then(|s| s.parse::<u16>().ok())
+            .filter(|s| (200..600).contains(s))
+            .ok_or_else(|| invalid("invalid Status header"))?,
+        None if header("Location").is_some() => 302,
+        None => 200,
+    };
+    let content_type = header("Content-Type").unwrap_or("application/octet-stream".to_string());
+
+    // The framing of the body is chosen by the server
+    headers.retain(|(name, _)| !["Status", "Content-Type", "Content-Length", "Transfer-Encoding", "Connection"].iter().any(|h| h.eq_ignore_ascii_case(name)));
+    Ok(Head { status, content_type, headers })
+}
+
+/// Build the response from the output of a CGI program, once its headers are read.
+///
+/// The body is streamed to the client as the program writes it, then
+/// `finish` is called.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `head` - The CGI headers of the output.
+/// * `output` - The rest of the output, the body.
+/// * `finish` - Called once the body is copied, or the copy failed.
+pub fn stream_output<R, F>(request: Request, head: Head, mut output: R, finish: F) -> Response
+where
+    R: Read + Send + 'static,
+    F: FnOnce() + Send + 'static,
+{
+    let mut response = Response::streaming(request, &head.content_type, head.status, move |out| {
+        let copied = copy_flushing(&mut output, out);
+        finish();
+        copied
+    });
+    response.headers.extend(head.headers);
+    response
+}
+
+/// Copy a body, flushing the output after each read so that the client gets the data promptly.
+fn copy_flushing(input: &mut dyn Read, output: &mut dyn Write) -> io::Result<()> {
+    let mut buf = [0; 16 * 1024];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        output.write_all(&buf[..n])?;
+        output.flush()?;
+    }
+}
+
+/// Run the CGI program of a request.
+fn run(request: Request, prefix: &str, dir: &Path) -> Response {
+    let path = request.target.split('?').next().unwrap_or_default().to_string();
+    let script = match find_script(prefix, &path, dir) {
+        Some(script) => script,
+        None => return Response::new(request, None, 404, None),
+    };
+    let program = script.filename.clone().unwrap_or_default();
+
+    let executable = program.metadata().is_ok_and(|m| m.permissions().mode() & 0o111 != 0);
+    if !executable {
+        tracing::warn!("CGI script {} is not executable", program.display());
+        return Response::new(request, None, 403, None);
+    }
+
+    // The programs only see the CGI variables, and a usable PATH. They get
+    // their own process group, so that the processes they start are killed with them
+    let mut command = Command::new(&program);
+    command
+        .process_group(0)
+        .env_clear()
+        .envs(environment(&request, &script))
+        .env("PATH", std::env::var("PATH").unwrap_or("/usr/local/bin:/usr/bin:/bin".to_string()))
+        .current_dir(program.parent().unwrap_or(dir))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::error!("Error running CGI script {}: {}", program.display(), e);
+            return Response::new(request, None, 500, None);
+        }
+    };
+
+    // The body is written on its own thread, so that a program answering before reading it cannot block
+    if let Some(mut stdin) = child.stdin.take() {
+        let body = request.body.clone();
+        thread::spawn(move || {
+            let _ = stdin.write_all(&body);
+        });
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let name = script.name.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                tracing::warn!("CGI script {}: {}", name, line);
+            }
+        });
+    }
+
+    let mut stdout = match child.stdout.take() {
+        Some(stdout) => BufReader::new(stdout),
+        None => return Response::new(request, None, 500, None),
+    };
+    let running = Running::watch(child, script.name.clone(), ServerParams::current().cgi.timeout);
+
+    match read_head(&mut stdout) {
+        Ok(head) => stream_output(request, head, stdout, move || running.finish(false)),
+        Err(e) => {
+            tracing::error!("Error running CGI script {}: {}", script.name, e);
+            running.finish(true);
+            Response::new(request, None, 500, None)
+        }
+    }
+}
+
+/// A running CGI program, killed if it runs longer than the timeout.
+struct Running {
+    child: Arc<Mutex<Child>>,
+
+    /// Tells the watchdog that the program exited
+    done: mpsc::Sender<()>,
+}
+
+impl Running {
+    /// Start watching a program.
+    fn watch(child: Child, name: String, timeout: Duration) -> Running {
+        let child = Arc::new(Mutex::new(child));
+        let (done, exited) = mpsc::channel::<()>();
+
+        let watched = child.clone();
+        thread::spawn(move || {
+            if exited.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                tracing::warn!("CGI script {} timed out after {}s, killing it", name, timeout.as_secs());
+                kill(&mut watched.lock().unwrap_or_else(|e| e.into_inner()));
+            }
+        });
+
+        Running { child, done }
+    }
+
+    /// Wait for the program to exit, killing it first if `kill` is set.
+    fn finish(&self, kill: bool) {
+        loop {
+            let mut child = self.child.lock().unwrap_or_else(|e| e.into_inner());
+            if kill {
+                self::kill(&mut child);
+            }
+            // The lock is released while waiting, so that the watchdog can kill the program
+            if !matches!(child.try_wait(), Ok(None)) {
+                break;
+            }
+            drop(child);
+            thread::sleep(EXIT_POLL_INTERVAL);
+        }
+        let _ = self.done.send(());
+    }
+}
+
+/// Kill a program and the processes it started, unless it was already reaped.
+fn kill(child: &mut Child) {
+    if matches!(child.try_wait(), Ok(Some(_))) {
+        return;
+    }
+    // The process group of the program is named after it
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    let _ = child.kill();
+}
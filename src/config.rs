@@ -0,0 +1,108 @@
+//! config.rs
+//!
+//! Configuration file of the server.
+//! Options are read from a TOML file whose keys are the long names of the
+//! command line arguments (e.g. `port = 8080`, `tls-sni = ["HOST=CERT,KEY"]`).
+//!
+//! Each option is taken from the first source setting it:
+//! 1. the command line arguments,
+//! 2. the `HTTP_SERVER_*` environment variables (e.g. `HTTP_SERVER_PORT`),
+//! 3. the configuration file,
+//! 4. the default value.
+//!
+//! The configuration is reloaded on SIGHUP and when the file changes.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::utils::{Args, ServerParams};
+
+/// Interval at which reload signals and configuration file changes are checked.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Layer options over the ones of a configuration file.
+///
+/// Options set in `options` replace the ones of the file, list options
+/// included. Unknown keys and invalid values of the file are rejected.
+///
+/// # Arguments
+///
+/// * `options` - Options from the command line and environment, unset ones are `None`.
+/// * `path` - Path of the configuration file, if one is used.
+pub fn layered<T>(options: &T, path: Option<&Path>) -> Result<T, String>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let mut table = match path {
+        Some(path) => read::<T>(path)?,
+        None => toml::Table::new(),
+    };
+
+    let overrides = toml::Table::try_from(options).map_err(|e| format!("Invalid options: {}", e))?;
+    table.extend(overrides);
+    T::deserialize(table).map_err(|e| format!("Invalid options: {}", e.message()))
+}
+
+/// Render options as a configuration file.
+pub fn render<T: Serialize>(options: &T) -> Result<String, String> {
+    toml::to_string(options).map_err(|e| format!("Cannot render the configuration: {}", e))
+}
+
+/// Read the keys of a configuration file, checking them against the options.
+fn read<T: DeserializeOwned>(path: &Path) -> Result<toml::Table, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Cannot read configuration file {}: {}", path.display(), e))?;
+
+    // Parse the file on its own first, so errors point at its lines
+    toml::from_str::<T>(&content).map_err(|e| format!("Invalid configuration file {}: {}", path.display(), e))?;
+    toml::from_str::<toml::Table>(&content).map_err(|e| format!("Invalid configuration file {}: {}", path.display(), e))
+}
+
+/// Start reloading the configuration on SIGHUP and when its file changes.
+///
+/// The new configuration is validated first, the running one is kept if it
+/// is invalid. Options that need a restart keep their current value.
+pub fn watch() -> io::Result<()> {
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        let mut last = modified();
+        loop {
+            thread::sleep(RELOAD_POLL_INTERVAL);
+
+            let signaled = signals.pending().next().is_some();
+            let current = modified();
+            if !signaled && current == last {
+                continue;
+            }
+            last = current;
+
+            match Args::reload_params() {
+                Ok(params) => {
+                    let (params, ignored) = params.reloaded(&ServerParams::current());
+                    if !ignored.is_empty() {
+                        tracing::warn!("Changes to {} need a restart and were not applied", ignored.join(", "));
+                    }
+                    params.install();
+                    tracing::info!("Configuration reloaded");
+                }
+                Err(e) => tracing::error!("Error reloading configuration, keeping the previous one: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Get the last modification time of the configuration file, if one is used.
+fn modified() -> Option<SystemTime> {
+    let path = ServerParams::current().config_file.clone()?;
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
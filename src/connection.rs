@@ -0,0 +1,187 @@
+//! connection.rs
+//!
+//! Abstracts the client connections served by the threaded runtime,
+//! so the same handler serves plaintext and TLS connections, over TCP or
+//! Unix domain sockets.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use rustls::{ServerConnection, StreamOwned};
+
+use crate::tls::{self, PeerIdentity};
+
+/// A client connection.
+pub trait Connection: Read + Write {
+    /// Identity of the client certificate verified during the TLS handshake.
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        None
+    }
+
+    /// Check if the connection is encrypted.
+    fn is_secure(&self) -> bool {
+        false
+    }
+
+    /// Set how long reads wait for data before timing out.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Socket to poll before reading, `None` if data is already buffered and a read returns it at once.
+    fn poll_fd(&self) -> Option<RawFd>;
+
+    /// Address of the client, `None` over Unix domain sockets.
+    ///
+    /// Behind a proxy using the PROXY protocol, this is the address of the
+    /// client of the proxy.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+// Connections taken over by a route are borrowed, e.g. to serve HTTP/2 after an upgrade
+impl<C: Connection + ?Sized> Connection for &mut C {
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        (**self).peer_identity()
+    }
+
+    fn is_secure(&self) -> bool {
+        (**self).is_secure()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        (**self).poll_fd()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+}
+
+/// Socket of a client connection.
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// A socket accepted from one of the listeners.
+pub struct Stream {
+    socket: Socket,
+
+    /// Client address announced by a trusted proxy, replacing the socket peer
+    proxied_addr: Option<SocketAddr>,
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream { socket: Socket::Tcp(stream), proxied_addr: None }
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream { socket: Socket::Unix(stream), proxied_addr: None }
+    }
+}
+
+impl Stream {
+    /// Move the socket into or out of non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(s) => s.set_nonblocking(nonblocking),
+            Socket::Unix(s) => s.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Address of the other end of the socket, e.g. the proxy, `None` over Unix domain sockets.
+    pub fn socket_peer_addr(&self) -> Option<SocketAddr> {
+        match &self.socket {
+            // IPv4 clients of dual-stack listeners are reported with their IPv4 address
+            Socket::Tcp(s) => s.peer_addr().ok().map(|a| SocketAddr::new(a.ip().to_canonical(), a.port())),
+            Socket::Unix(_) => None,
+        }
+    }
+
+    /// Replace the peer address with the client address announced by a proxy.
+    pub fn set_proxied_addr(&mut self, addr: SocketAddr) {
+        self.proxied_addr = Some(addr);
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.socket {
+            Socket::Tcp(s) => s.read(buf),
+            Socket::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.socket {
+            Socket::Tcp(s) => s.write(buf),
+            Socket::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.socket {
+            Socket::Tcp(s) => s.flush(),
+            Socket::Unix(s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.socket {
+            Socket::Tcp(s) => s.as_raw_fd(),
+            Socket::Unix(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+impl Connection for Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(s) => s.set_read_timeout(timeout),
+            Socket::Unix(s) => s.set_read_timeout(timeout),
+        }
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.proxied_addr.or_else(|| self.socket_peer_addr())
+    }
+}
+
+impl Connection for StreamOwned<ServerConnection, Stream> {
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        tls::peer_identity(&self.conn)
+    }
+
+    fn is_secure(&self) -> bool {
+        true
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn poll_fd(&self) -> Option<RawFd> {
+        // Decrypted data waiting to be read stops rustls from wanting to read the socket
+        self.conn.wants_read().then(|| self.sock.as_raw_fd())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.sock.peer_addr()
+    }
+}
@@ -0,0 +1,480 @@
+//! event_loop.rs
+//!
+//! Event-driven runtime of the server.
+//! A single thread waits on an epoll instance (through `mio`) and serves every
+//! client socket in non-blocking mode, so the number of open connections is not
+//! bounded by the number of threads.
+//!
+//! Requests are parsed and answered with the same `Request`/`Response` code as
+//! the threaded runtime. Connections taken over by a route (e.g. WebSockets)
+//! leave the event loop and are served by their own blocking thread, and so do
+//! HTTP/2 connections, with prior knowledge or upgraded from HTTP/1.1, and
+//! requests to proxy and CGI routes, which wait on other servers or programs.
+//! At most `--max-thread-num` connections are on their own thread at once,
+//! the following ones are refused with a 503.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::event::Event;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+
+use crate::access_log;
+use crate::auth;
+use crate::cgi;
+use crate::connection::{Connection as _, Stream};
+use crate::http2;
+use crate::listener::Listener;
+use crate::logging;
+use crate::metrics;
+use crate::proxy;
+use crate::request::Request;
+use crate::response::{Response, Takeover};
+use crate::shutdown;
+use crate::upstream;
+use crate::utils::{HTTPVersion, RequestParseError, ServerParams, MAX_REQUEST_SIZE};
+
+/// Token of the waker used by the signal thread.
+const WAKER: Token = Token(0);
+
+/// Token of the first listening socket, client sockets use the tokens following the listeners.
+const FIRST_LISTENER: usize = 1;
+
+/// Interval between the checks for connections silent for longer than the keep-alive timeout.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of connections handed over to their own thread and still open.
+static DETACHED: AtomicUsize = AtomicUsize::new(0);
+
+/// State of a client connection handled by the event loop.
+struct Connection {
+    /// Client socket, in non-blocking mode
+    stream: Stream,
+
+    /// Bytes received but not yet consumed as a request
+    read_buf: Vec<u8>,
+
+    /// Bytes of the responses not yet written to the socket
+    write_buf: Vec<u8>,
+
+    /// Set once a response asked to close the connection
+    closing: bool,
+
+    /// Set when a route takes over the connection
+    takeover: Option<Takeover>,
+
+    /// Set until the PROXY header of a trusted proxy has been read
+    proxy_header: bool,
+
+    /// Set until the first bytes tell whether the client speaks HTTP/2
+    preface_pending: bool,
+
+    /// Length of the write buffer before the response to the last request
+    response_start: usize,
+
+    /// Time of the last readiness event, connections silent for too long are closed
+    last_active: Instant,
+
+    /// Counts the connection as active in the metrics
+    active: metrics::ActiveConnection,
+}
+
+impl Connection {
+    fn new(stream: Stream) -> Self {
+        Self {
+            proxy_header: stream.socket_peer_addr().is_some_and(|peer| proxy::is_trusted(&peer)),
+            stream,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            closing: false,
+            takeover: None,
+            preface_pending: true,
+            response_start: 0,
+            last_active: Instant::now(),
+            active: metrics::ActiveConnection::open(),
+        }
+    }
+
+    /// Handle a readiness event on the connection.
+    ///
+    /// Returns `true` when the connection is finished and must be dropped.
+    fn ready(&mut self, event: &Event) -> bool {
+        self.last_active = Instant::now();
+        if event.is_readable() && !self.closing {
+            match self.read() {
+                Ok(open) => {
+                    if !self.respond() {
+                        return true;
+                    }
+                    // The connection leaves the event loop, unless too many already did
+                    if self.takeover.is_some() {
+                        if DETACHED.load(Ordering::SeqCst) < ServerParams::current().pool.max_threads {
+                            return false;
+                        }
+                        tracing::warn!("Too many connections on their own thread, refusing connection");
+                        metrics::record_shed();
+                        self.takeover = None;
+
+                        // HTTP/2 clients would not understand an HTTP/1.1 response
+                        if self.preface_pending {
+                            return true;
+                        }
+                        self.write_buf.truncate(self.response_start);
+                        self.write_buf.extend_from_slice(&Response::rejected(HTTPVersion::Http1_1, 503).as_bytes());
+                        self.closing = true;
+                        self.read_buf.clear();
+                    }
+                    // The peer closed its side, flush what is left and stop
+                    if !open {
+                        self.closing = true;
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!("Error reading from stream: {:?}", e);
+                    return true;
+                }
+            }
+        }
+
+        if let Err(e) = self.write() {
+            tracing::debug!("Error writing to stream: {:?}", e);
+            return true;
+        }
+
+        self.closing && self.write_buf.is_empty()
+    }
+
+    /// Read everything available on the socket.
+    ///
+    /// Returns `false` if the peer closed the connection.
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.read_buf.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Answer every complete request held in the read buffer.
+    ///
+    /// Returns `false` if the connection must be dropped immediately.
+    fn respond(&mut self) -> bool {
+        // Trusted proxies give the address of their client first
+        if self.proxy_header {
+            match proxy::parse(&self.read_buf) {
+                Ok(Some((len, addr))) => {
+                    self.read_buf.drain(..len);
+                    if let Some(addr) = addr {
+                        self.stream.set_proxied_addr(addr);
+                    }
+                    self.proxy_header = false;
+                }
+                Ok(None) => return true,
+                Err(e) => {
+                    let peer = self.stream.socket_peer_addr().map_or("-".to_string(), |p| p.to_string());
+                    tracing::warn!("Invalid PROXY protocol header from {}: {}", peer, e);
+                    return false;
+                }
+            }
+        }
+
+        // HTTP/2 connections are served by their own thread
+        if self.preface_pending {
+            match http2::detect_preface(&self.read_buf) {
+                Some(true) => {
+                    self.takeover = Some(Box::new(|stream, buffer| http2::serve(stream, buffer, None, answer)));
+                    return true;
+                }
+                Some(false) => self.preface_pending = false,
+                None => return true,
+            }
+        }
+
+        loop {
+            self.response_start = self.write_buf.len();
+            let request_len = match Request::message_len(&self.read_buf) {
+                Ok(Some(len)) => len,
+                Ok(None) => break,
+                Err(e) => return self.reject(e),
+            };
+            let raw = self.read_buf.drain(..request_len).collect::<Vec<u8>>();
+
+            // Parse the received bytes into a Request object
+            let mut request: Request = match Request::from_bytes(&raw) {
+                Ok(r) => r,
+                Err(e) => return self.reject(e),
+            };
+
+            request.peer_addr = self.stream.peer_addr();
+
+            // Switch to HTTP/2 on request, the upgraded request is answered as stream 1
+            if let Some(settings) = http2::upgrade_settings(&request) {
+                self.write_buf.extend_from_slice(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n");
+                self.takeover = Some(Box::new(move |stream, buffer| http2::serve(stream, buffer, Some((request, settings)), answer)));
+                return true;
+            }
+
+            // Proxy and CGI routes block on other processes, they would stall every connection
+            if upstream::route(&request.target).is_some() || cgi::route(&request.target).is_some() {
+                self.takeover = Some(Box::new(move |stream, _| {
+                    let mut response = answer(request);
+                    response.close_connection();
+                    let written = stream
+                        .write_all(&response.as_bytes())
+                        .and_then(|_| stream.flush())
+                        .and_then(|_| response.write_streaming_body(stream));
+                    if let Err(e) = written {
+                        tracing::debug!("Error writing to stream: {:?}", e);
+                    }
+                }));
+                return true;
+            }
+
+            // Generate the response based on the request
+            let mut response: Response = answer(request);
+
+            // Do not keep the connection alive while the server is shutting down,
+            // nor after a streaming body, which is produced on its own thread
+            if shutdown::is_requested() || response.is_streaming() {
+                response.close_connection();
+            }
+            self.write_buf.extend_from_slice(&response.as_bytes());
+
+            if response.is_streaming() {
+                self.takeover = Some(Box::new(move |stream, _| {
+                    if let Err(e) = response.write_streaming_body(stream) {
+                        tracing::debug!("Error streaming response: {:?}", e);
+                    }
+                }));
+                return true;
+            }
+
+            // Stop parsing requests, the rest of the bytes belong to the route
+            if let Some(takeover) = response.takeover() {
+                self.takeover = Some(takeover);
+                return true;
+            }
+
+            // Stop reading requests once the connection is being closed
+            if response.closes_connection() {
+                self.closing = true;
+                self.read_buf.clear();
+                return true;
+            }
+        }
+
+        if self.read_buf.len() > MAX_REQUEST_SIZE {
+            tracing::warn!("Request too large");
+            return false;
+        }
+        true
+    }
+
+    /// Answer a request that could not be parsed, then close the connection.
+    ///
+    /// Returns `true`, the connection stays until the answer is written.
+    fn reject(&mut self, error: RequestParseError) -> bool {
+        tracing::warn!("Error parsing request: {:?}", error);
+        let response = Response::rejected(HTTPVersion::Http1_1, error.status_code());
+        self.write_buf.extend_from_slice(&response.as_bytes());
+        self.closing = true;
+        self.read_buf.clear();
+        true
+    }
+
+    /// Write as much of the pending responses as the socket accepts.
+    fn write(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve a connection taken over by a route on its own blocking thread.
+    ///
+    /// The pending response is written first, then the route receives the
+    /// socket and the bytes already read.
+    fn detach(self) -> io::Result<()> {
+        let takeover = match self.takeover {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        let mut stream = self.stream;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(crate::IDLE_POLL_INTERVAL))?;
+
+        let (write_buf, read_buf, active) = (self.write_buf, self.read_buf, self.active);
+        DETACHED.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            let _active = active;
+            if let Err(e) = stream.write_all(&write_buf) {
+                tracing::debug!("Error writing to stream: {:?}", e);
+            } else {
+                takeover(&mut stream, read_buf);
+            }
+            DETACHED.fetch_sub(1, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+
+    /// Check if the connection is waiting for a new request.
+    fn is_idle(&self) -> bool {
+        self.read_buf.is_empty() && self.write_buf.is_empty()
+    }
+}
+
+/// Answer a request through the same middleware as the threaded runtime.
+fn answer(request: Request) -> Response {
+    access_log::logged(logging::traced(metrics::observed(auth::guarded(Response::parse_request))))(request)
+}
+
+/// Serve connections accepted on `listeners` until a shutdown is requested.
+///
+/// Once a shutdown signal is received, the listeners are closed, idle
+/// connections are dropped and the others get until the deadline to finish.
+/// Returns `true` if every connection finished in time.
+///
+/// # Arguments
+///
+/// * `listeners` - The bound listeners of the server.
+/// * `handover_fds` - Listening sockets handed to a new process on upgrade.
+/// * `shutdown_timeout` - Time given to active connections on shutdown.
+pub fn run(listeners: Vec<Listener>, handover_fds: Vec<RawFd>, shutdown_timeout: Duration) -> io::Result<bool> {
+    let mut poll = Poll::new()?;
+    for (i, listener) in listeners.iter().enumerate() {
+        listener.set_nonblocking(true)?;
+        poll.registry().register(&mut SourceFd(&listener.as_raw_fd()), Token(FIRST_LISTENER + i), Interest::READABLE)?;
+    }
+    let mut next_token = FIRST_LISTENER + listeners.len();
+    let mut listeners = Some(listeners);
+
+    // Interrupt the poll when a shutdown signal is received
+    let waker = Waker::new(poll.registry(), WAKER)?;
+    shutdown::listen(handover_fds, move || { let _ = waker.wake(); })?;
+
+    let mut events = Events::with_capacity(1024);
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut last_idle_check = Instant::now();
+
+    loop {
+        let mut timeout = Some(IDLE_CHECK_INTERVAL);
+
+        // Close the connections silent for longer than the keep-alive timeout
+        if last_idle_check.elapsed() >= IDLE_CHECK_INTERVAL {
+            let keep_alive_timeout = ServerParams::current().keep_alive_timeout;
+            connections.retain(|_, c| c.last_active.elapsed() < keep_alive_timeout);
+            last_idle_check = Instant::now();
+        }
+
+        if shutdown::is_requested() {
+            // Stop accepting connections and drop the idle ones
+            if let Some(listeners) = listeners.take() {
+                for listener in &listeners {
+                    let _ = poll.registry().deregister(&mut SourceFd(&listener.as_raw_fd()));
+                }
+                tracing::info!("Waiting for active connections to finish");
+            }
+            connections.retain(|_, c| !c.is_idle());
+
+            // Detached connections close by themselves once the shutdown is requested
+            let detached = DETACHED.load(Ordering::SeqCst);
+            if connections.is_empty() && detached == 0 {
+                return Ok(true);
+            }
+
+            let deadline = shutdown::deadline(shutdown_timeout).unwrap_or_else(Instant::now);
+            let now = Instant::now();
+            if now >= deadline {
+                tracing::warn!("Shutdown deadline reached with {} active connections", connections.len() + detached);
+                return Ok(false);
+            }
+
+            // Check the detached connections again shortly
+            timeout = Some((deadline - now).min(Duration::from_millis(50)));
+        }
+
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {}
+                Token(i) if i >= FIRST_LISTENER && i < FIRST_LISTENER + listeners.as_ref().map_or(0, Vec::len) => {
+                    if let Some(l) = listeners.as_ref().and_then(|l| l.get(i - FIRST_LISTENER)) {
+                        accept(l, poll.registry(), &mut connections, &mut next_token);
+                    }
+                }
+                token => {
+                    let (finished, detach) = match connections.get_mut(&token) {
+                        Some(connection) => (connection.ready(event), connection.takeover.is_some()),
+                        None => continue,
+                    };
+
+                    if detach {
+                        if let Some(connection) = connections.remove(&token) {
+                            let _ = poll.registry().deregister(&mut SourceFd(&connection.stream.as_raw_fd()));
+                            if let Err(e) = connection.detach() {
+                                tracing::error!("Error detaching connection: {:?}", e);
+                            }
+                        }
+                    } else if finished {
+                        if let Some(connection) = connections.remove(&token) {
+                            let _ = poll.registry().deregister(&mut SourceFd(&connection.stream.as_raw_fd()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Accept every pending connection and register it with the poller.
+fn accept(listener: &Listener, registry: &Registry, connections: &mut HashMap<Token, Connection>, next_token: &mut usize) {
+    loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let token = Token(*next_token);
+                *next_token += 1;
+
+                if let Err(e) = stream.set_nonblocking(true) {
+                    tracing::error!("Error setting non-blocking mode: {}", e);
+                    continue;
+                }
+                if let Err(e) = registry.register(&mut SourceFd(&stream.as_raw_fd()), token, Interest::READABLE | Interest::WRITABLE) {
+                    tracing::error!("Error registering connection: {}", e);
+                    continue;
+                }
+                connections.insert(token, Connection::new(stream));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                tracing::error!("Error accepting connection: {}", e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! fastcgi.rs
+//!
+//! FastCGI client, to answer CGI routes with a long-running application
+//! such as php-fpm instead of a program run for each request.
+//! Each request opens a connection to the application, over TCP or a Unix
+//! domain socket, sends the CGI environment and the body as records, and
+//! reads the CGI response back from the records of the standard output.
+
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::cgi::{self, Script};
+use crate::connection::Stream;
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::ServerParams;
+
+/// Version of the FastCGI protocol.
+const VERSION: u8 = 1;
+
+/// Record types (FastCGI specification, section 8).
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+/// Role of an application answering requests.
+const RESPONDER: u16 = 1;
+
+/// Identifier of the only request of each connection.
+const REQUEST_ID: u16 = 1;
+
+/// Largest content of a record.
+const MAX_CONTENT: usize = u16::MAX as usize;
+
+/// Answer a request with a FastCGI application.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `prefix` - Prefix of the CGI route.
+/// * `address` - Address of the application, `HOST:PORT` or `unix:PATH`.
+/// * `root` - Directory of the scripts, to find the script of the request.
+pub fn respond(request: Request, prefix: &str, address: &str, root: Option<&Path>) -> Response {
+    let path = request.target.split('?').next().unwrap_or_default().to_string();
+    let script = match root {
+        Some(root) => match cgi::find_script(prefix, &path, root) {
+            Some(script) => script,
+            None => return Response::new(request, None, 404, None),
+        },
+        // Without a directory, the application is the script of the whole route
+        None => Script {
+            name: prefix.trim_end_matches('/').to_string(),
+            path_info: path[prefix.trim_end_matches('/').len()..].to_string(),
+            filename: None,
+        },
+    };
+
+    let records = begin(&request, &script);
+    let stream = match connect(address).and_then(|mut stream| stream.write_all(&records).map(|_| stream)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            tracing::error!("Error connecting to FastCGI application {}: {}", address, e);
+            let status = if e.kind() == ErrorKind::TimedOut { 504 } else { 502 };
+            return Response::new(request, None, status, None);
+        }
+    };
+
+    let mut output = BufReader::new(Output {
+        stream: BufReader::new(stream),
+        remaining: 0,
+        padding: 0,
+        ended: false,
+    });
+    match cgi::read_head(&mut output) {
+        Ok(head) => cgi::stream_output(request, head, output, || {}),
+        Err(e) => {
+            tracing::error!("Error reading FastCGI application {}: {}", address, e);
+            let status = if e.kind() == ErrorKind::TimedOut { 504 } else { 502 };
+            Response::new(request, None, status, None)
+        }
+    }
+}
+
+/// Open a connection to a FastCGI application.
+fn connect(address: &str) -> io::Result<Stream> {
+    let timeout = ServerParams::current().cgi.timeout;
+
+    if let Some(path) = address.strip_prefix("unix:") {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        return Ok(Stream::from(stream));
+    }
+
+    let mut last_error = io::Error::new(ErrorKind::NotFound, format!("No address for {}", address));
+    for address in address.to_socket_addrs()?.collect::<Vec<SocketAddr>>() {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                stream.set_nodelay(true)?;
+                return Ok(Stream::from(stream));
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Encode the records starting a request: its role, its CGI environment and its body.
+fn begin(request: &Request, script: &Script) -> Vec<u8> {
+    let mut records = Vec::new();
+
+    // The connection is closed by the application once the request ends
+    let mut body = RESPONDER.to_be_bytes().to_vec();
+    body.extend_from_slice(&[0; 6]);
+    push_record(&mut records, BEGIN_REQUEST, &body);
+
+    let mut params = Vec::new();
+    for (name, value) in cgi::environment(request, script) {
+        push_length(&mut params, name.len());
+        push_length(&mut params, value.len());
+        params.extend_from_slice(name.as_bytes());
+        params.extend_from_slice(value.as_bytes());
+    }
+    push_stream(&mut records, PARAMS, &params);
+    push_stream(&mut records, STDIN, &request.body);
+
+    records
+}
+
+/// Encode a stream: records of its content, then an empty record ending it.
+fn push_stream(records: &mut Vec<u8>, record_type: u8, content: &[u8]) {
+    for chunk in content.chunks(MAX_CONTENT) {
+        push_record(records, record_type, chunk);
+    }
+    push_record(records, record_type, &[]);
+}
+
+/// Encode a record, padded to a multiple of 8 bytes.
+fn push_record(records: &mut Vec<u8>, record_type: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    records.extend_from_slice(&[VERSION, record_type]);
+    records.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    records.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    records.extend_from_slice(&[padding as u8, 0]);
+    records.extend_from_slice(content);
+    records.extend_from_slice(&[0; 8][..padding]);
+}
+
+/// Encode the length of a name or value, on 1 byte below 128, otherwise on 4 bytes.
+fn push_length(params: &mut Vec<u8>, length: usize) {
+    if length < 128 {
+        params.push(length as u8);
+    } else {
+        params.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Standard output of a request, read from the records of the application.
+///
+/// The standard error is logged, and the output ends with the request.
+struct Output {
+    stream: BufReader<Stream>,
+
+    /// Bytes left in the current record of the standard output
+    remaining: usize,
+
+    /// Padding following the current record
+    padding: usize,
+
+    /// Whether the application ended the request
+    ended: bool,
+}
+
+impl Output {
+    /// Read the next record header, returning its type, content length and padding length.
+    fn header(&mut self) -> io::Result<(u8, usize, usize)> {
+        let mut header = [0; 8];
+        self.stream.read_exact(&mut header).map_err(timed_out)?;
+        if header[0] != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Invalid FastCGI record version"));
+        }
+        Ok((header[1], u16::from_be_bytes([header[4], header[5]]) as usize, header[6] as usize))
+    }
+
+    /// Read the content of a record, with its padding.
+    fn content(&mut self, length: usize, padding: usize) -> io::Result<Vec<u8>> {
+        let mut content = vec![0; length + padding];
+        self.stream.read_exact(&mut content).map_err(timed_out)?;
+        content.truncate(length);
+        Ok(content)
+    }
+}
+
+impl Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            if self.ended {
+                return Ok(0);
+            }
+            if self.padding > 0 {
+                self.content(0, self.padding)?;
+                self.padding = 0;
+            }
+
+            let (record_type, length, padding) = self.header()?;
+            match record_type {
+                STDOUT => {
+                    self.remaining = length;
+                    self.padding = padding;
+                }
+                STDERR => {
+                    let content = self.content(length, padding)?;
+                    for line in String::from_utf8_lossy(&content).lines().filter(|l| !l.is_empty()) {
+                        tracing::warn!("FastCGI application: {}", line);
+                    }
+                }
+                END_REQUEST => {
+                    let content = self.content(length, padding)?;
+                    // The protocol status is 0 when the request completed
+                    if content.get(4).is_some_and(|status| *status != 0) {
+                        return Err(io::Error::new(ErrorKind::InvalidData, "FastCGI application rejected the request"));
+                    }
+                    self.ended = true;
+                }
+                // Records of other types are not expected, and skipped
+                _ => {
+                    self.content(length, padding)?;
+                }
+            }
+        }
+
+        let max = buf.len().min(self.remaining);
+        let n = self.stream.read(&mut buf[..max]).map_err(timed_out)?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "FastCGI connection closed during a record"));
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
+
+/// Report reads stopped by the socket timeout as timeouts.
+fn timed_out(e: io::Error) -> io::Error {
+    match e.kind() {
+        ErrorKind::WouldBlock => io::Error::new(ErrorKind::TimedOut, "FastCGI application timed out"),
+        _ => e,
+    }
+}
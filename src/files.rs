@@ -2,24 +2,34 @@
 //!
 //! Manages file operations such as reading and writing files.
 
-use std::{env, fs::File, path::Path};
-use std::io::Write;
+use std::{fs::File, path::{Path, PathBuf}};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::Duration;
+
+use crate::jwt::Claims;
+use crate::metrics;
+use crate::sse::EventStream;
+use crate::tls::PeerIdentity;
+use crate::utils::ServerParams;
+use crate::websocket::{Event, Message, WebSocket, INTERNAL_ERROR};
+
+/// Interval at which followed files are checked for new lines.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 fn sanitize_path(file_path: &str) -> Option<String> {
     let path = Path::new(file_path);
 
     // Check if the path is absolute
     if path.is_absolute() {
-        eprintln!("Path traversal attempt: absolute path not allowed");
+        tracing::warn!("Path traversal attempt: absolute path not allowed");
+        metrics::record_path_traversal("absolute_path");
         return None;
     }
 
     // Check for ".." components
-    if path.components().any(|c| match c {
-        std::path::Component::ParentDir => true,
-        _ => false
-    }) {
-        eprintln!("Path traversal attempt: parent directory references not allowed");
+    if path.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        tracing::warn!("Path traversal attempt: parent directory references not allowed");
+        metrics::record_path_traversal("parent_directory");
         return None;
     }
 
@@ -29,7 +39,8 @@ fn sanitize_path(file_path: &str) -> Option<String> {
         .all(|c| c.is_alphanumeric() || c == '.' || c == '_' || c == '-' || c == '/');
     
     if !valid_chars {
-        eprintln!("Path traversal attempt: invalid characters in path");
+        tracing::warn!("Path traversal attempt: invalid characters in path");
+        metrics::record_path_traversal("invalid_characters");
         return None;
     }
     
@@ -38,29 +49,38 @@ fn sanitize_path(file_path: &str) -> Option<String> {
 
 /// Get the content of a file on the server.
 ///
-/// Get the working directory from the command line arguments.
-/// If no directory is provided, it defaults to the current directory.
 /// The function constructs the full path to the file and reads its content.
 ///
 /// # Arguments
 ///
+/// * `root` - Files directory of the virtual host.
 /// * `file_path` - Path to the file to be read.
-pub fn get_file_content(file_path: String) -> Option<String> {
-    // Sanitize the file path to prevent path traversal attacks
-    let sanitized_path = match sanitize_path(&file_path) {
-        Some(path) => path,
-        None => return None,
-    };
+pub fn get_file_content(root: &Path, file_path: String) -> Option<String> {
+    let canonical_path = resolve_file(root, &file_path)?;
 
-    // Get the working directory from command line arguments
-    let env_args: Vec<String> = env::args().collect();
-    let mut dir = "./".to_string();
-    if env_args.len() > 2{
-        dir = env_args[2].clone();
+    // Check if the file exists and read its content
+    if canonical_path.exists() {
+        let content = std::fs::read_to_string(canonical_path).ok()?;
+        Some(content)
+    } else {
+        None
     }
-    
+}
+
+/// Get the full path of an existing file on the server.
+///
+/// Returns `None` if the file does not exist or is outside the files directory.
+///
+/// # Arguments
+///
+/// * `root` - Files directory of the virtual host.
+/// * `file_path` - Path to the file, relative to the files directory.
+pub fn resolve_file(root: &Path, file_path: &str) -> Option<PathBuf> {
+    // Sanitize the file path to prevent path traversal attacks
+    let sanitized_path = sanitize_path(file_path)?;
+
     // Construct the full path to the file
-    let base_dir = Path::new(&dir).canonicalize().ok()?;
+    let base_dir = root.canonicalize().ok()?;
     let full_path = base_dir.join(&sanitized_path);
 
     // Check if the canonical path is within the base directory
@@ -70,30 +90,188 @@ pub fn get_file_content(file_path: String) -> Option<String> {
     };
 
     if !canonical_path.starts_with(&base_dir) {
-        eprintln!("Path traversal attempt: path escapes base directory");
+        tracing::warn!("Path traversal attempt: path escapes base directory");
+        metrics::record_path_traversal("escapes_base_directory");
         return None;
     }
-    
-    // Check if the file exists and read its content
-    if canonical_path.exists() {
-        let content = std::fs::read_to_string(canonical_path).ok()?;
-        Some(content)
-    } else {
-        None
+
+    Some(canonical_path)
+}
+
+/// Follows the lines appended to a file, like `tail -f`.
+struct FileFollower {
+    file: File,
+
+    /// Offset of the next byte to read
+    position: u64,
+
+    /// Beginning of a line whose end is not written yet
+    partial_line: Vec<u8>,
+}
+
+impl FileFollower {
+    /// Open a file, following it from `position` or from its end.
+    fn open(path: &Path, position: Option<u64>) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let end = file.seek(SeekFrom::End(0))?;
+
+        Ok(FileFollower {
+            file,
+            position: position.filter(|p| *p <= end).unwrap_or(end),
+            partial_line: Vec::new(),
+        })
+    }
+
+    /// Read the complete lines appended since the last call.
+    ///
+    /// Each line is returned with the offset following it. If the file was
+    /// truncated, e.g. by log rotation, it is read again from the start.
+    fn read_lines(&mut self) -> std::io::Result<Vec<(String, u64)>> {
+        let len = self.file.metadata()?.len();
+        if len < self.position {
+            self.position = 0;
+            self.partial_line.clear();
+        }
+        if len == self.position {
+            return Ok(Vec::new());
+        }
+
+        self.file.seek(SeekFrom::Start(self.position))?;
+        let mut appended = Vec::new();
+        Read::by_ref(&mut self.file).take(len - self.position).read_to_end(&mut appended)?;
+        self.position += appended.len() as u64;
+        self.partial_line.extend_from_slice(&appended);
+
+        // The end of the last line may not be written yet
+        let mut lines = Vec::new();
+        let mut offset = self.position - self.partial_line.len() as u64;
+        while let Some(end) = self.partial_line.iter().position(|b| *b == b'\n') {
+            let line = self.partial_line.drain(..=end).collect::<Vec<u8>>();
+            offset += line.len() as u64;
+            lines.push((String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string(), offset));
+        }
+        Ok(lines)
+    }
+}
+
+/// Send the lines appended to a file over a WebSocket, like `tail -f`.
+///
+/// Only lines written after the connection was opened are sent.
+///
+/// # Arguments
+///
+/// * `websocket` - The client's WebSocket.
+/// * `path` - Full path of the file to follow.
+pub fn tail_file(websocket: &mut WebSocket, path: PathBuf) {
+    let mut follower = match FileFollower::open(&path, None) {
+        Ok(f) => f,
+        Err(e) => {
+            websocket.close(INTERNAL_ERROR, &format!("Cannot open file: {}", e));
+            return;
+        }
+    };
+
+    loop {
+        match websocket.next() {
+            Ok(Event::Closed) | Err(_) => return,
+            // Messages from the client are ignored
+            Ok(Event::Message(_)) | Ok(Event::Idle) => {}
+        }
+
+        let lines = match follower.read_lines() {
+            Ok(l) => l,
+            Err(_) => return,
+        };
+        for (line, _) in lines {
+            if websocket.send(&Message::Text(line)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Send the lines appended to a file as Server-Sent Events, like `tail -f`.
+///
+/// The identifier of each event is the offset following its line, so a client
+/// reconnecting with `Last-Event-ID` resumes where it stopped. New clients
+/// only receive the lines written after they connected.
+///
+/// # Arguments
+///
+/// * `events` - The client's event stream.
+/// * `path` - Full path of the file to follow.
+pub fn tail_file_events(events: &mut EventStream, path: PathBuf) -> std::io::Result<()> {
+    let resume_at = events.last_event_id().and_then(|id| id.parse::<u64>().ok());
+    let mut follower = FileFollower::open(&path, resume_at)?;
+
+    loop {
+        for (line, offset) in follower.read_lines()? {
+            events.send(Some(&offset.to_string()), None, &line)?;
+        }
+        if !events.wait(TAIL_POLL_INTERVAL)? {
+            return Ok(());
+        }
     }
 }
 
+/// Check if a client is allowed to create files.
+///
+/// When writes are restricted, the client must have presented a verified
+/// certificate, issued to one of the allowed names if any are configured.
+///
+/// # Arguments
+///
+/// * `identity` - Identity of the client certificate, if one was verified.
+pub fn can_write(identity: Option<&PeerIdentity>) -> bool {
+    let params = ServerParams::current();
+    if !params.files_write_client_cert {
+        return true;
+    }
+
+    match identity {
+        Some(id) => params.files_write_allowed.is_empty() || params.files_write_allowed.iter().any(|name| id.matches(name)),
+        None => false,
+    }
+}
+
+/// Check if a client is allowed to access a file.
+///
+/// When the files are split by user, the client must have sent a JWT, and
+/// the file must be in the directory named by the claim of its token.
+///
+/// # Arguments
+///
+/// * `claims` - Claims of the JWT of the client, if it sent one.
+/// * `filename` - Path of the file, relative to the files directory.
+pub fn can_access(claims: Option<&Claims>, filename: &str) -> bool {
+    let params = ServerParams::current();
+    let claim = match &params.files_prefix_claim {
+        Some(claim) => claim,
+        None => return true,
+    };
+
+    let directory = match claims.and_then(|c| c.get(claim)).and_then(|v| v.as_str()) {
+        Some(directory) => directory.trim_matches('/'),
+        None => return false,
+    };
+    // The directory must stay under the files directory
+    if directory.is_empty() || directory.split('/').any(|s| s.is_empty() || s == "." || s == "..") {
+        tracing::warn!("Invalid {} claim for a files directory: {}", claim, directory);
+        return false;
+    }
+    filename.strip_prefix(directory).is_some_and(|rest| rest.starts_with('/'))
+}
+
 /// Create a file on the server
 ///
-/// Get the working directory from the command line arguments.
-/// If no directory is provided, it defaults to the current directory.
 /// The function constructs the full path to the file and create a file.
 ///
 /// # Arguments
 ///
+/// * `root` - Files directory of the virtual host.
 /// * `file_name` - Name of the file to create.
 /// * `file_content` - Content to write to the file.
-pub fn create_file(file_name: String, file_content: String) -> std::io::Result<()> {
+pub fn create_file(root: &Path, file_name: String, file_content: Vec<u8>) -> std::io::Result<()> {
     // Sanitize the path
     let sanitized_path = match sanitize_path(&file_name){
         Some(path) => path,
@@ -103,15 +281,8 @@ pub fn create_file(file_name: String, file_content: String) -> std::io::Result<(
          ))
     };
     
-    // Get the working directory from command line arguments
-    let env_args: Vec<String> = env::args().collect();
-    let mut dir = "./".to_string();
-    if env_args.len() > 2{
-        dir = env_args[2].clone();
-    }
-
     // Construct the full path to the file
-    let base_dir = Path::new(&dir).canonicalize().map_err(|e| {
+    let base_dir = root.canonicalize().map_err(|e| {
         std::io::Error::new(std::io::ErrorKind::NotFound, 
             format!("Base directory error: {}", e))
     })?;
@@ -125,7 +296,7 @@ pub fn create_file(file_name: String, file_content: String) -> std::io::Result<(
         
         // Get the canonical path of the parent directory
         let canonical_parent = parent.canonicalize().map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::Other, format!("Cannot canonicalize path: {}", e))
+            std::io::Error::other(format!("Cannot canonicalize path: {}", e))
         })?;
         
         // Check if the parent directory is within the base directory
@@ -139,7 +310,7 @@ pub fn create_file(file_name: String, file_content: String) -> std::io::Result<(
 
     // Write the content to the file
     let mut file = File::create(&file_path)?;
-    file.write_all(file_content.as_bytes())?;
+    file.write_all(&file_content)?;
     file.flush()?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,320 @@
+//! forwarded.rs
+//!
+//! Origin of the requests received through reverse proxies.
+//! Proxies tell the address of their client, the scheme and the host it used
+//! in the `Forwarded` header (RFC 7239), or in the older `X-Forwarded-For`,
+//! `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+//!
+//! Clients can send these headers too, so they are only read from the peers
+//! in the trusted proxy blocks. The chain of addresses is walked back from
+//! the closest proxy until an address that is not a trusted proxy is found.
+
+use std::net::{IpAddr, Ipv6Addr};
+
+use crate::request::Request;
+use crate::utils::{Cidr, ServerParams};
+
+/// Origin of a request, as seen by the client.
+#[derive(Debug, PartialEq)]
+pub struct Origin {
+    /// Address of the client, `None` over Unix domain sockets
+    pub ip: Option<IpAddr>,
+
+    /// Scheme used by the client, "http" or "https"
+    pub scheme: String,
+
+    /// Host requested by the client
+    pub host: Option<String>,
+}
+
+/// Hop of a request through a proxy, one element of the `Forwarded` header or of the `X-Forwarded-*` lists.
+#[derive(Default)]
+struct Hop {
+    /// Address the proxy received the request from, `None` if hidden or unknown
+    from: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+/// Get the origin of a request.
+///
+/// Without forwarding headers from a trusted proxy, the origin is the peer of
+/// the connection, its scheme and the `Host` header.
+///
+/// # Arguments
+///
+/// * `request` - The request received from the peer.
+pub fn origin(request: &Request) -> Origin {
+    resolve(request, &ServerParams::current().trusted_proxies)
+}
+
+/// Get the origin of a request, trusting the forwarding headers of the peers in `trusted`.
+fn resolve(request: &Request, trusted: &[Cidr]) -> Origin {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    let mut origin = Origin {
+        ip: request.peer_addr.map(|a| a.ip()),
+        scheme: if request.secure { "https" } else { "http" }.to_string(),
+        host: request.header("Host").map(str::to_string),
+    };
+
+    if !origin.ip.as_ref().is_some_and(is_trusted) {
+        return origin;
+    }
+
+    let forwarded = request.header_values("Forwarded").collect::<Vec<&str>>();
+    let hops = if forwarded.is_empty() {
+        x_forwarded_hops(request)
+    } else {
+        forwarded.iter().flat_map(|value| split_quoted(value, ',')).map(|element| parse_hop(&element)).collect()
+    };
+
+    // Walk back the chain while the addresses are trusted proxies
+    let mut client_hop = None;
+    for (i, hop) in hops.iter().enumerate().rev() {
+        client_hop = Some(i);
+        match hop.from {
+            Some(ip) => {
+                origin.ip = Some(ip);
+                if !is_trusted(&ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+
+    // The hop of the client tells what it requested
+    let hop = client_hop.map(|i| &hops[i]);
+    let (proto, host) = (hop.and_then(|h| h.proto.clone()), hop.and_then(|h| h.host.clone()));
+
+    if let Some(proto) = proto.map(|p| p.to_ascii_lowercase()).filter(|p| p == "http" || p == "https") {
+        origin.scheme = proto;
+    }
+    if let Some(host) = host.filter(|h| is_valid_host(h)) {
+        origin.host = Some(host);
+    }
+    origin
+}
+
+/// Check if the forwarding headers of a peer are trusted.
+///
+/// # Arguments
+///
+/// * `ip` - Address of the peer.
+pub fn is_trusted(ip: &IpAddr) -> bool {
+    ServerParams::current().trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Get the hops of the `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+///
+/// Each proxy appends to the lists, so their values are matched from the end:
+/// the last value of each header belongs to the closest proxy. Proxies often
+/// set a single `X-Forwarded-Proto` or `X-Forwarded-Host` for the whole chain,
+/// so the first value of a list shorter than `X-Forwarded-For` applies to
+/// every hop, the client hop included.
+fn x_forwarded_hops(request: &Request) -> Vec<Hop> {
+    let values = |name: &str| {
+        request
+            .header_values(name)
+            .flat_map(|value| value.split(','))
+            .map(|value| value.trim().to_string())
+            .collect::<Vec<String>>()
+    };
+    let (nodes, protos, hosts) = (values("X-Forwarded-For"), values("X-Forwarded-Proto"), values("X-Forwarded-Host"));
+
+    // Values of the n-th hop from the end, if the list goes back that far
+    let from_end = |list: &[String], n: usize| {
+        list.len().checked_sub(n + 1).map(|i| list[i].clone()).filter(|v| !v.is_empty())
+    };
+
+    let value = |list: &[String], n: usize| {
+        if list.len() < nodes.len() {
+            list.first().cloned().filter(|v| !v.is_empty())
+        } else {
+            from_end(list, n)
+        }
+    };
+
+    let count = nodes.len().max(protos.len()).max(hosts.len());
+    (0..count)
+        .rev()
+        .map(|n| Hop {
+            from: from_end(&nodes, n).and_then(|node| parse_node(&node)),
+            proto: value(&protos, n),
+            host: value(&hosts, n),
+        })
+        .collect()
+}
+
+/// Parse an element of the `Forwarded` header, e.g. `for=192.0.2.60;proto=https;host=example.com`.
+fn parse_hop(element: &str) -> Hop {
+    let mut hop = Hop::default();
+    for pair in split_quoted(element, ';') {
+        let Some((key, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let value = unquote(value.trim());
+        match key.trim().to_ascii_lowercase().as_str() {
+            "for" => hop.from = parse_node(&value),
+            "proto" => hop.proto = Some(value),
+            "host" => hop.host = Some(value),
+            _ => {}
+        }
+    }
+    hop
+}
+
+/// Parse the address of a node, e.g. `192.0.2.43:47011` or `[2001:db8::17]:4711`.
+///
+/// Returns `None` for obfuscated (`_hidden`) and `unknown` nodes.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _) = rest.split_once(']')?;
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    // IPv4 address followed by a port
+    let (ip, _) = node.rsplit_once(':')?;
+    ip.parse::<IpAddr>().ok().filter(IpAddr::is_ipv4)
+}
+
+/// Split a header value on `separator`, outside of quoted strings.
+fn split_quoted(value: &str, separator: char) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+        } else if quoted && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(current.trim().to_string());
+            current.clear();
+            continue;
+        }
+        current.push(c);
+    }
+    parts.push(current.trim().to_string());
+    parts.retain(|p| !p.is_empty());
+    parts
+}
+
+/// Remove the quotes and escapes of a quoted string.
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::new();
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Check if a forwarded host can be used in URLs, e.g. in redirects.
+fn is_valid_host(host: &str) -> bool {
+    !host.is_empty() && host.chars().all(|c| c.is_ascii_alphanumeric() || "-._:[]".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolve the origin of a request from `peer` with the given header lines, trusting `10.0.0.0/8`.
+    fn origin_of(peer: &str, headers: &str) -> (String, String, Option<String>) {
+        let buffer = format!("GET / HTTP/1.1\r\nHost: backend\r\n{}\r\n", headers);
+        let mut request = Request::from_bytes(buffer.as_bytes()).expect("valid request");
+        request.peer_addr = Some(format!("{}:4000", peer).parse().expect("valid peer"));
+        let trusted = [Cidr::from_str("10.0.0.0/8").unwrap()];
+        let origin = resolve(&request, &trusted);
+        (origin.ip.map(|ip| ip.to_string()).unwrap_or_default(), origin.scheme, origin.host)
+    }
+
+    #[test]
+    fn x_forwarded_headers_of_trusted_proxies() {
+        let cases = [
+            // One trusted tier
+            ("10.0.0.1", "X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Proto: https\r\n", ("203.0.113.7", "https", "backend")),
+            // Two trusted tiers, a single proto and host for the whole chain
+            (
+                "10.0.0.1",
+                "X-Forwarded-For: 203.0.113.7, 10.0.0.2\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: example.com\r\n",
+                ("203.0.113.7", "https", "example.com"),
+            ),
+            // Two trusted tiers, each appending its own proto
+            (
+                "10.0.0.1",
+                "X-Forwarded-For: 203.0.113.7, 10.0.0.2\r\nX-Forwarded-Proto: https, http\r\n",
+                ("203.0.113.7", "https", "backend"),
+            ),
+            // The client prepends an address, the last untrusted one is the client
+            (
+                "10.0.0.1",
+                "X-Forwarded-For: 198.51.100.1, 203.0.113.7\r\nX-Forwarded-Proto: http, https\r\n",
+                ("203.0.113.7", "https", "backend"),
+            ),
+            // Headers split over several lines
+            ("10.0.0.1", "X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-For: 10.0.0.2\r\n", ("203.0.113.7", "http", "backend")),
+            // Proto other than http and https, host unusable in URLs
+            (
+                "10.0.0.1",
+                "X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Proto: gopher\r\nX-Forwarded-Host: a/b\r\n",
+                ("203.0.113.7", "http", "backend"),
+            ),
+            // Malformed address, the walk stops at the closest proxy
+            ("10.0.0.1", "X-Forwarded-For: not-an-ip\r\n", ("10.0.0.1", "http", "backend")),
+        ];
+        for (peer, headers, (ip, scheme, host)) in cases {
+            assert_eq!(origin_of(peer, headers), (ip.to_string(), scheme.to_string(), Some(host.to_string())), "{}", headers);
+        }
+    }
+
+    #[test]
+    fn forwarded_header_of_trusted_proxies() {
+        let cases = [
+            ("10.0.0.1", "Forwarded: for=203.0.113.7;proto=https;host=example.com\r\n", ("203.0.113.7", "https", "example.com")),
+            ("10.0.0.1", "Forwarded: for=\"[2001:db8::17]:4711\";proto=HTTPS\r\n", ("2001:db8::17", "https", "backend")),
+            ("10.0.0.1", "Forwarded: for=203.0.113.7:47011, for=10.0.0.2\r\n", ("203.0.113.7", "http", "backend")),
+            ("10.0.0.1", "Forwarded: for=_hidden;proto=https\r\n", ("10.0.0.1", "https", "backend")),
+            ("10.0.0.1", "Forwarded: for=\"203.0.113.7;proto=https\"\r\n", ("10.0.0.1", "http", "backend")),
+            // The Forwarded header is preferred to the X-Forwarded-* headers
+            (
+                "10.0.0.1",
+                "Forwarded: for=203.0.113.7\r\nX-Forwarded-For: 198.51.100.1\r\n",
+                ("203.0.113.7", "http", "backend"),
+            ),
+        ];
+        for (peer, headers, (ip, scheme, host)) in cases {
+            assert_eq!(origin_of(peer, headers), (ip.to_string(), scheme.to_string(), Some(host.to_string())), "{}", headers);
+        }
+    }
+
+    #[test]
+    fn forwarding_headers_of_untrusted_peers_are_ignored() {
+        let cases = [
+            "X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: example.com\r\n",
+            "Forwarded: for=203.0.113.7;proto=https;host=example.com\r\n",
+        ];
+        for headers in cases {
+            assert_eq!(
+                origin_of("192.0.2.1", headers),
+                ("192.0.2.1".to_string(), "http".to_string(), Some("backend".to_string())),
+                "{}",
+                headers
+            );
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! health.rs
+//!
+//! Health check endpoints of the server.
+//! `/healthz` tells that the process is alive and answering, while `/readyz`
+//! tells if it should receive new traffic: it fails while the pool is at its
+//! maximum size with connections waiting, when the files directory cannot be
+//! read, and once the server is shutting down.
+
+use std::fmt::Write;
+use std::fs;
+
+use crate::metrics;
+use crate::request::Request;
+use crate::response::Response;
+use crate::shutdown;
+use crate::utils::ServerParams;
+
+/// Answer a liveness check.
+///
+/// The server stays alive while it drains its connections on shutdown.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+pub fn healthz(request: Request) -> Response {
+    Response::new(request, Some("text/plain".to_string()), 200, Some("ok\n".to_string()))
+}
+
+/// Answer a readiness check, with the result of each check in the body.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+pub fn readyz(request: Request) -> Response {
+    let mut body = String::new();
+    let mut ready = true;
+
+    // Connections waiting for a worker once the pool cannot grow mean it is saturated
+    match metrics::pool_usage() {
+        Some(usage) if usage.queued > 0 && usage.workers >= usage.max_workers => {
+            ready = false;
+            let _ = writeln!(body, "pool: saturated ({}/{} workers busy, {} connections queued)", usage.busy, usage.max_workers, usage.queued);
+        }
+        Some(usage) => {
+            let _ = writeln!(body, "pool: ok ({}/{} workers busy, {} started)", usage.busy, usage.max_workers, usage.workers);
+        }
+        None => {
+            let _ = writeln!(body, "pool: ok (event runtime)");
+        }
+    }
+
+    let files_root = ServerParams::current().files_root.clone();
+    match fs::read_dir(&files_root) {
+        Ok(_) => {
+            let _ = writeln!(body, "files: ok");
+        }
+        Err(e) => {
+            ready = false;
+            let _ = writeln!(body, "files: {} is not accessible: {}", files_root.display(), e);
+        }
+    }
+
+    if shutdown::is_requested() {
+        ready = false;
+        let _ = writeln!(body, "shutdown: in progress");
+    } else {
+        let _ = writeln!(body, "shutdown: ok");
+    }
+
+    let status = if ready { 200 } else { 503 };
+    Response::new(request, Some("text/plain".to_string()), status, Some(body))
+}
@@ -0,0 +1,1329 @@
+//! http2.rs
+//!
+//! HTTP/2 support of the server (RFC 9113).
+//! A connection switches to HTTP/2 when it starts with the client preface,
+//! either directly (prior knowledge on cleartext, or "h2" selected by ALPN on
+//! TLS) or after an HTTP/1.1 request upgraded with `Upgrade: h2c`.
+//!
+//! Frames are read one at a time and several streams can be open at once.
+//! Once a stream has received its whole request, it is routed through the same
+//! code as HTTP/1.1 requests on the worker pool, so that streams are answered
+//! concurrently, and the response is sent as HEADERS and DATA frames within the
+//! flow-control windows of the client.
+//!
+//! Clients must respect the limits announced by the server: the frame size, the
+//! flow-control windows, and the size of the header blocks and their decoded
+//! header lists. Violations close the connection.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::connection::Connection;
+use crate::pool;
+use crate::request::Request;
+use crate::response::Response;
+use crate::shutdown;
+use crate::utils::{HTTPMethod, HTTPVersion, MAX_REQUEST_SIZE};
+
+/// Client connection preface.
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// Frame flags
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+// Settings
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// Error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const INTERNAL_ERROR: u32 = 0x2;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+
+/// Size of a frame header.
+const FRAME_HEADER_LEN: usize = 9;
+
+/// Default flow-control window of streams and connections.
+const DEFAULT_WINDOW: i64 = 65_535;
+
+/// Largest flow-control window allowed by the protocol.
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Default and minimum maximum frame size.
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+
+/// Largest frame size allowed by the protocol.
+const MAX_FRAME_SIZE_LIMIT: usize = (1 << 24) - 1;
+
+/// Number of streams a client may open at once.
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Window advertised to the client for each stream and for the connection.
+const RECEIVE_WINDOW: u32 = 1 << 20;
+
+/// Size of the dynamic table of the decoder, the default as no other is advertised.
+const HEADER_TABLE_SIZE: usize = 4_096;
+
+/// Largest header block accepted, across its CONTINUATION frames.
+const MAX_HEADER_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Largest decoded header list accepted, counted as in SETTINGS_MAX_HEADER_LIST_SIZE.
+const MAX_HEADER_LIST_SIZE: u32 = 256 * 1024;
+
+/// Chunks of a streaming body queued before its producer waits for them to be collected.
+const STREAMING_CHANNEL_BOUND: usize = 4;
+
+/// Data of a streaming body held for a stream before no more is collected from its producer.
+///
+/// Together with `STREAMING_CHANNEL_BOUND`, this holds the producer back while the flow-control window is closed.
+const MAX_PENDING_STREAM_DATA: usize = 64 * 1024;
+
+/// Size of written frames above which they are sent without waiting.
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Headers that are specific to HTTP/1.1 connections and never sent over HTTP/2.
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Error closing the whole connection with a GOAWAY frame.
+#[derive(Debug)]
+enum Error {
+    Io(io::Error),
+    Protocol(u32, &'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Protocol(code, reason) => write!(f, "{} (error code {})", reason, code),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// A frame received from the client.
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+/// State of a stream opened by the client.
+struct Stream {
+    /// Header fields of the request, including pseudo-headers
+    fields: Vec<(String, String)>,
+
+    /// Body received so far
+    body: Vec<u8>,
+
+    /// Set once the client finished sending the request
+    remote_closed: bool,
+
+    /// Window available to send data on the stream
+    send_window: i64,
+
+    /// Window left to the client to send data on the stream
+    recv_window: i64,
+
+    /// Response of the handler answering the stream on another thread
+    handler: Option<Receiver<Response>>,
+
+    /// Response data waiting for window to be sent
+    pending: Vec<u8>,
+
+    /// Data of a streaming body produced by another thread
+    source: Option<Receiver<Vec<u8>>>,
+
+    /// Set once the whole response body is known, the stream ends when it is sent
+    body_finished: bool,
+}
+
+/// Self-pipe waking the connection thread when a handler answered or a streaming body produced data.
+struct Wakeup {
+    receiver: File,
+    sender: Arc<File>,
+}
+
+/// Wakes the connection thread when told to, and when dropped, e.g. by a handler that panicked.
+struct Notifier(Arc<File>);
+
+impl Wakeup {
+    fn new() -> io::Result<Wakeup> {
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (receiver, sender) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+        Ok(Wakeup { receiver, sender: Arc::new(sender) })
+    }
+
+    fn notifier(&self) -> Notifier {
+        Notifier(Arc::clone(&self.sender))
+    }
+
+    /// Consume the pending notifications.
+    fn clear(&mut self) {
+        let mut buf = [0; 64];
+        while matches!(self.receiver.read(&mut buf), Ok(n) if n > 0) {}
+    }
+}
+
+impl Notifier {
+    fn notify(&self) {
+        // A full pipe already holds a notification
+        let _ = (&*self.0).write(&[1]);
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.notify();
+    }
+}
+
+/// Sends the body of a streaming response to the connection thread.
+struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+
+    /// Dropped after the sender, so the connection thread sees the end of the body when woken
+    notifier: Notifier,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The receiver is dropped when the stream is reset or the connection closed
+        self.sender
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "HTTP/2 stream closed"))?;
+        self.notifier.notify();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An HTTP/2 connection with a client.
+struct Http2<S, F> {
+    stream: S,
+    respond: Arc<F>,
+
+    /// Bytes received but not yet consumed as frames
+    buffer: Vec<u8>,
+
+    /// Open streams by identifier
+    streams: BTreeMap<u32, Stream>,
+
+    /// Decoder of the header blocks sent by the client
+    decoder: hpack::Decoder<'static>,
+
+    /// Window available to send data on the connection
+    send_window: i64,
+
+    /// Window left to the client to send data on the connection
+    recv_window: i64,
+
+    /// Initial window of new streams, set by the client
+    initial_window: i64,
+
+    /// Largest frame accepted by the client
+    max_frame_size: usize,
+
+    /// Highest stream identifier opened by the client
+    last_stream_id: u32,
+
+    /// Set once a GOAWAY frame was sent or received
+    going_away: bool,
+
+    /// Frames written but not yet sent
+    output: Vec<u8>,
+
+    /// Woken by the threads producing responses, so they are sent as soon as they are ready
+    wakeup: Wakeup,
+}
+
+/// Check if a connection starts with the HTTP/2 client preface.
+///
+/// Returns `None` while the received bytes are too short to decide.
+///
+/// # Arguments
+///
+/// * `buffer` - Bytes received so far on the connection.
+pub fn detect_preface(buffer: &[u8]) -> Option<bool> {
+    if buffer.len() >= PREFACE.len() {
+        Some(buffer.starts_with(PREFACE))
+    } else if PREFACE.starts_with(buffer) {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+/// Get the settings of an HTTP/1.1 request asking to upgrade to cleartext HTTP/2.
+///
+/// Returns `None` if the request does not ask for `h2c` with valid settings.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP/1.1 request.
+pub fn upgrade_settings(request: &Request) -> Option<Vec<u8>> {
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+
+    let upgrade = header("Upgrade")?;
+    if !upgrade.split(',').any(|p| p.trim().eq_ignore_ascii_case("h2c")) {
+        return None;
+    }
+
+    let settings = URL_SAFE_NO_PAD.decode(header("HTTP2-Settings")?.trim().trim_end_matches('=')).ok()?;
+    (settings.len() % 6 == 0).then_some(settings)
+}
+
+/// Serve an HTTP/2 connection until it is closed.
+///
+/// # Arguments
+///
+/// * `stream` - The client's connection.
+/// * `buffer` - Bytes already received, starting with the client preface.
+/// * `upgrade` - The HTTP/1.1 request upgraded to HTTP/2 and its settings, if any.
+/// * `respond` - Generates the response to each request.
+pub fn serve<S, F>(stream: S, buffer: Vec<u8>, upgrade: Option<(Request, Vec<u8>)>, respond: F)
+where
+    S: Connection,
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    let wakeup = match Wakeup::new() {
+        Ok(w) => w,
+        Err(e) => {
+            tracing::error!("Error creating HTTP/2 wakeup pipe: {}", e);
+            return;
+        }
+    };
+
+    let mut connection = Http2 {
+        stream,
+        respond: Arc::new(respond),
+        buffer,
+        streams: BTreeMap::new(),
+        decoder: hpack::Decoder::new(),
+        send_window: DEFAULT_WINDOW,
+        recv_window: RECEIVE_WINDOW as i64,
+        initial_window: DEFAULT_WINDOW,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        last_stream_id: 0,
+        going_away: false,
+        output: Vec::new(),
+        wakeup,
+    };
+
+    let result = connection.run(upgrade);
+    if let Err(Error::Protocol(code, _)) = &result {
+        let _ = connection.send_goaway(*code);
+    }
+
+    // Send what is left even if the connection failed, e.g. the GOAWAY frame
+    if let Err(e) = result.and(connection.send()) {
+        tracing::debug!("HTTP/2 connection error: {}", e);
+    }
+}
+
+impl<S, F> Http2<S, F>
+where
+    S: Connection,
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    /// Exchange frames with the client until the connection ends.
+    fn run(&mut self, upgrade: Option<(Request, Vec<u8>)>) -> Result<(), Error> {
+        // The server settings are the first frame sent on the connection
+        let mut settings = Vec::new();
+        let server_settings = [
+            (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CONCURRENT_STREAMS),
+            (SETTINGS_INITIAL_WINDOW_SIZE, RECEIVE_WINDOW),
+            (SETTINGS_MAX_HEADER_LIST_SIZE, MAX_HEADER_LIST_SIZE),
+        ];
+        for (id, value) in server_settings {
+            settings.extend_from_slice(&id.to_be_bytes());
+            settings.extend_from_slice(&value.to_be_bytes());
+        }
+        self.write_frame(SETTINGS, 0, 0, &settings)?;
+        self.write_frame(WINDOW_UPDATE, 0, 0, &(RECEIVE_WINDOW - DEFAULT_WINDOW as u32).to_be_bytes())?;
+
+        // The upgraded request is stream 1, already fully received
+        if let Some((request, settings)) = upgrade {
+            self.apply_settings(&settings)?;
+            self.last_stream_id = 1;
+            self.streams.insert(1, self.new_stream(true));
+            self.start_handler(1, request)?;
+        }
+
+        // Check the client preface
+        if !self.fill(PREFACE.len())? {
+            return Ok(());
+        }
+        if !self.buffer.starts_with(PREFACE) {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "invalid client preface"));
+        }
+        self.buffer.drain(..PREFACE.len());
+
+        // The first frame of the client must be its settings
+        match self.read_frame()? {
+            Some(frame) if frame.kind == SETTINGS && frame.flags & FLAG_ACK == 0 => self.handle_frame(frame)?,
+            Some(_) => return Err(Error::Protocol(PROTOCOL_ERROR, "expected SETTINGS frame")),
+            None => return Ok(()),
+        }
+
+        while let Some(frame) = self.read_frame()? {
+            self.handle_frame(frame)?;
+
+            // Close once the client went away and every stream is finished
+            if self.going_away && self.streams.is_empty() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the state of a new stream.
+    fn new_stream(&self, remote_closed: bool) -> Stream {
+        Stream {
+            fields: Vec::new(),
+            body: Vec::new(),
+            remote_closed,
+            send_window: self.initial_window,
+            recv_window: RECEIVE_WINDOW as i64,
+            handler: None,
+            pending: Vec::new(),
+            source: None,
+            body_finished: false,
+        }
+    }
+
+    /// Read until the buffer holds at least `len` bytes.
+    ///
+    /// Returns `false` if the connection was closed, or if it is idle while
+    /// the server is shutting down.
+    fn fill(&mut self, len: usize) -> Result<bool, Error> {
+        while self.buffer.len() < len {
+            self.poll_handlers()?;
+            self.poll_sources()?;
+            self.send()?;
+
+            let mut chunk = [0; 16_384];
+            let read = if self.wait()? { self.stream.read(&mut chunk) } else { Err(ErrorKind::TimedOut.into()) };
+            match read {
+                Ok(0) => return Ok(false),
+                // TLS clients often close without notifying
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                    // Refuse new streams once the server is shutting down
+                    if shutdown::is_requested() {
+                        if !self.going_away {
+                            self.send_goaway(NO_ERROR)?;
+                        }
+                        if self.streams.is_empty() {
+                            return Ok(false);
+                        }
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Wait until the client sent data, a response is ready or the idle poll interval elapsed.
+    ///
+    /// Returns `true` if the connection can be read.
+    fn wait(&mut self) -> Result<bool, Error> {
+        let fd = match self.stream.poll_fd() {
+            Some(fd) => fd,
+            None => return Ok(true),
+        };
+
+        let mut fds = [
+            libc::pollfd { fd, events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: self.wakeup.receiver.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        let timeout = crate::IDLE_POLL_INTERVAL.as_millis() as libc::c_int;
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == ErrorKind::Interrupted { Ok(false) } else { Err(e.into()) };
+        }
+
+        if fds[1].revents != 0 {
+            self.wakeup.clear();
+        }
+        Ok(fds[0].revents != 0)
+    }
+
+    /// Read the next frame sent by the client.
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if !self.fill(FRAME_HEADER_LEN)? {
+            return Ok(None);
+        }
+
+        // No larger frame size is advertised to the client
+        let len = u32::from_be_bytes([0, self.buffer[0], self.buffer[1], self.buffer[2]]) as usize;
+        if len > DEFAULT_MAX_FRAME_SIZE {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "frame too large"));
+        }
+        if !self.fill(FRAME_HEADER_LEN + len)? {
+            return Ok(None);
+        }
+
+        let header = self.buffer.drain(..FRAME_HEADER_LEN).collect::<Vec<u8>>();
+        Ok(Some(Frame {
+            kind: header[3],
+            flags: header[4],
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+            payload: self.buffer.drain(..len).collect(),
+        }))
+    }
+
+    /// Handle a frame sent by the client.
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), Error> {
+        match frame.kind {
+            DATA => self.handle_data(frame),
+            HEADERS => self.handle_headers(frame),
+            PRIORITY => Ok(()),
+            RST_STREAM => {
+                if frame.stream_id == 0 {
+                    return Err(Error::Protocol(PROTOCOL_ERROR, "RST_STREAM on stream 0"));
+                }
+                self.streams.remove(&frame.stream_id);
+                Ok(())
+            }
+            SETTINGS => {
+                if frame.stream_id != 0 {
+                    return Err(Error::Protocol(PROTOCOL_ERROR, "SETTINGS on a stream"));
+                }
+                if frame.flags & FLAG_ACK != 0 {
+                    return Ok(());
+                }
+                self.apply_settings(&frame.payload)?;
+                self.write_frame(SETTINGS, FLAG_ACK, 0, &[])?;
+                self.flush_all()
+            }
+            PUSH_PROMISE => Err(Error::Protocol(PROTOCOL_ERROR, "PUSH_PROMISE sent by a client")),
+            PING => {
+                if frame.payload.len() != 8 {
+                    return Err(Error::Protocol(FRAME_SIZE_ERROR, "invalid PING frame"));
+                }
+                if frame.flags & FLAG_ACK == 0 {
+                    self.write_frame(PING, FLAG_ACK, 0, &frame.payload)?;
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.handle_window_update(frame),
+            CONTINUATION => Err(Error::Protocol(PROTOCOL_ERROR, "unexpected CONTINUATION frame")),
+            // Unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    /// Handle a DATA frame, part of a request body.
+    fn handle_data(&mut self, frame: Frame) -> Result<(), Error> {
+        // DATA belongs to a stream, on stream 0 it is a connection error (RFC 9113, section 6.1)
+        if frame.stream_id == 0 {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        let flow_len = frame.payload.len() as i64;
+        let data = strip_padding(&frame)?;
+
+        // The client must stay within the window of the connection
+        self.recv_window -= flow_len;
+        if self.recv_window < 0 {
+            return Err(Error::Protocol(FLOW_CONTROL_ERROR, "connection window exceeded"));
+        }
+        // Give the window back to the client once half of it is used, the body is buffered in memory
+        if let Some(increment) = window_increment(&mut self.recv_window) {
+            self.write_frame(WINDOW_UPDATE, 0, 0, &increment.to_be_bytes())?;
+        }
+
+        let stream = match self.streams.get_mut(&frame.stream_id) {
+            Some(s) if !s.remote_closed => s,
+            _ => return self.reset_stream(frame.stream_id, STREAM_CLOSED),
+        };
+
+        stream.recv_window -= flow_len;
+        if stream.recv_window < 0 {
+            return self.reset_stream(frame.stream_id, FLOW_CONTROL_ERROR);
+        }
+
+        stream.body.extend_from_slice(data);
+        if stream.body.len() > MAX_REQUEST_SIZE {
+            return self.reset_stream(frame.stream_id, REFUSED_STREAM);
+        }
+
+        if frame.flags & FLAG_END_STREAM != 0 {
+            stream.remote_closed = true;
+            return self.dispatch(frame.stream_id);
+        }
+
+        if let Some(increment) = window_increment(&mut stream.recv_window) {
+            self.write_frame(WINDOW_UPDATE, 0, frame.stream_id, &increment.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Handle a HEADERS frame, opening a stream or carrying trailers.
+    fn handle_headers(&mut self, frame: Frame) -> Result<(), Error> {
+        let stream_id = frame.stream_id;
+        if stream_id == 0 || stream_id % 2 == 0 {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "invalid stream identifier"));
+        }
+
+        // Gather the header block, which continues until END_HEADERS
+        let mut block = strip_padding(&frame)?.to_vec();
+        if frame.flags & FLAG_PRIORITY != 0 {
+            if block.len() < 5 {
+                return Err(Error::Protocol(FRAME_SIZE_ERROR, "invalid HEADERS frame"));
+            }
+            block.drain(..5);
+        }
+
+        let mut end_headers = frame.flags & FLAG_END_HEADERS != 0;
+        while !end_headers {
+            match self.read_frame()? {
+                Some(next) if next.kind == CONTINUATION && next.stream_id == stream_id => {
+                    block.extend_from_slice(&next.payload);
+                    end_headers = next.flags & FLAG_END_HEADERS != 0;
+                }
+                Some(_) => return Err(Error::Protocol(PROTOCOL_ERROR, "expected CONTINUATION frame")),
+                None => return Ok(()),
+            }
+            if block.len() > MAX_HEADER_BLOCK_SIZE {
+                return Err(Error::Protocol(ENHANCE_YOUR_CALM, "header block too large"));
+            }
+        }
+
+        // Decode even refused streams to keep the compression context in sync
+        let fields = self.decode_block(&block)?;
+
+        let end_stream = frame.flags & FLAG_END_STREAM != 0;
+
+        // Trailers of an open stream end its request
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.remote_closed || !end_stream {
+                return self.reset_stream(stream_id, PROTOCOL_ERROR);
+            }
+            stream.remote_closed = true;
+            return self.dispatch(stream_id);
+        }
+
+        if stream_id <= self.last_stream_id {
+            return Err(Error::Protocol(STREAM_CLOSED, "HEADERS on a closed stream"));
+        }
+        self.last_stream_id = stream_id;
+
+        if self.going_away || self.streams.len() >= MAX_CONCURRENT_STREAMS as usize {
+            return self.reset_stream(stream_id, REFUSED_STREAM);
+        }
+
+        let mut stream = self.new_stream(end_stream);
+        stream.fields = fields;
+        self.streams.insert(stream_id, stream);
+
+        if end_stream {
+            return self.dispatch(stream_id);
+        }
+        Ok(())
+    }
+
+    /// Decode a header block, one representation at a time.
+    ///
+    /// Each representation is checked before it reaches the decoder, which
+    /// panics on some malformed input, and the decoded header list is bounded
+    /// so that small references to the dynamic table cannot expand without limit.
+    fn decode_block(&mut self, block: &[u8]) -> Result<Vec<(String, String)>, Error> {
+        let invalid = || Error::Protocol(COMPRESSION_ERROR, "invalid header block");
+
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut pos = 0;
+        while pos < block.len() {
+            let (len, size_update) = representation(&block[pos..]).ok_or_else(invalid)?;
+
+            // Table size updates come first and stay within the size known to the server
+            if let Some(size) = size_update {
+                if !fields.is_empty() || size > HEADER_TABLE_SIZE {
+                    return Err(invalid());
+                }
+            }
+
+            let decoded = self.decoder.decode(&block[pos..pos + len]).map_err(|_| invalid())?;
+            for (name, value) in decoded {
+                list_size += name.len() + value.len() + 32;
+                if list_size > MAX_HEADER_LIST_SIZE as usize {
+                    return Err(Error::Protocol(ENHANCE_YOUR_CALM, "header list too large"));
+                }
+                fields.push((String::from_utf8_lossy(&name).to_string(), String::from_utf8_lossy(&value).to_string()));
+            }
+            pos += len;
+        }
+        Ok(fields)
+    }
+
+    /// Handle a WINDOW_UPDATE frame, then send the data it allows.
+    fn handle_window_update(&mut self, frame: Frame) -> Result<(), Error> {
+        if frame.payload.len() != 4 {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE frame"));
+        }
+
+        let increment = (u32::from_be_bytes([frame.payload[0], frame.payload[1], frame.payload[2], frame.payload[3]]) & 0x7fff_ffff) as i64;
+        if increment == 0 {
+            return Err(Error::Protocol(PROTOCOL_ERROR, "zero window increment"));
+        }
+
+        if frame.stream_id == 0 {
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW {
+                return Err(Error::Protocol(FLOW_CONTROL_ERROR, "connection window overflow"));
+            }
+            return self.flush_all();
+        }
+
+        if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            stream.send_window += increment;
+            if stream.send_window > MAX_WINDOW {
+                return self.reset_stream(frame.stream_id, FLOW_CONTROL_ERROR);
+            }
+            return self.flush(frame.stream_id);
+        }
+        Ok(())
+    }
+
+    /// Apply the settings sent by the client.
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() % 6 != 0 {
+            return Err(Error::Protocol(FRAME_SIZE_ERROR, "invalid SETTINGS frame"));
+        }
+
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return Err(Error::Protocol(FLOW_CONTROL_ERROR, "initial window too large"));
+                    }
+
+                    // The change applies to the windows of every open stream
+                    let delta = value as i64 - self.initial_window;
+                    self.initial_window = value as i64;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    let size = value as usize;
+                    if !(DEFAULT_MAX_FRAME_SIZE..=MAX_FRAME_SIZE_LIMIT).contains(&size) {
+                        return Err(Error::Protocol(PROTOCOL_ERROR, "invalid maximum frame size"));
+                    }
+                    self.max_frame_size = size;
+                }
+                // Responses never use the dynamic table nor push, other settings do not matter
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Route the complete request of a stream, its response is sent once the handler returns.
+    fn dispatch(&mut self, stream_id: u32) -> Result<(), Error> {
+        let (fields, body) = match self.streams.get_mut(&stream_id) {
+            Some(stream) => (std::mem::take(&mut stream.fields), std::mem::take(&mut stream.body)),
+            None => return Ok(()),
+        };
+
+        let request = match build_request(fields, body) {
+            Some(mut request) => {
+                request.peer_identity = self.stream.peer_identity();
+                request.peer_addr = self.stream.peer_addr();
+                request.secure = self.stream.is_secure();
+                request
+            }
+            None => return self.reset_stream(stream_id, PROTOCOL_ERROR),
+        };
+
+        // Fields are written on one line each when forwarded, e.g. to an upstream
+        if !request.has_valid_fields() {
+            tracing::debug!("Invalid character in the fields of stream {}", stream_id);
+            return self.send_response(stream_id, Response::rejected(HTTPVersion::Http2_0, 400));
+        }
+
+        self.start_handler(stream_id, request)
+    }
+
+    /// Answer the request of a stream on the worker pool.
+    ///
+    /// The handler runs on the connection thread when no worker is available,
+    /// so that the stream is answered even when the pool is saturated.
+    fn start_handler(&mut self, stream_id: u32, request: Request) -> Result<(), Error> {
+        let (sender, receiver) = mpsc::channel();
+        let respond = Arc::clone(&self.respond);
+        let notifier = self.wakeup.notifier();
+        let handler = move || {
+            // The notifier is dropped after the sender, waking the connection once the response is sent or the handler panicked
+            let reply = (sender, notifier);
+            let _ = reply.0.send(respond(request));
+        };
+        if let Err(handler) = pool::spawn(handler) {
+            handler();
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.handler = Some(receiver);
+        }
+        self.poll_handlers()
+    }
+
+    /// Send the responses of the handlers that returned.
+    fn poll_handlers(&mut self) -> Result<(), Error> {
+        let mut answered = Vec::new();
+        for (id, stream) in self.streams.iter_mut() {
+            let handler = match &stream.handler {
+                Some(h) => h,
+                None => continue,
+            };
+            match handler.try_recv() {
+                Ok(response) => answered.push((*id, Some(response))),
+                Err(TryRecvError::Empty) => {}
+                // The handler panicked
+                Err(TryRecvError::Disconnected) => answered.push((*id, None)),
+            }
+        }
+        if answered.is_empty() {
+            return Ok(());
+        }
+
+        for (id, response) in answered {
+            if let Some(stream) = self.streams.get_mut(&id) {
+                stream.handler = None;
+            }
+            match response {
+                Some(response) => self.send_response(id, response)?,
+                None => self.reset_stream(id, INTERNAL_ERROR)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a response on a stream.
+    ///
+    /// The body is sent as far as the flow-control windows allow, the rest is
+    /// sent when the client opens them.
+    fn send_response(&mut self, stream_id: u32, mut response: Response) -> Result<(), Error> {
+        let mut fields = vec![(":status".to_string(), response.status_code().to_string())];
+        for (name, value) in &response.headers {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                fields.push((name, value.clone()));
+            }
+        }
+
+        let body = response.body().to_vec();
+        let streaming = response.is_streaming();
+        let block = encode_fields(&fields);
+
+        // Split the header block to fit the maximum frame size of the client
+        let mut chunks = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        while let Some(chunk) = chunks.next() {
+            let mut flags = 0;
+            if chunks.peek().is_none() {
+                flags |= FLAG_END_HEADERS;
+            }
+            if kind == HEADERS && body.is_empty() && !streaming {
+                flags |= FLAG_END_STREAM;
+            }
+            self.write_frame(kind, flags, stream_id, chunk)?;
+            kind = CONTINUATION;
+        }
+
+        // Produce streaming bodies on their own thread, the data is sent as it arrives
+        if streaming {
+            let (sender, receiver) = mpsc::sync_channel(STREAMING_CHANNEL_BOUND);
+            let notifier = self.wakeup.notifier();
+            thread::spawn(move || {
+                if let Err(e) = response.write_streaming_body(&mut ChannelWriter { sender, notifier }) {
+                    tracing::debug!("Error streaming response: {:?}", e);
+                }
+            });
+
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                stream.source = Some(receiver);
+            }
+            return Ok(());
+        }
+
+        if body.is_empty() {
+            self.streams.remove(&stream_id);
+            return Ok(());
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.pending = body;
+            stream.body_finished = true;
+        }
+        self.flush(stream_id)
+    }
+
+    /// Collect the data produced for streaming bodies, then send it.
+    fn poll_sources(&mut self) -> Result<(), Error> {
+        let mut ready = Vec::new();
+        for (id, stream) in self.streams.iter_mut() {
+            let source = match &stream.source {
+                Some(s) => s,
+                None => continue,
+            };
+
+            while stream.pending.len() < MAX_PENDING_STREAM_DATA {
+                match source.try_recv() {
+                    Ok(data) => stream.pending.extend_from_slice(&data),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        stream.source = None;
+                        stream.body_finished = true;
+                        break;
+                    }
+                }
+            }
+            if !stream.pending.is_empty() || stream.body_finished {
+                ready.push(*id);
+            }
+        }
+
+        for id in ready {
+            self.flush(id)?;
+        }
+        Ok(())
+    }
+
+    /// Send the pending data of a stream within the flow-control windows.
+    fn flush(&mut self, stream_id: u32) -> Result<(), Error> {
+        loop {
+            let stream = match self.streams.get_mut(&stream_id) {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+
+            // A streaming body may end after all its data was sent
+            if stream.pending.is_empty() {
+                if stream.body_finished {
+                    self.streams.remove(&stream_id);
+                    self.write_frame(DATA, FLAG_END_STREAM, stream_id, &[])?;
+                }
+                return Ok(());
+            }
+
+            let window = self.send_window.min(stream.send_window);
+            if window <= 0 {
+                return Ok(());
+            }
+
+            let len = stream.pending.len().min(window as usize).min(self.max_frame_size);
+            let data = stream.pending.drain(..len).collect::<Vec<u8>>();
+            stream.send_window -= len as i64;
+            self.send_window -= len as i64;
+
+            // The last frame of the body ends the stream
+            let finished = stream.pending.is_empty() && stream.body_finished;
+            let flags = if finished { FLAG_END_STREAM } else { 0 };
+            self.write_frame(DATA, flags, stream_id, &data)?;
+
+            if finished {
+                self.streams.remove(&stream_id);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send the pending data of every stream, lowest identifiers first.
+    fn flush_all(&mut self) -> Result<(), Error> {
+        let ids = self
+            .streams
+            .iter()
+            .filter(|(_, s)| !s.pending.is_empty())
+            .map(|(id, _)| *id)
+            .collect::<Vec<u32>>();
+
+        for id in ids {
+            self.flush(id)?;
+        }
+        Ok(())
+    }
+
+    /// Close a stream with an error, the connection stays open.
+    fn reset_stream(&mut self, stream_id: u32, code: u32) -> Result<(), Error> {
+        self.streams.remove(&stream_id);
+        self.write_frame(RST_STREAM, 0, stream_id, &code.to_be_bytes())
+    }
+
+    /// Tell the client that no new stream will be processed.
+    fn send_goaway(&mut self, code: u32) -> Result<(), Error> {
+        self.going_away = true;
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    /// Write a frame to the client.
+    fn write_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), Error> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+        frame.extend_from_slice(&len[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        // Frames are sent together before waiting for the client
+
+        self.output.extend_from_slice(&frame);
+        if self.output.len() >= OUTPUT_BUFFER_SIZE {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    /// Send the frames written so far.
+    fn send(&mut self) -> Result<(), Error> {
+        if !self.output.is_empty() {
+            self.stream.write_all(&self.output)?;
+            self.stream.flush()?;
+            self.output.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Remove the padding of a DATA or HEADERS frame.
+fn strip_padding(frame: &Frame) -> Result<&[u8], Error> {
+    if frame.flags & FLAG_PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+
+    let pad_len = *frame.payload.first().ok_or(Error::Protocol(PROTOCOL_ERROR, "invalid padding"))? as usize;
+    if pad_len + 1 > frame.payload.len() {
+        return Err(Error::Protocol(PROTOCOL_ERROR, "invalid padding"));
+    }
+    Ok(&frame.payload[1..frame.payload.len() - pad_len])
+}
+
+/// Take back the used part of a receive window once half of it is used.
+///
+/// Returns the increment to send to the client in a WINDOW_UPDATE frame.
+fn window_increment(window: &mut i64) -> Option<u32> {
+    let used = RECEIVE_WINDOW as i64 - *window;
+    if used < RECEIVE_WINDOW as i64 / 2 {
+        return None;
+    }
+    *window = RECEIVE_WINDOW as i64;
+    Some(used as u32)
+}
+
+/// Get the length of the HPACK representation starting a header block (RFC 7541, section 6).
+///
+/// Also returns the new table size of dynamic table size updates. Returns
+/// `None` if the representation is truncated or its integers overflow.
+fn representation(block: &[u8]) -> Option<(usize, Option<usize>)> {
+    let first = *block.first()?;
+
+    // Indexed field, or table size update
+    if first & 0x80 != 0 {
+        return integer(block, 7).map(|(_, len)| (len, None));
+    }
+    if first & 0xe0 == 0x20 {
+        return integer(block, 5).map(|(size, len)| (len, Some(size)));
+    }
+
+    // Literal field, with incremental indexing or not, whose name is indexed or a literal
+    let prefix = if first & 0x40 != 0 { 6 } else { 4 };
+    let (name_index, mut len) = integer(block, prefix)?;
+    if name_index == 0 {
+        len += string(&block[len..])?;
+    }
+    len += string(&block[len..])?;
+    Some((len, None))
+}
+
+/// Decode an HPACK integer with a prefix of `prefix` bits, returning its value and length.
+fn integer(block: &[u8], prefix: u8) -> Option<(usize, usize)> {
+    let mask = (1u16 << prefix) as usize - 1;
+    let mut value = (*block.first()? as usize) & mask;
+    if value < mask {
+        return Some((value, 1));
+    }
+
+    // Longer integers would not fit the decoder
+    for (i, byte) in block.iter().enumerate().skip(1).take(4) {
+        value += ((byte & 0x7f) as usize) << (7 * (i - 1));
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Get the length of an HPACK string literal.
+fn string(block: &[u8]) -> Option<usize> {
+    let (len, prefix_len) = integer(block, 7)?;
+    let total = prefix_len.checked_add(len)?;
+    (total <= block.len()).then_some(total)
+}
+
+/// Build a request from the header fields and body of a stream.
+///
+/// Returns `None` if the pseudo-headers are missing or invalid.
+fn build_request(fields: Vec<(String, String)>, body: Vec<u8>) -> Option<Request> {
+    let mut pseudo = HashMap::new();
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for (name, value) in fields {
+        if let Some(pseudo_name) = name.strip_prefix(':') {
+            pseudo.insert(pseudo_name.to_string(), value);
+        } else if name == "cookie" {
+            // Cookies may be split into several fields
+            match headers.iter_mut().find(|(k, _)| k == "Cookie") {
+                Some((_, v)) => {
+                    v.push_str("; ");
+                    v.push_str(&value);
+                }
+                None => headers.push(("Cookie".to_string(), value)),
+            }
+        } else {
+            headers.push((canonical_name(&name), value));
+        }
+    }
+
+    let method = HTTPMethod::from_str(pseudo.get("method")?)?;
+    let target = pseudo.remove("path").filter(|p| !p.is_empty())?;
+
+    // The authority replaces the Host header of HTTP/1.1
+    if let Some(authority) = pseudo.remove("authority") {
+        if !headers.iter().any(|(k, _)| k == "Host") {
+            headers.insert(0, ("Host".to_string(), authority));
+        }
+    }
+
+    Some(Request {
+        method,
+        target,
+        http_version: HTTPVersion::Http2_0,
+        headers,
+        body,
+        peer_identity: None,
+        peer_addr: None,
+        secure: false,
+        user: None,
+        claims: None,
+    })
+}
+
+/// Convert a lowercase HTTP/2 header name to the usual HTTP/1.1 form (e.g. "user-agent" to "User-Agent").
+fn canonical_name(name: &str) -> String {
+    name.split('-')
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Encode header fields as literals that are never added to the dynamic table.
+///
+/// This keeps the encoder independent of the table size chosen by the client.
+fn encode_fields(fields: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        block.push(0x00);
+        encode_string(name.as_bytes(), &mut block);
+        encode_string(value.as_bytes(), &mut block);
+    }
+    block
+}
+
+/// Encode a string literal without Huffman coding.
+fn encode_string(value: &[u8], block: &mut Vec<u8>) {
+    // Lengths use a 7-bit prefix integer
+    let mut len = value.len();
+    if len < 0x7f {
+        block.push(len as u8);
+    } else {
+        block.push(0x7f);
+        len -= 0x7f;
+        while len >= 0x80 {
+            block.push((len % 0x80) as u8 | 0x80);
+            len /= 0x80;
+        }
+        block.push(len as u8);
+    }
+    block.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    /// Value and length of an integer, `None` when it is malformed.
+    type Integer = Option<(usize, usize)>;
+
+    /// Length of a representation and its new table size, `None` when it is malformed.
+    type Representation = Option<(usize, Option<usize>)>;
+
+    /// Header fields decoded from a block.
+    type Fields = Vec<(String, String)>;
+
+    /// Build a connection over one end of a socket pair, to decode header blocks.
+    fn connection() -> Http2<crate::connection::Stream, fn(Request) -> Response> {
+        let (stream, _) = UnixStream::pair().unwrap();
+        Http2 {
+            stream: stream.into(),
+            respond: Arc::new(|_| unreachable!()),
+            buffer: Vec::new(),
+            streams: BTreeMap::new(),
+            decoder: hpack::Decoder::new(),
+            send_window: DEFAULT_WINDOW,
+            recv_window: RECEIVE_WINDOW as i64,
+            initial_window: DEFAULT_WINDOW,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            last_stream_id: 0,
+            going_away: false,
+            output: Vec::new(),
+            wakeup: Wakeup::new().unwrap(),
+        }
+    }
+
+    /// Build a literal field with incremental indexing and a new name.
+    fn indexed_literal(name: &str, value: &str) -> Vec<u8> {
+        let mut block = vec![0x40];
+        encode_string(name.as_bytes(), &mut block);
+        encode_string(value.as_bytes(), &mut block);
+        block
+    }
+
+    #[test]
+    fn integers() {
+        let cases: [(&[u8], u8, Integer); 7] = [
+            (&[0x0a], 5, Some((10, 1))),
+            (&[0xea], 5, Some((10, 1))),
+            (&[0x1f, 0x9a, 0x0a], 5, Some((1337, 3))),
+            (&[0x1f, 0x00], 5, Some((31, 2))),
+            (&[0x1f, 0x9a], 5, None),
+            (&[0x7f, 0xff, 0xff, 0xff, 0xff, 0x01], 7, None),
+            (&[], 7, None),
+        ];
+        for (block, prefix, expected) in cases {
+            assert_eq!(integer(block, prefix), expected, "{:?} with a {} bits prefix", block, prefix);
+        }
+    }
+
+    #[test]
+    fn representations() {
+        let cases: [(Vec<u8>, Representation); 9] = [
+            (vec![0x82], Some((1, None))),
+            (vec![0x3f, 0xe1, 0x1f], Some((3, Some(4096)))),
+            (vec![0x20, 0x82], Some((1, Some(0)))),
+            (indexed_literal("custom-key", "custom-header"), Some((26, None))),
+            ([b"\x04\x0c/sample/path".as_slice(), b"\x82"].concat(), Some((14, None))),
+            (b"\x10\x08password\x06secret".to_vec(), Some((17, None))),
+            (b"\x04\x0c/sample".to_vec(), None),
+            (b"\x40\x0acustom-key".to_vec(), None),
+            (vec![], None),
+        ];
+        for (block, expected) in cases {
+            assert_eq!(representation(&block), expected, "{:?}", block);
+        }
+    }
+
+    #[test]
+    fn header_blocks() {
+        let fields = |list: &[(&str, &str)]| list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Fields>();
+        let cases: [(Vec<u8>, Fields); 3] = [
+            (
+                b"\x82\x86\x84\x41\x0fwww.example.com".to_vec(),
+                fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]),
+            ),
+            ([&[0x3f, 0xe1, 0x1f][..], &[0x82]].concat(), fields(&[(":method", "GET")])),
+            ([indexed_literal("custom-key", "custom-header"), vec![0xbe]].concat(), fields(&[("custom-key", "custom-header"); 2])),
+        ];
+        for (block, expected) in cases {
+            assert_eq!(connection().decode_block(&block).unwrap(), expected, "{:?}", block);
+        }
+    }
+
+    #[test]
+    fn malformed_header_blocks() {
+        let large = indexed_literal("x", &"a".repeat(4000));
+        let cases: [(&str, Vec<u8>, u32); 7] = [
+            ("size update after a field", vec![0x82, 0x20], COMPRESSION_ERROR),
+            ("size update above the table size", vec![0x3f, 0xe2, 0x1f], COMPRESSION_ERROR),
+            ("truncated literal", b"\x40\x0acustom-key\x0dcustom".to_vec(), COMPRESSION_ERROR),
+            ("truncated integer", vec![0xff, 0x80], COMPRESSION_ERROR),
+            ("oversized integer", vec![0xff, 0xff, 0xff, 0xff, 0xff, 0x01], COMPRESSION_ERROR),
+            ("index beyond the tables", vec![0xff, 0x00], COMPRESSION_ERROR),
+            ("references expanding past the list size", [large, vec![0xbe; 70]].concat(), ENHANCE_YOUR_CALM),
+        ];
+        for (case, block, code) in cases {
+            let result = connection().decode_block(&block);
+            assert!(matches!(result, Err(Error::Protocol(c, _)) if c == code), "{}: {:?}", case, result);
+        }
+    }
+}
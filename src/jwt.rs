@@ -0,0 +1,360 @@
+//! jwt.rs
+//!
+//! JSON Web Tokens (RFC 7519) sent as Bearer tokens.
+//! Tokens are signed with HS256 by a shared secret, or with RS256 or ES256
+//! by keys given as a PEM public key or a JWKS file. Their signature, their
+//! `exp` and `nbf` times and their `iss` and `aud` claims are checked, and
+//! their claims are given to the route handlers on `Request::claims`.
+
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+
+/// Claims of a validated token, by name.
+pub type Claims = Map<String, Value>;
+
+/// JWT parameters of the server.
+#[derive(Clone)]
+pub struct JwtParams {
+    /// File of the HS256 secret
+    pub secret_file: Option<PathBuf>,
+
+    /// File of the RS256 or ES256 public keys, PEM or JWKS
+    pub key_file: Option<PathBuf>,
+
+    /// Keys checking the signatures, read from the files
+    keys: Vec<Key>,
+
+    /// Issuer the tokens must come from, any if `None`
+    pub issuer: Option<String>,
+
+    /// Audiences the tokens must be intended for, any if empty
+    pub audience: Vec<String>,
+
+    /// Seconds of clock skew tolerated on `exp` and `nbf`
+    pub leeway: u64,
+
+    /// Claim naming the user of a token
+    pub user_claim: String,
+}
+
+// The keys are kept out of debug output
+impl fmt::Debug for JwtParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtParams")
+            .field("secret_file", &self.secret_file)
+            .field("key_file", &self.key_file)
+            .field("keys", &self.keys.len())
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("leeway", &self.leeway)
+            .field("user_claim", &self.user_claim)
+            .finish()
+    }
+}
+
+/// Key checking the signatures of one algorithm.
+#[derive(Clone)]
+struct Key {
+    /// Identifier matched against the `kid` of the tokens, from JWKS files
+    id: Option<String>,
+
+    algorithm: Algorithm,
+
+    key: DecodingKey,
+}
+
+impl JwtParams {
+    /// Build the JWT parameters, reading the secret and key files.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_file` - File of the HS256 secret.
+    /// * `key_file` - File of the RS256 or ES256 public keys, PEM or JWKS.
+    /// * `issuer` - Issuer the tokens must come from.
+    /// * `audience` - Audiences the tokens must be intended for.
+    /// * `leeway` - Seconds of clock skew tolerated.
+    /// * `user_claim` - Claim naming the user of a token.
+    pub fn load(
+        secret_file: Option<PathBuf>,
+        key_file: Option<PathBuf>,
+        issuer: Option<String>,
+        audience: Vec<String>,
+        leeway: u64,
+        user_claim: String,
+    ) -> Result<JwtParams, String> {
+        let mut keys = Vec::new();
+
+        if let Some(path) = &secret_file {
+            let secret = fs::read(path).map_err(|e| format!("Error reading --jwt-secret file {}: {}", path.display(), e))?;
+            // A trailing newline left by an editor is not part of the secret
+            let secret = secret.strip_suffix(b"\n").unwrap_or(&secret);
+            let secret = secret.strip_suffix(b"\r").unwrap_or(secret);
+            if secret.len() < 32 {
+                return Err(format!("The --jwt-secret file {} must hold at least 32 bytes", path.display()));
+            }
+            keys.push(Key {
+                id: None,
+                algorithm: Algorithm::HS256,
+                key: DecodingKey::from_secret(secret),
+            });
+        }
+
+        if let Some(path) = &key_file {
+            let content = fs::read(path).map_err(|e| format!("Error reading --jwt-key file {}: {}", path.display(), e))?;
+            let read = if content.trim_ascii_start().starts_with(b"{") { jwks_keys(&content) } else { pem_key(&content).map(|key| vec![key]) };
+            let read = read.map_err(|e| format!("Invalid --jwt-key file {}: {}", path.display(), e))?;
+            if read.is_empty() {
+                return Err(format!("No HS256, RS256 or ES256 key in --jwt-key file {}", path.display()));
+            }
+            keys.extend(read);
+        }
+
+        Ok(JwtParams { secret_file, key_file, keys, issuer, audience, leeway, user_claim })
+    }
+
+    /// Validate a token, returning its user and its claims.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The token sent by the client.
+    pub fn validate(&self, token: &str) -> Result<(String, Claims), String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| format!("invalid header ({})", e))?;
+
+        // Each key only checks the algorithm it was given for, so that a public key cannot be used as an HMAC secret
+        let candidates = self.keys.iter().filter(|key| key.algorithm == header.alg);
+        let keys = match &header.kid {
+            Some(kid) if candidates.clone().any(|key| key.id.as_ref() == Some(kid)) => candidates.filter(|key| key.id.as_ref() == Some(kid)).collect(),
+            Some(_) => candidates.filter(|key| key.id.is_none()).collect(),
+            None => candidates.collect::<Vec<&Key>>(),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if self.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audience);
+        }
+
+        // Without a key id, the keys of the algorithm are tried in turn
+        let mut result = Err(format!("no {:?} key{}", header.alg, header.kid.as_ref().map_or(String::new(), |kid| format!(" with id {}", kid))));
+        for key in keys {
+            result = jsonwebtoken::decode::<Claims>(token, &key.key, &validation).map_err(|e| e.to_string());
+            if result.is_ok() {
+                break;
+            }
+        }
+        let claims = result?.claims;
+        let user = match claims.get(&self.user_claim) {
+            Some(Value::String(user)) if !user.is_empty() => user.clone(),
+            _ => return Err(format!("no {} claim", self.user_claim)),
+        };
+        Ok((user, claims))
+    }
+}
+
+/// Check if a Bearer token has the shape of a JWT, three dot-separated parts.
+///
+/// # Arguments
+///
+/// * `token` - The token sent by the client.
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3
+}
+
+/// Read the key of a PEM public key, RSA for RS256 or EC for ES256.
+fn pem_key(content: &[u8]) -> Result<Key, String> {
+    let (algorithm, key) = match DecodingKey::from_rsa_pem(content) {
+        Ok(key) => (Algorithm::RS256, key),
+        Err(_) => (Algorithm::ES256, DecodingKey::from_ec_pem(content).map_err(|_| "expected an RSA or EC public key in PEM".to_string())?),
+    };
+    Ok(Key { id: None, algorithm, key })
+}
+
+/// Read the keys of a JWKS file, skipping the keys of other algorithms.
+fn jwks_keys(content: &[u8]) -> Result<Vec<Key>, String> {
+    let set: JwkSet = serde_json::from_slice(content).map_err(|e| e.to_string())?;
+
+    let mut keys = Vec::new();
+    for jwk in &set.keys {
+        let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+            (Some(KeyAlgorithm::HS256) | None, AlgorithmParameters::OctetKey(_)) => Algorithm::HS256,
+            (Some(KeyAlgorithm::RS256) | None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+            (Some(KeyAlgorithm::ES256) | None, AlgorithmParameters::EllipticCurve(ec)) if ec.curve == EllipticCurve::P256 => Algorithm::ES256,
+            _ => {
+                tracing::warn!("Skipping JWKS key {}: only HS256, RS256 and ES256 keys are supported", jwk.common.key_id.as_deref().unwrap_or("without id"));
+                continue;
+            }
+        };
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+        keys.push(Key { id: jwk.common.key_id.clone(), algorithm, key });
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    /// RSA public key, only used to check that it cannot serve as an HMAC secret.
+    const RSA_PUBLIC_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEArgLFF31tLuhmGUKvR6b/
+fIgayx14MdAVbY+DJ4goTfBQHvytPysLtEv1wWs28DCDF124tJGvQV8tvRMWdW3x
+gbCuLm6IXtwYY1MHuhxSoza0GX/LN5AiRTP79G+uNOby/Tnbk4HhvZ6p8+HHNPnc
+3HS/Ar2r2LiYAacALOdEJkFR5uFdMsscRaee7QgTQas/CyoLPfCY2OSC0Kt+ITOK
+76rjirrPc9BOSddtAAsOyxwOIuAWPXNMjWg/FI38TljwuR6jO5clh32yPEU24QJ9
+kqoE0nA4vzLsQ3OrQOozZwIuNtFerycqufPxvh0BD7ZZofqTdqbi4YU1fm5Zk/7s
+kQIDAQAB
+-----END PUBLIC KEY-----
+";
+
+    const SECRET: &[u8] = b"secret-without-key-id-0123456789";
+    const SECRET_A: &[u8] = b"secret-of-key-a-0123456789abcdef";
+    const SECRET_B: &[u8] = b"secret-of-key-b-0123456789abcdef";
+
+    fn params(keys: Vec<Key>) -> JwtParams {
+        JwtParams {
+            secret_file: None,
+            key_file: None,
+            keys,
+            issuer: Some("issuer".to_string()),
+            audience: vec!["server".to_string()],
+            leeway: 0,
+            user_claim: "sub".to_string(),
+        }
+    }
+
+    /// Keys `a` and `b` of a JWKS file, and a secret without key id.
+    fn hmac_keys() -> Vec<Key> {
+        let jwks = json!({"keys": [
+            {"kty": "oct", "kid": "a", "alg": "HS256", "k": URL_SAFE_NO_PAD.encode(SECRET_A)},
+            {"kty": "oct", "kid": "b", "k": URL_SAFE_NO_PAD.encode(SECRET_B)},
+        ]});
+        let mut keys = jwks_keys(jwks.to_string().as_bytes()).unwrap();
+        keys.push(Key { id: None, algorithm: Algorithm::HS256, key: DecodingKey::from_secret(SECRET) });
+        keys
+    }
+
+    fn now() -> i64 {
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn token(alg: Algorithm, kid: Option<&str>, secret: &[u8], claims: Value) -> String {
+        let header = Header { kid: kid.map(str::to_string), ..Header::new(alg) };
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn claims() -> Value {
+        json!({"sub": "alice", "iss": "issuer", "aud": "server", "exp": now() + 60})
+    }
+
+    #[test]
+    fn key_selection() {
+        let jwt = params(hmac_keys());
+        let cases = [
+            (Algorithm::HS256, Some("a"), SECRET_A, true),
+            (Algorithm::HS256, Some("b"), SECRET_B, true),
+            // A key id only selects its own key
+            (Algorithm::HS256, Some("a"), SECRET_B, false),
+            (Algorithm::HS256, Some("b"), SECRET, false),
+            // Unknown key ids fall back to the keys without id
+            (Algorithm::HS256, Some("c"), SECRET, true),
+            (Algorithm::HS256, Some("c"), SECRET_A, false),
+            // Without key id, every key of the algorithm is tried
+            (Algorithm::HS256, None, SECRET_B, true),
+            (Algorithm::HS256, None, b"another-secret-0123456789abcdefg", false),
+            // Keys only check the algorithm they were given for
+            (Algorithm::HS384, Some("a"), SECRET_A, false),
+            (Algorithm::HS512, None, SECRET, false),
+        ];
+        for (alg, kid, secret, valid) in cases {
+            let result = jwt.validate(&token(alg, kid, secret, claims()));
+            assert_eq!(result.is_ok(), valid, "{:?} {:?} {}: {:?}", alg, kid, String::from_utf8_lossy(secret), result);
+        }
+    }
+
+    #[test]
+    fn public_keys_are_not_hmac_secrets() {
+        let key = pem_key(RSA_PUBLIC_KEY.as_bytes()).unwrap();
+        assert_eq!(key.algorithm, Algorithm::RS256);
+        let jwt = params(vec![key]);
+
+        let forged = token(Algorithm::HS256, None, RSA_PUBLIC_KEY.as_bytes(), claims());
+        assert_eq!(jwt.validate(&forged).unwrap_err(), "no HS256 key");
+    }
+
+    #[test]
+    fn invalid_tokens() {
+        let jwt = params(hmac_keys());
+        let with = |changes: Value| {
+            let mut claims = claims();
+            for (name, value) in changes.as_object().unwrap() {
+                match value {
+                    Value::Null => claims.as_object_mut().unwrap().remove(name),
+                    value => claims.as_object_mut().unwrap().insert(name.clone(), value.clone()),
+                };
+            }
+            token(Algorithm::HS256, Some("a"), SECRET_A, claims)
+        };
+        let unsigned = format!("{}.{}.", URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#), URL_SAFE_NO_PAD.encode(claims().to_string()));
+        let valid = with(json!({}));
+        let tampered = format!("{}.{}.{}", valid.split('.').next().unwrap(), URL_SAFE_NO_PAD.encode(json!({"sub": "admin"}).to_string()), valid.split('.').nth(2).unwrap());
+
+        let cases = [
+            ("expired", with(json!({"exp": now() - 10}))),
+            ("not yet valid", with(json!({"nbf": now() + 60}))),
+            ("without expiry", with(json!({"exp": null}))),
+            ("other issuer", with(json!({"iss": "elsewhere"}))),
+            ("other audience", with(json!({"aud": "other"}))),
+            ("without user", with(json!({"sub": null}))),
+            ("empty user", with(json!({"sub": ""}))),
+            ("non-string user", with(json!({"sub": 42}))),
+            ("unsigned", unsigned),
+            ("tampered", tampered),
+            ("malformed", "a.b.c".to_string()),
+        ];
+        assert_eq!(jwt.validate(&valid).map(|(user, _)| user), Ok("alice".to_string()));
+        for (case, token) in cases {
+            assert!(jwt.validate(&token).is_err(), "{}", case);
+        }
+    }
+
+    #[test]
+    fn jwks_keys_of_other_algorithms_are_skipped() {
+        let jwks = json!({"keys": [
+            {"kty": "oct", "kid": "hs512", "alg": "HS512", "k": URL_SAFE_NO_PAD.encode(SECRET_A)},
+            {"kty": "EC", "kid": "p384", "crv": "P-384", "x": "AAAA", "y": "AAAA"},
+            {"kty": "OKP", "kid": "ed25519", "crv": "Ed25519", "x": "AAAA"},
+            {"kty": "oct", "kid": "hs256", "k": URL_SAFE_NO_PAD.encode(SECRET_B)},
+        ]});
+        let keys = jwks_keys(jwks.to_string().as_bytes()).unwrap();
+        assert_eq!(keys.iter().map(|k| (k.id.as_deref(), k.algorithm)).collect::<Vec<_>>(), [(Some("hs256"), Algorithm::HS256)]);
+
+        for malformed in ["{", "{\"keys\": 1}", "{\"keys\": [{\"kty\": \"oct\"}]}"] {
+            assert!(jwks_keys(malformed.as_bytes()).is_err(), "{}", malformed);
+        }
+        assert!(pem_key(b"-----BEGIN PUBLIC KEY-----\nAAAA\n-----END PUBLIC KEY-----\n").is_err());
+    }
+
+    #[test]
+    fn jwt_shape() {
+        let cases = [("a.b.c", true), ("a.b", false), ("a.b.c.d", false), ("opaque-token", false), ("..", true)];
+        for (token, expected) in cases {
+            assert_eq!(is_jwt(token), expected, "{}", token);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! listener.rs
+//!
+//! Creates the listening sockets of the server.
+//! The sockets are either bound by the server, received from systemd through
+//! socket activation, or inherited from the previous process during an upgrade.
+//!
+//! The server listens on TCP addresses, IPv4 or IPv6, and on Unix domain
+//! sockets. The IPv6 wildcard address `[::]` also accepts IPv4 clients.
+//!
+//! An upgrade starts a new server process sharing the same listening sockets,
+//! then drains the current one, so no connection is refused while deploying.
+
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::connection::Stream;
+
+/// First file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Environment variable listing the file descriptors handed to a new process on upgrade.
+const UPGRADE_FDS_VAR: &str = "HTTP_SERVER_LISTEN_FDS";
+
+/// Environment variable giving a new process the pipe telling the previous one it is ready.
+const READY_FD_VAR: &str = "HTTP_SERVER_READY_FD";
+
+/// Time given to a new process to start serving before the upgrade is abandoned.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Connections waiting to be accepted by a listener bound by the server.
+const LISTEN_BACKLOG: libc::c_int = 128;
+
+/// Address the server listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddress {
+    /// IPv4 or IPv6 address and port
+    Tcp(SocketAddr),
+
+    /// Path of a Unix domain socket, with the permissions it is created with
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+impl ListenAddress {
+    /// Parse a listen address.
+    ///
+    /// Accepts `IP:PORT`, `[IPv6]:PORT` and `unix:PATH`, optionally followed
+    /// by `,mode=OCTAL` to set the permissions of the socket file.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The address to parse (e.g. "[::1]:8080", "unix:/run/app.sock,mode=660").
+    pub fn parse(entry: &str) -> Result<ListenAddress, String> {
+        let invalid = || format!("Invalid listen address (expected IP:PORT, [IPv6]:PORT or unix:PATH): {}", entry);
+
+        let Some(socket) = entry.strip_prefix("unix:") else {
+            return entry.parse::<SocketAddr>().map(ListenAddress::Tcp).map_err(|_| invalid());
+        };
+
+        let (path, mode) = match socket.rsplit_once(",mode=") {
+            Some((path, mode)) => {
+                let mode = u32::from_str_radix(mode, 8).ok().filter(|m| *m <= 0o777).ok_or_else(invalid)?;
+                (path, Some(mode))
+            }
+            None => (socket, None),
+        };
+        if path.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ListenAddress::Unix { path: PathBuf::from(path), mode })
+    }
+
+    /// Get the same TCP address with another port.
+    ///
+    /// Returns `None` for Unix domain sockets.
+    pub fn with_port(&self, port: u16) -> Option<ListenAddress> {
+        match self {
+            ListenAddress::Tcp(addr) => Some(ListenAddress::Tcp(SocketAddr::new(addr.ip(), port))),
+            ListenAddress::Unix { .. } => None,
+        }
+    }
+
+    /// Get the port of a TCP address.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ListenAddress::Tcp(addr) => Some(addr.port()),
+            ListenAddress::Unix { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix { path, mode: Some(mode) } => write!(f, "unix:{},mode={:o}", path.display(), mode),
+            ListenAddress::Unix { path, mode: None } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A listening socket of the server.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Accept a new client connection.
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(l) => l.accept().map(|(s, _)| Stream::from(s)),
+            Listener::Unix(l) => l.accept().map(|(s, _)| Stream::from(s)),
+        }
+    }
+
+    /// Get the address the listener is bound to.
+    pub fn local_address(&self) -> io::Result<ListenAddress> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().map(ListenAddress::Tcp),
+            Listener::Unix(l) => {
+                let path = l.local_addr()?.as_pathname().map(PathBuf::from);
+                path.map(|path| ListenAddress::Unix { path, mode: None })
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Unix socket without a path"))
+            }
+        }
+    }
+
+    /// Wait until a connection can be accepted, up to `timeout`.
+    ///
+    /// Returns `false` if the timeout expired first.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Longest time to wait.
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd { fd: self.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        match unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) } {
+            n if n >= 0 => Ok(n > 0),
+            _ => match io::Error::last_os_error() {
+                e if e.kind() == io::ErrorKind::Interrupted => Ok(false),
+                e => Err(e),
+            },
+        }
+    }
+
+    /// Move the listener into or out of non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking),
+            Listener::Unix(l) => l.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(l) => l.as_raw_fd(),
+            Listener::Unix(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+/// Open the listening sockets of the server.
+///
+/// Inherited sockets are used when available, in the same order as the
+/// addresses, otherwise new ones are bound.
+///
+/// # Arguments
+///
+/// * `addresses` - The addresses to bind to.
+pub fn open(addresses: &[ListenAddress]) -> io::Result<Vec<Listener>> {
+    if let Some(listeners) = inherited()? {
+        if listeners.len() != addresses.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} inherited sockets, got {}", addresses.len(), listeners.len()),
+            ));
+        }
+
+        for listener in &listeners {
+            tracing::info!("Using inherited listener on {}", listener.local_address()?);
+        }
+        return Ok(listeners);
+    }
+
+    addresses
+        .iter()
+        .map(|address| {
+            let listener = bind(address).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))?;
+            tracing::info!("Listening on {}", address);
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Bind a new listening socket.
+fn bind(address: &ListenAddress) -> io::Result<Listener> {
+    match address {
+        ListenAddress::Tcp(SocketAddr::V6(addr)) if addr.ip().is_unspecified() => bind_dual_stack(addr.port()).map(Listener::Tcp),
+        ListenAddress::Tcp(addr) => TcpListener::bind(addr).map(Listener::Tcp),
+        ListenAddress::Unix { path, mode } => {
+            remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path)?;
+            if let Some(mode) = mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+            }
+            Ok(Listener::Unix(listener))
+        }
+    }
+}
+
+/// Bind the IPv6 wildcard address, accepting IPv4 clients as mapped addresses.
+///
+/// The `IPV6_V6ONLY` option is cleared explicitly, as its default depends on
+/// the system configuration.
+fn bind_dual_stack(port: u16) -> io::Result<TcpListener> {
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just created and is owned by the listener from now on.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 0)?;
+
+    let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    addr.sin6_port = port.to_be();
+    let len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    if unsafe { libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::listen(fd, LISTEN_BACKLOG) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+/// Set an integer socket option.
+fn set_option(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe { libc::setsockopt(fd, level, name, &value as *const _ as *const libc::c_void, len) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove the socket file left by a server that did not exit cleanly.
+///
+/// Sockets still accepting connections are kept, so that binding fails
+/// instead of stealing the path of a running server.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "Socket is in use by another process")),
+            Err(_) => fs::remove_file(path),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Get the sockets inherited from systemd or from a previous server process.
+///
+/// The environment variables are removed so that they do not leak into
+/// processes started later on.
+fn inherited() -> io::Result<Option<Vec<Listener>>> {
+    // Sockets handed over by the previous process during an upgrade
+    if let Ok(fds) = env::var(UPGRADE_FDS_VAR) {
+        env::remove_var(UPGRADE_FDS_VAR);
+
+        return fds
+            .split(',')
+            .map(|fd| {
+                fd.trim()
+                    .parse::<RawFd>()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid {}: {}", UPGRADE_FDS_VAR, fds)))
+                    .and_then(listener_from_fd)
+            })
+            .collect::<io::Result<Vec<_>>>()
+            .map(Some);
+    }
+
+    // Sockets passed by systemd, only valid for the process they were meant for
+    let pid = env::var("LISTEN_PID").ok().and_then(|p| p.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    match (pid, count) {
+        (Some(pid), Some(count)) if pid == std::process::id() && count > 0 => {
+            (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+                .map(listener_from_fd)
+                .collect::<io::Result<Vec<_>>>()
+                .map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Take ownership of an inherited listening socket, TCP or Unix.
+fn listener_from_fd(fd: RawFd) -> io::Result<Listener> {
+    // Make sure the descriptor is an open socket before taking ownership of it
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut kind as *mut _ as *mut libc::c_void, &mut len) };
+    if ret != 0 || kind != libc::SOCK_STREAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("File descriptor {} is not a stream socket", fd)));
+    }
+
+    let mut domain: libc::c_int = 0;
+    let ret = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_DOMAIN, &mut domain as *mut _ as *mut libc::c_void, &mut len) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Do not leak the socket into processes started later on
+    set_cloexec(fd, true)?;
+
+    // SAFETY: the descriptor was passed to this process to be owned by the server.
+    if domain == libc::AF_UNIX {
+        Ok(Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }))
+    } else {
+        Ok(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+    }
+}
+
+/// Start a new server process sharing the listening sockets.
+///
+/// The new process is started with the same executable and arguments, and
+/// the current one waits until it is ready to serve. If it exits or does not
+/// get ready in time, it is stopped and an error is returned, so that the
+/// current process keeps serving. Returns the process ID of the new server.
+///
+/// # Arguments
+///
+/// * `fds` - File descriptors of the listening sockets to hand over.
+pub fn spawn_successor(fds: &[RawFd]) -> io::Result<u32> {
+    let program = env::current_exe()?;
+    let args = env::args_os().skip(1).collect::<Vec<_>>();
+    let fds_var = fds.iter().map(|fd| fd.to_string()).collect::<Vec<_>>().join(",");
+
+    let mut pipe = [0; 2];
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors were just created and are owned by the files from now on.
+    let (mut ready, notifier) = unsafe { (File::from_raw_fd(pipe[0]), File::from_raw_fd(pipe[1])) };
+
+    // Let the descriptors survive the exec of the new process
+    let inherited = fds.iter().copied().chain([notifier.as_raw_fd()]).collect::<Vec<RawFd>>();
+    for fd in &inherited {
+        set_cloexec(*fd, false)?;
+    }
+    let child = Command::new(program)
+        .args(args)
+        .env(UPGRADE_FDS_VAR, fds_var)
+        .env(READY_FD_VAR, notifier.as_raw_fd().to_string())
+        .spawn();
+    for fd in &inherited {
+        set_cloexec(*fd, true)?;
+    }
+    let mut child = child?;
+
+    // Only the new process holds the writing end now, it is closed if the process exits
+    drop(notifier);
+
+    let mut fd = libc::pollfd { fd: ready.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    let polled = unsafe { libc::poll(&mut fd, 1, READY_TIMEOUT.as_millis() as libc::c_int) };
+    let mut byte = [0; 1];
+    if polled > 0 && matches!(ready.read(&mut byte), Ok(1)) {
+        return Ok(child.id());
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+    Err(io::Error::other(format!("New server process {} did not get ready", child.id())))
+}
+
+/// Tell the previous server process that this one is ready to serve, after an upgrade.
+///
+/// Does nothing if the process was not started by an upgrade.
+pub fn notify_ready() {
+    let Some(fd) = env::var(READY_FD_VAR).ok().and_then(|fd| fd.parse::<RawFd>().ok()) else {
+        return;
+    };
+    env::remove_var(READY_FD_VAR);
+
+    // SAFETY: the descriptor was passed to this process to be owned by the server.
+    let mut notifier = unsafe { File::from_raw_fd(fd) };
+    if let Err(e) = notifier.write_all(b"1") {
+        tracing::error!("Error notifying the previous server process: {}", e);
+    }
+}
+
+/// Set or clear the close-on-exec flag of a file descriptor.
+fn set_cloexec(fd: RawFd, enabled: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let flags = if enabled { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! logging.rs
+//!
+//! Diagnostic logging of the server.
+//! Messages go through the `tracing` facade and are written to the standard
+//! error with their level, so they can be filtered with `--log-level` and kept
+//! apart from the access log.
+//!
+//! Each request is handled inside a `request` span carrying its ID, which is
+//! taken from the `X-Request-Id` header sent by the client or generated, and
+//! echoed back in the response.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use once_cell::sync::Lazy;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt;
+use tracing_subscriber::prelude::*;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::LogLevel;
+
+/// Longest request ID accepted from a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Random prefix of the generated request IDs, different for each process.
+static REQUEST_ID_PREFIX: Lazy<u32> = Lazy::new(|| RandomState::new().build_hasher().finish() as u32);
+
+/// Number of request IDs generated so far.
+static REQUEST_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Start writing the diagnostic messages to the standard error.
+///
+/// Messages of the `log` crate, e.g. from `rustls`, are written as well, but
+/// dependencies are limited to warnings so they do not drown the server's own.
+///
+/// # Arguments
+///
+/// * `level` - Most verbose level written.
+pub fn init(level: LogLevel) -> Result<(), String> {
+    let level = match level {
+        LogLevel::Error => Level::ERROR,
+        LogLevel::Warn => Level::WARN,
+        LogLevel::Info => Level::INFO,
+        LogLevel::Debug => Level::DEBUG,
+        LogLevel::Trace => Level::TRACE,
+    };
+
+    let filter = Targets::new()
+        .with_target(env!("CARGO_CRATE_NAME"), level)
+        .with_default(level.min(Level::WARN));
+
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(io::stderr).with_ansi(io::stderr().is_terminal()))
+        .with(filter)
+        .try_init()
+        .map_err(|e| e.to_string())
+}
+
+/// Wrap a request handler so that each request is handled in its own span.
+///
+/// The ID of the request is added to the response as `X-Request-Id`.
+///
+/// # Arguments
+///
+/// * `respond` - Generates the response to each request.
+pub fn traced<F>(respond: F) -> impl Fn(Request) -> Response
+where
+    F: Fn(Request) -> Response,
+{
+    move |request: Request| {
+        let id = request_id(&request);
+        let span = tracing::info_span!("request", id = %id);
+        let _entered = span.enter();
+
+        tracing::debug!("{} {} {}", request.method.to_str(), request.target, request.http_version.to_str());
+        let mut response = respond(request);
+        tracing::debug!("Answered with status {}", response.status_code());
+
+        response.headers.push(("X-Request-Id".to_string(), id));
+        response
+    }
+}
+
+/// Get the ID of a request, from its `X-Request-Id` header or a new one.
+///
+/// IDs sent by clients are only kept if they are short and printable, so they
+/// cannot forge log lines or response headers.
+fn request_id(request: &Request) -> String {
+    let received = request
+        .headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("X-Request-Id"))
+        .map(|(_, v)| v.trim())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+        });
+
+    match received {
+        Some(id) => id.to_string(),
+        None => format!("{:08x}{:08x}", *REQUEST_ID_PREFIX, REQUEST_COUNT.fetch_add(1, Ordering::Relaxed)),
+    }
+}
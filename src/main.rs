@@ -4,120 +4,530 @@
 //! This file sets up the TCP listener and handles incoming connections.
 //!
 //! Modules:
+//! - `access_log`: Records every answered request in the access log.
+//! - `auth`: Authenticates clients with Basic credentials or Bearer tokens and protects the routes.
+//! - `balancer`: Spreads proxy routes over groups of upstream servers and checks their health.
+//! - `cgi`: Runs CGI programs to answer the requests of CGI routes.
+//! - `config`: Layers the options over the configuration file and reloads it.
+//! - `connection`: Abstracts plaintext and TLS client connections.
+//! - `event_loop`: Event-driven runtime serving connections from a single thread.
+//! - `fastcgi`: Sends the requests of CGI routes to FastCGI applications.
 //! - `files`: Handles file reading/writing.
+//! - `forwarded`: Derives the client origin from the headers of trusted proxies.
+//! - `health`: Answers the liveness and readiness checks.
+//! - `http2`: Serves HTTP/2 connections with binary framing and HPACK.
+//! - `jwt`: Validates the JWTs sent as Bearer tokens and reads their claims.
+//! - `listener`: Binds or inherits the TCP and Unix listening sockets.
+//! - `logging`: Writes leveled diagnostic messages and tags them with request IDs.
+//! - `metrics`: Records and exposes the Prometheus metrics of the server.
+//! - `pool`: Runs connections on a growing and shrinking pool of worker threads.
+//! - `proxy`: Reads the client address sent by proxies with the PROXY protocol.
 //! - `request`: Parses incoming HTTP requests into `Request` objects.
 //! - `response`: Generates `Response` objects based on the request.
+//! - `shutdown`: Handles termination signals and connection draining.
+//! - `sse`: Streams Server-Sent Events.
+//! - `tls`: Loads certificates and configures HTTPS.
+//! - `upstream`: Forwards the requests of proxy routes to backend services.
 //! - `utils`: Contains shared constants, helpers, and compression logic.
+//! - `vhost`: Selects the virtual host answering each request by its `Host` header.
+//! - `websocket`: Handles WebSocket handshakes and messages.
 
+mod access_log;
+mod auth;
+mod balancer;
+mod cgi;
+mod config;
+mod connection;
+mod event_loop;
+mod fastcgi;
 mod files;
+mod forwarded;
+mod health;
+mod http2;
+mod jwt;
+mod listener;
+mod logging;
+mod metrics;
+mod pool;
+mod proxy;
 mod request;
 mod response;
+mod shutdown;
+mod sse;
+mod tls;
+mod upstream;
 mod utils;
+mod vhost;
+mod websocket;
 
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use threadpool::ThreadPool;
 
+use crate::connection::{Connection, Stream};
+use crate::listener::Listener;
+use crate::pool::WorkerPool;
 use crate::request::Request;
 use crate::response::Response;
-use crate::utils::Args;
+use crate::utils::{Args, HTTPVersion, RequestParseError, Runtime, ServerParams, MAX_REQUEST_SIZE};
 
-fn main() {
-    println!("Server started successfully");
+/// Interval at which idle connections check if the server is shutting down.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
+/// Worker threads of the admin listener.
+const ADMIN_THREADS: usize = 2;
+
+/// Exit status when active connections were cut by the shutdown deadline.
+const EXIT_DEADLINE_EXCEEDED: u8 = 2;
+
+fn main() -> ExitCode {
     let srv_params = match Args::parse_params(){
-        Ok(srv) => srv,
+        Ok(srv) => srv.install(),
         Err(e) => {
-            println!("Error parsing arguments: {}", e);
-            return;
+            eprintln!("Error parsing arguments: {}", e);
+            return ExitCode::FAILURE;
         }
     };
 
-    // Max number of threads
-    let pool = ThreadPool::new(srv_params.nb_threads);
+    if let Err(e) = logging::init(srv_params.log_level) {
+        eprintln!("Error initializing logging: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    tracing::info!("Server started successfully");
+
+    // Apply configuration changes without restarting
+    if let Err(e) = config::watch() {
+        tracing::error!("Error installing configuration reload: {}", e);
+    }
+
+    // Probe the upstream servers with a health check
+    balancer::watch();
 
-    // Create a TCP listener on port 4221
-    let listener = match TcpListener::bind(srv_params.get_address()){
+    // Record the answered requests if enabled
+    if let Some(params) = &srv_params.access_log {
+        if let Err(e) = access_log::init(params) {
+            tracing::error!("Error opening access log: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // Listen on the server addresses, then on the HTTPS redirect and admin addresses if enabled
+    let mut addresses = srv_params.get_addresses().to_vec();
+    let redirect_start = addresses.len();
+    addresses.extend(srv_params.get_redirect_addresses());
+    let admin_start = addresses.len();
+    addresses.extend(srv_params.get_admin_addresses());
+
+    // Create the listeners, on port 4221 by default, or reuse inherited ones
+    let mut listeners = match listener::open(&addresses){
         Ok(l) => l,
         Err(e) => {
-            println!("Error binding to port: {}", e);
-            return;
+            tracing::error!("Error binding to port: {}", e);
+            return ExitCode::FAILURE;
         }
     };
-    
-    // Accept incoming connections and check for errors
-    for stream in listener.incoming() {
-        match stream {
+
+    // Every listener is handed over to the new process on upgrade
+    let handover_fds = listeners.iter().map(|l| l.as_raw_fd()).collect::<Vec<RawFd>>();
+    let admin_listeners = listeners.split_off(admin_start);
+    let redirect_listeners = listeners.split_off(redirect_start);
+
+    // Load the certificates if HTTPS is enabled
+    let tls_config = match &srv_params.tls {
+        Some(params) => match tls::server_config(params) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                tracing::error!("Error loading TLS configuration: {}", e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+
+    // Serve the metrics and health checks on their own listeners and workers, whatever the runtime
+    if !admin_listeners.is_empty() {
+        run_admin(admin_listeners);
+    }
+
+    // The previous process drains its connections once this one is ready to take over
+    listener::notify_ready();
+
+    let drained = match srv_params.runtime {
+        Runtime::Threaded => run_threaded(listeners, redirect_listeners, handover_fds, &srv_params, tls_config),
+        Runtime::Event => {
+            match event_loop::run(listeners, handover_fds, srv_params.shutdown_timeout) {
+                Ok(drained) => drained,
+                Err(e) => {
+                    tracing::error!("Error in event loop: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+    };
+
+    if drained {
+        tracing::info!("Server stopped");
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_DEADLINE_EXCEEDED)
+    }
+}
+
+/// Serves connections with blocking sockets on a pool of worker threads.
+///
+/// Returns once a shutdown signal has been received and the active
+/// connections have finished, or the shutdown deadline has been reached.
+/// The returned value tells if every connection finished in time.
+///
+/// Each listener is accepted by its own thread, all of them feeding the same
+/// pool of workers.
+///
+/// # Arguments
+///
+/// * `listeners` - The server listeners.
+/// * `redirect_listeners` - The HTTPS redirect listeners, if enabled.
+/// * `handover_fds` - Listening sockets handed to a new process on upgrade.
+/// * `srv_params` - Parameters of the server.
+/// * `tls_config` - TLS configuration of the server listeners, if HTTPS is enabled.
+fn run_threaded(listeners: Vec<Listener>, redirect_listeners: Vec<Listener>, handover_fds: Vec<RawFd>, srv_params: &ServerParams, tls_config: Option<Arc<ServerConfig>>) -> bool {
+    // Workers between the minimum and maximum number of threads
+    let pool = WorkerPool::new(srv_params.pool.clone());
+    metrics::watch_pool(&pool);
+    pool.share();
+
+    // The accept loops notice the shutdown on their own, within the poll interval
+    if let Err(e) = shutdown::listen(handover_fds, || {}) {
+        tracing::error!("Error installing signal handlers: {}", e);
+    }
+
+    let mut acceptors = Vec::new();
+
+    // Redirect plaintext requests to HTTPS on the secondary listeners
+    let https_port = srv_params.get_port().unwrap_or(443);
+    for redirect_listener in redirect_listeners {
+        let pool = pool.clone();
+        acceptors.push(thread::spawn(move || {
+            accept_loop(redirect_listener, &pool, false, move |stream| {
+                serve(stream, None, move |request| tls::redirect(request, https_port));
+            });
+        }));
+    }
+
+    for listener in listeners {
+        let pool = pool.clone();
+        let tls_config = tls_config.clone();
+        let secure = tls_config.is_some();
+        acceptors.push(thread::spawn(move || {
+            accept_loop(listener, &pool, secure, move |stream| {
+                serve(stream, tls_config.clone(), Response::parse_request);
+            });
+        }));
+    }
+
+    for acceptor in acceptors {
+        let _ = acceptor.join();
+    }
+
+    tracing::info!("Waiting for active connections to finish");
+    shutdown::drain(srv_params.shutdown_timeout, || pool.pending())
+}
+
+/// Serves the admin listeners until the process exits.
+///
+/// Unlike the other listeners, they keep answering while the server drains its
+/// connections on shutdown, so readiness checks report it. Their requests are
+/// not counted in the metrics.
+///
+/// # Arguments
+///
+/// * `listeners` - The bound admin listeners.
+fn run_admin(listeners: Vec<Listener>) {
+    let pool = ThreadPool::new(ADMIN_THREADS);
+
+    for listener in listeners {
+        let pool = pool.clone();
+        thread::spawn(move || loop {
+            match listener.accept() {
+                Ok(stream) => pool.execute(move || match stream.set_read_timeout(Some(IDLE_POLL_INTERVAL)) {
+                    Ok(_) => handler(stream, metrics::admin_respond),
+                    Err(e) => tracing::error!("Error setting read timeout: {:?}", e),
+                }),
+                Err(e) => tracing::error!("Error accepting connection: {}", e),
+            }
+        });
+    }
+}
+
+/// Accepts connections and serves each of them on the pool.
+///
+/// Connections arriving while the accept queue of the pool is full are shed.
+/// Returns once the server is shutting down, closing the listener. Every
+/// accepted connection is served, even when the shutdown started meanwhile.
+///
+/// # Arguments
+///
+/// * `listener` - The bound listener.
+/// * `pool` - The pool of worker threads.
+/// * `secure` - Whether connections use TLS, in which case shed ones get no response.
+/// * `serve` - Serves an accepted connection.
+fn accept_loop<F>(listener: Listener, pool: &WorkerPool, secure: bool, serve: F)
+where
+    F: Fn(Stream) + Clone + Send + 'static,
+{
+    // Wait for connections with a timeout to notice a shutdown, as another
+    // process sharing the socket after an upgrade may accept any of them
+    if let Err(e) = listener.set_nonblocking(true) {
+        tracing::error!("Error setting non-blocking mode: {}", e);
+        return;
+    }
+
+    // Stop accepting connections once the server is shutting down
+    while !shutdown::is_requested() {
+        match listener.accept() {
             Ok(stream) => {
-                pool.execute(move || {
-                    handler(stream);
-                });
-            }                            
+                if let Err(e) = stream.set_nonblocking(false) {
+                    tracing::error!("Error setting blocking mode: {}", e);
+                    continue;
+                }
+                let serve = serve.clone();
+                if let Err(stream) = pool.execute(stream, serve) {
+                    shed(stream, secure);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if let Err(e) = listener.wait(IDLE_POLL_INTERVAL) {
+                    tracing::error!("Error waiting for connections: {}", e);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
             Err(e) => {
-                println!("error: {}", e);
+                tracing::error!("Error accepting connection: {}", e);
             }
         }
     }
 }
 
+/// Refuses a connection because the server is overloaded.
+///
+/// Plaintext clients are answered with a 503 asking them to retry later,
+/// without blocking the accepting thread. TLS connections are closed, as a
+/// response would need a handshake.
+///
+/// # Arguments
+///
+/// * `stream` - The refused connection.
+/// * `secure` - Whether the connection uses TLS.
+fn shed(mut stream: Stream, secure: bool) {
+    tracing::warn!("Accept queue full, refusing connection");
+    metrics::record_shed();
+
+    if secure || stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let _ = stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+
+    // Consume the request already received, so that closing does not reset the connection
+    let _ = stream.read(&mut [0; 4096]);
+}
+
+/// Serves a client connection, over TLS when a configuration is given.
+///
+/// # Arguments
+///
+/// * `stream` - A TCP or Unix stream representing the client's connection.
+/// * `tls_config` - TLS configuration, if the connection uses HTTPS.
+/// * `respond` - Generates the response to each request.
+fn serve<F>(mut stream: Stream, tls_config: Option<Arc<ServerConfig>>, respond: F)
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    // Wake up periodically to notice a shutdown while waiting for a request
+    if let Err(e) = stream.set_read_timeout(Some(IDLE_POLL_INTERVAL)) {
+        tracing::error!("Error setting read timeout: {:?}", e);
+        return;
+    }
+
+    let _active = metrics::ActiveConnection::open();
+
+    // Trusted proxies give the address of their client before anything else, TLS included
+    if let Some(peer) = stream.socket_peer_addr().filter(proxy::is_trusted) {
+        if let Err(e) = proxy::accept(&mut stream) {
+            tracing::warn!("Invalid PROXY protocol header from {}: {}", peer, e);
+            return;
+        }
+    }
+
+    let respond = access_log::logged(logging::traced(metrics::observed(auth::guarded(respond))));
+
+    match tls_config {
+        Some(config) => match ServerConnection::new(config) {
+            Ok(connection) => handler(StreamOwned::new(connection, stream), respond),
+            Err(e) => tracing::error!("Error creating TLS connection: {}", e),
+        },
+        None => handler(stream, respond),
+    }
+}
+
 /// Handles a single client connection.
 ///
 /// Reads the incoming HTTP request, parses it, generates a response,
 /// and writes it back to the stream. If the "Connection: close" header
 /// is present, the function will close the connection.
 ///
+/// Once the server is shutting down, idle connections are closed and the
+/// next response asks the client to close the connection.
+///
+/// Connections starting with the HTTP/2 preface, and cleartext requests
+/// asking to upgrade to `h2c`, are handed over to `http2`. Routes can also
+/// take over the connection after their response, e.g. for WebSockets.
+///
 /// # Arguments
 ///
-/// * `stream` - A TCP stream representing the client's connection.
-fn handler (mut stream: TcpStream){
+/// * `stream` - The client's connection, plaintext or TLS.
+/// * `respond` - Generates the response to each request.
+fn handler<S, F>(mut stream: S, respond: F)
+where
+    S: Connection,
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    // Bytes received but not yet consumed as a request
+    let mut buffer = Vec::new();
+
+    // Set until the first bytes tell whether the client speaks HTTP/2
+    let mut preface_pending = true;
+
     // Loop to handle multiple requests from the same client
     loop{
-        // Read the incoming request
-        let mut buffer = [0; 1024];
-        let bytes_read = match stream.read(&mut buffer) {
-            Ok(0) => return,  // Connection closed
-            Ok(b) => b,
-            Err(e) => {
-                println!("Error reading from stream: {:?}", e);
+        // Connections silent for longer than the keep-alive timeout while waiting for a request are closed
+        let mut last_received = Instant::now();
+
+        // Read until a complete request has been received
+        let request_len = loop {
+            if preface_pending {
+                match http2::detect_preface(&buffer) {
+                    Some(true) => return http2::serve(stream, buffer, None, respond),
+                    Some(false) => preface_pending = false,
+                    None => {}
+                }
+            }
+
+            if !preface_pending {
+                match Request::message_len(&buffer) {
+                    Ok(Some(len)) => break len,
+                    Ok(None) => {}
+                    Err(e) => return reject(&mut stream, e),
+                }
+            }
+
+            if buffer.len() > MAX_REQUEST_SIZE {
+                tracing::warn!("Request too large");
                 return;
             }
+
+            let mut chunk = [0; 1024];
+            match stream.read(&mut chunk) {
+                Ok(0) => return,  // Connection closed
+                Ok(b) => {
+                    buffer.extend_from_slice(&chunk[..b]);
+                    last_received = Instant::now();
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                    // Idle connections are closed once the server is shutting down
+                    if buffer.is_empty() && shutdown::is_requested() {
+                        return;
+                    }
+                    if last_received.elapsed() >= ServerParams::current().keep_alive_timeout {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    tracing::debug!("Error reading from stream: {:?}", e);
+                    return;
+                }
+            };
         };
-    
-        if bytes_read == 0 {
-            return;
-        }
-    
-        let stream_string = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
-    
-        // Parse the stream string into a Request object
-        let request : Request = match Request::from_str(stream_string){
+
+        let raw = buffer.drain(..request_len).collect::<Vec<u8>>();
+
+        // Parse the received bytes into a Request object
+        let mut request : Request = match Request::from_bytes(&raw){
             Ok(r) => r,
-            Err(e) => {
-                println!("Error parsing request: {:?}", e);
-                return;
-            }
+            Err(e) => return reject(&mut stream, e),
         };
+
+        // Attach the identity of the verified client certificate, the client address and the transport
+        request.peer_identity = stream.peer_identity();
+        request.peer_addr = stream.peer_addr();
+        request.secure = stream.is_secure();
+
+        // Switch to HTTP/2 on request, the upgraded request is answered as stream 1
+        if !stream.is_secure() {
+            if let Some(settings) = http2::upgrade_settings(&request) {
+                let switching = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+                if let Err(e) = stream.write_all(switching).and_then(|_| stream.flush()) {
+                    tracing::debug!("Error writing to stream: {:?}", e);
+                    return;
+                }
+                return http2::serve(stream, buffer, Some((request, settings)), respond);
+            }
+        }
     
         // Generate the response based on the request
-        let response : Response = Response::parse_request(request);
+        let mut response : Response = respond(request);
+
+        // Do not keep the connection alive while the server is shutting down
+        if shutdown::is_requested() {
+            response.close_connection();
+        }
     
         // Write the response back to the stream
-        if let Err(e) = stream.write(&response.as_bytes()){
-            println!("Error writing to stream: {:?}", e);
+        if let Err(e) = stream.write_all(&response.as_bytes()){
+            tracing::debug!("Error writing to stream: {:?}", e);
             return;
         }
 
         if let Err(e) = stream.flush(){
-            println!("Error flushing stream: {:?}", e);
+            tracing::debug!("Error flushing stream: {:?}", e);
+            return;
+        }
+
+        // Send the body of streaming responses as it is produced
+        if let Err(e) = response.write_streaming_body(&mut stream) {
+            tracing::debug!("Error streaming response: {:?}", e);
+            return;
+        }
+
+        // Hand the connection over to the route, e.g. after a WebSocket upgrade
+        if let Some(takeover) = response.takeover() {
+            takeover(&mut stream, buffer);
             return;
         }
 
         // Check if the "Connection: close" header is present
         // If it is, close the connection
-        if response.headers.contains(&("Connection".to_string(), "close".to_string())){
-            println!("Connection closed");
+        if response.closes_connection(){
+            tracing::debug!("Connection closed");
             return;
         }
     }
+}
+
+/// Answer a request that could not be parsed, before the connection is closed.
+///
+/// # Arguments
+///
+/// * `stream` - The client's connection.
+/// * `error` - Why the request was refused.
+fn reject(stream: &mut impl Write, error: RequestParseError) {
+    tracing::warn!("Error parsing request: {:?}", error);
+    let response = Response::rejected(HTTPVersion::Http1_1, error.status_code());
+    if let Err(e) = stream.write_all(&response.as_bytes()).and_then(|_| stream.flush()) {
+        tracing::debug!("Error writing to stream: {:?}", e);
+    }
 }
\ No newline at end of file
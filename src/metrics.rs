@@ -0,0 +1,266 @@
+//! metrics.rs
+//!
+//! Prometheus metrics of the server.
+//! Counters are updated while requests are served and rendered in the
+//! Prometheus text exposition format when the metrics endpoint is scraped.
+//!
+//! The endpoint is served by the main listener, or only by a separate admin
+//! listener when an admin port is configured, so that scrapes are not queued
+//! behind client requests. The admin listener also answers the health checks.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use once_cell::sync::{Lazy, OnceCell};
+use crate::health;
+use crate::pool::{PoolUsage, WorkerPool};
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::ServerParams;
+
+/// Upper bounds in seconds of the request duration histogram buckets.
+const DURATION_BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Metrics parameters of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricsParams {
+    /// Path of the metrics endpoint
+    pub path: String,
+
+    /// Port of the admin listener serving the endpoint, the main listener if `None`
+    pub port: Option<u16>,
+}
+
+/// Distribution of the request durations of a route.
+#[derive(Default)]
+struct Histogram {
+    /// Number of requests in each bucket, not cumulated
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Metrics recorded since the server started.
+#[derive(Default)]
+struct Metrics {
+    /// Requests answered by route, method and status
+    requests: Mutex<BTreeMap<(&'static str, &'static str, u16), u64>>,
+
+    /// Request durations by route
+    durations: Mutex<BTreeMap<&'static str, Histogram>>,
+
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    compression_input_bytes: AtomicU64,
+    compression_output_bytes: AtomicU64,
+
+    /// Connections refused because the accept queue was full
+    connections_shed: AtomicU64,
+
+    /// Paths rejected by the path traversal protection, by reason
+    path_traversals: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Worker pool of the threaded runtime, watched for its queue depth.
+static POOL: OnceCell<WorkerPool> = OnceCell::new();
+
+/// Counts a client connection as active for as long as it lives.
+pub struct ActiveConnection(());
+
+impl ActiveConnection {
+    /// Count a new client connection.
+    pub fn open() -> Self {
+        METRICS.connections_total.fetch_add(1, Ordering::Relaxed);
+        METRICS.connections_active.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection(())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        METRICS.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wrap a request handler so that each answered request is counted.
+///
+/// # Arguments
+///
+/// * `respond` - Generates the response to each request.
+pub fn observed<F>(respond: F) -> impl Fn(Request) -> Response
+where
+    F: Fn(Request) -> Response,
+{
+    move |request: Request| {
+        let route = Response::route(&request.target);
+        let method = request.method.to_str();
+        METRICS.received_bytes.fetch_add(request.body.len() as u64, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let response = respond(request);
+        let duration = start.elapsed().as_secs_f64();
+
+        METRICS.sent_bytes.fetch_add(response.body().len() as u64, Ordering::Relaxed);
+        *lock(&METRICS.requests).entry((route, method, response.status_code())).or_default() += 1;
+
+        let mut durations = lock(&METRICS.durations);
+        let histogram = durations.entry(route).or_default();
+        if let Some(i) = DURATION_BUCKETS.iter().position(|&bound| duration <= bound) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += duration;
+
+        response
+    }
+}
+
+/// Watch the queue depth and busy workers of the thread pool.
+pub fn watch_pool(pool: &WorkerPool) {
+    let _ = POOL.set(pool.clone());
+}
+
+/// Get the usage of the thread pool.
+///
+/// Returns `None` with the event runtime, which has no pool.
+pub fn pool_usage() -> Option<PoolUsage> {
+    Some(POOL.get()?.usage())
+}
+
+/// Count a connection refused because the server was overloaded.
+pub fn record_shed() {
+    METRICS.connections_shed.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a body compressed for a response.
+///
+/// # Arguments
+///
+/// * `input` - Size of the body before compression.
+/// * `output` - Size of the compressed body.
+pub fn record_compression(input: usize, output: usize) {
+    METRICS.compression_input_bytes.fetch_add(input as u64, Ordering::Relaxed);
+    METRICS.compression_output_bytes.fetch_add(output as u64, Ordering::Relaxed);
+}
+
+/// Count a path rejected by the path traversal protection.
+///
+/// # Arguments
+///
+/// * `reason` - Why the path was rejected (e.g. "parent_directory").
+pub fn record_path_traversal(reason: &'static str) {
+    *lock(&METRICS.path_traversals).entry(reason).or_default() += 1;
+}
+
+/// Check if a request is for the metrics endpoint of the main listener.
+pub fn is_endpoint(request: &Request) -> bool {
+    let params = &ServerParams::current().metrics;
+    params.port.is_none() && request.target == params.path
+}
+
+/// Answer the requests received by the admin listener.
+///
+/// The admin listener serves the metrics and the health checks.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+pub fn admin_respond(request: Request) -> Response {
+    if request.target == ServerParams::current().metrics.path {
+        respond(request)
+    } else if request.target == "/healthz" {
+        health::healthz(request)
+    } else if request.target == "/readyz" {
+        health::readyz(request)
+    } else {
+        Response::new(request, None, 404, None)
+    }
+}
+
+/// Answer a scrape of the metrics endpoint.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+pub fn respond(request: Request) -> Response {
+    Response::new(request, Some("text/plain; version=0.0.4".to_string()), 200, Some(render()))
+}
+
+/// Render every metric in the Prometheus text format.
+fn render() -> String {
+    let mut out = String::new();
+
+    header(&mut out, "http_requests_total", "counter", "Requests answered, by route, method and status.");
+    for ((route, method, status), count) in lock(&METRICS.requests).iter() {
+        let _ = writeln!(out, "http_requests_total{{route=\"{}\",method=\"{}\",status=\"{}\"}} {}", route, method, status, count);
+    }
+
+    header(&mut out, "http_request_duration_seconds", "histogram", "Time taken to generate responses, by route.");
+    for (route, histogram) in lock(&METRICS.durations).iter() {
+        let mut cumulated = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulated += count;
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, bound, cumulated);
+        }
+        let _ = writeln!(out, "http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, histogram.count);
+        let _ = writeln!(out, "http_request_duration_seconds_sum{{route=\"{}\"}} {}", route, histogram.sum);
+        let _ = writeln!(out, "http_request_duration_seconds_count{{route=\"{}\"}} {}", route, histogram.count);
+    }
+
+    counter(&mut out, "http_request_body_bytes_total", "Bytes received in request bodies.", &METRICS.received_bytes);
+    counter(&mut out, "http_response_body_bytes_total", "Bytes sent in response bodies, streaming bodies excluded.", &METRICS.sent_bytes);
+    counter(&mut out, "http_connections_total", "Client connections accepted.", &METRICS.connections_total);
+
+    header(&mut out, "http_connections_active", "gauge", "Client connections currently open.");
+    let _ = writeln!(out, "http_connections_active {}", METRICS.connections_active.load(Ordering::Relaxed));
+
+    counter(&mut out, "http_connections_shed_total", "Connections refused because the server was overloaded.", &METRICS.connections_shed);
+
+    if let Some(usage) = pool_usage() {
+        header(&mut out, "threadpool_workers", "gauge", "Worker threads of the pool.");
+        let _ = writeln!(out, "threadpool_workers {}", usage.workers);
+        header(&mut out, "threadpool_max_workers", "gauge", "Worker threads the pool can grow to.");
+        let _ = writeln!(out, "threadpool_max_workers {}", usage.max_workers);
+        header(&mut out, "threadpool_active_workers", "gauge", "Worker threads serving a connection.");
+        let _ = writeln!(out, "threadpool_active_workers {}", usage.busy);
+        header(&mut out, "threadpool_queued_connections", "gauge", "Connections waiting for a free worker.");
+        let _ = writeln!(out, "threadpool_queued_connections {}", usage.queued);
+    }
+
+    counter(&mut out, "http_compression_input_bytes_total", "Bytes of response bodies before compression.", &METRICS.compression_input_bytes);
+    counter(&mut out, "http_compression_output_bytes_total", "Bytes of response bodies after compression.", &METRICS.compression_output_bytes);
+
+    header(&mut out, "files_path_traversal_rejections_total", "counter", "File paths rejected by the path traversal protection, by reason.");
+    for (reason, count) in lock(&METRICS.path_traversals).iter() {
+        let _ = writeln!(out, "files_path_traversal_rejections_total{{reason=\"{}\"}} {}", reason, count);
+    }
+
+    out
+}
+
+/// Write the help and type lines of a metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write a counter without labels.
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+/// Lock a mutex, even if a thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
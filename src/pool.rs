@@ -0,0 +1,269 @@
+//! pool.rs
+//!
+//! Worker threads of the threaded runtime.
+//! Connections are accepted by a thread per listener and handed to the pool,
+//! whose size grows on demand between its minimum and maximum and shrinks
+//! back once workers stay idle.
+//!
+//! Connections waiting for a worker are kept in a bounded queue. Once it is
+//! full, new connections are refused so that the server sheds load instead of
+//! letting the wait grow without limit.
+//!
+//! The pool is also shared with HTTP/2 connections, which run the handlers of
+//! their streams on it so that a slow stream does not hold up the others.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+/// Upper bound of the number of workers, to keep a typo from exhausting the system.
+pub const MAX_WORKERS: usize = 4096;
+
+/// Work waiting for a free worker.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Pool of the threaded runtime, running the streams of HTTP/2 connections.
+static SHARED: OnceCell<WorkerPool> = OnceCell::new();
+
+/// Sizing parameters of the worker pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolParams {
+    /// Workers kept alive even when idle
+    pub min_threads: usize,
+
+    /// Workers started at most
+    pub max_threads: usize,
+
+    /// Connections waiting for a worker at most, before new ones are refused
+    pub accept_queue: usize,
+
+    /// Time after which an idle worker above the minimum stops
+    pub idle_timeout: Duration,
+}
+
+/// Usage of the worker pool at a point in time.
+pub struct PoolUsage {
+    /// Workers serving a connection
+    pub busy: usize,
+
+    /// Connections waiting for a worker
+    pub queued: usize,
+
+    /// Workers currently started
+    pub workers: usize,
+
+    /// Workers started at most
+    pub max_workers: usize,
+}
+
+/// Jobs and workers of the pool.
+struct State {
+    jobs: VecDeque<Job>,
+    workers: usize,
+    idle: usize,
+}
+
+struct Shared {
+    params: PoolParams,
+    state: Mutex<State>,
+
+    /// Signaled when a job is queued
+    queued: Condvar,
+}
+
+/// Pool of worker threads with a bounded queue.
+#[derive(Clone)]
+pub struct WorkerPool {
+    shared: Arc<Shared>,
+}
+
+impl WorkerPool {
+    /// Create a pool and start its minimum number of workers.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Sizing parameters of the pool.
+    pub fn new(params: PoolParams) -> Self {
+        let pool = WorkerPool {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State { jobs: VecDeque::new(), workers: 0, idle: 0 }),
+                queued: Condvar::new(),
+                params,
+            }),
+        };
+
+        let mut state = pool.lock();
+        for _ in 0..pool.shared.params.min_threads {
+            pool.spawn_worker(&mut state);
+        }
+        drop(state);
+
+        pool
+    }
+
+    /// Run `job` with `value` on a worker.
+    ///
+    /// A worker is started if none is free and the pool is below its maximum,
+    /// otherwise the job waits in the queue. Returns `value` back if the queue
+    /// is full, so that the caller can refuse it.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value handed to the job, e.g. the accepted connection.
+    /// * `job` - Work to run on a worker.
+    pub fn execute<T, F>(&self, value: T, job: F) -> Result<(), T>
+    where
+        T: Send + 'static,
+        F: FnOnce(T) + Send + 'static,
+    {
+        let params = &self.shared.params;
+        let mut state = self.lock();
+
+        // Idle workers not yet claimed by a queued job
+        let free = state.idle.saturating_sub(state.jobs.len());
+        if free == 0 {
+            if state.workers < params.max_threads {
+                self.spawn_worker(&mut state);
+            } else if state.jobs.len() >= params.accept_queue {
+                return Err(value);
+            }
+        }
+
+        state.jobs.push_back(Box::new(move || job(value)));
+        drop(state);
+        self.shared.queued.notify_one();
+        Ok(())
+    }
+
+    /// Run `job` on a worker only if one is free or can be started.
+    ///
+    /// Unlike `execute`, the job is never queued behind the connections
+    /// waiting for a worker. Returns `job` back if no worker is available.
+    ///
+    /// # Arguments
+    ///
+    /// * `job` - Work to run on a worker.
+    pub fn execute_now<F>(&self, job: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.lock();
+        if state.idle <= state.jobs.len() {
+            if state.workers >= self.shared.params.max_threads {
+                return Err(job);
+            }
+            self.spawn_worker(&mut state);
+        }
+
+        state.jobs.push_back(Box::new(job));
+        drop(state);
+        self.shared.queued.notify_one();
+        Ok(())
+    }
+
+    /// Share the pool with the HTTP/2 connections, to run the handlers of their streams.
+    pub fn share(&self) {
+        let _ = SHARED.set(self.clone());
+    }
+
+    /// Get the current usage of the pool.
+    pub fn usage(&self) -> PoolUsage {
+        let state = self.lock();
+        PoolUsage {
+            busy: state.workers - state.idle,
+            queued: state.jobs.len(),
+            workers: state.workers,
+            max_workers: self.shared.params.max_threads,
+        }
+    }
+
+    /// Get the number of jobs running or waiting for a worker.
+    pub fn pending(&self) -> usize {
+        let usage = self.usage();
+        usage.busy + usage.queued
+    }
+
+    /// Start a new idle worker.
+    fn spawn_worker(&self, state: &mut State) {
+        let pool = self.clone();
+        let spawned = thread::Builder::new()
+            .name("worker".to_string())
+            .spawn(move || pool.work());
+
+        match spawned {
+            Ok(_) => {
+                state.workers += 1;
+                state.idle += 1;
+            }
+            Err(e) => tracing::error!("Error starting worker thread: {}", e),
+        }
+    }
+
+    /// Run the queued jobs until the worker has been idle for too long.
+    fn work(self) {
+        let params = &self.shared.params;
+        loop {
+            let job = {
+                let mut state = self.lock();
+                loop {
+                    if let Some(job) = state.jobs.pop_front() {
+                        state.idle -= 1;
+                        break job;
+                    }
+
+                    let (guard, timeout) = match self.shared.queued.wait_timeout(state, params.idle_timeout) {
+                        Ok(result) => result,
+                        Err(poisoned) => poisoned.into_inner(),
+                    };
+                    state = guard;
+
+                    // Shrink back towards the minimum
+                    if timeout.timed_out() && state.jobs.is_empty() && state.workers > params.min_threads {
+                        state.workers -= 1;
+                        state.idle -= 1;
+                        return;
+                    }
+                }
+            };
+
+            // A panicking connection must not take the worker down with it
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                tracing::error!("Worker thread panicked while serving a connection");
+            }
+
+            self.lock().idle += 1;
+        }
+    }
+
+    /// Lock the state of the pool, even if a thread panicked while holding it.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        match self.shared.state.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+/// Run a job on the shared pool, or on its own thread if no pool is shared (event runtime).
+///
+/// Returns `job` back if no worker is available, so that the caller can run it itself.
+///
+/// # Arguments
+///
+/// * `job` - Work to run.
+pub fn spawn<F>(job: F) -> Result<(), F>
+where
+    F: FnOnce() + Send + 'static,
+{
+    match SHARED.get() {
+        Some(pool) => pool.execute_now(job),
+        None => {
+            thread::spawn(job);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! proxy.rs
+//!
+//! PROXY protocol, versions 1 (text) and 2 (binary).
+//! A load balancer forwarding TCP connections, e.g. HAProxy with
+//! `send-proxy`, starts each of them with a header giving the address of its
+//! client. The header is only read from the proxies trusted by the server, and
+//! the address it carries replaces the peer address of the connection.
+
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::connection::Stream;
+use crate::shutdown;
+use crate::utils::ServerParams;
+
+/// Signature starting the headers of version 2.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest header of version 1, line ending included.
+const V1_MAX_LEN: usize = 107;
+
+/// Time given to a trusted proxy to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check if a connection from `peer` must start with a PROXY header.
+///
+/// # Arguments
+///
+/// * `peer` - Address of the other end of the socket.
+pub fn is_trusted(peer: &SocketAddr) -> bool {
+    ServerParams::current().proxy_protocol.iter().any(|cidr| cidr.contains(&peer.ip()))
+}
+
+/// Read the PROXY header starting a connection from a trusted proxy.
+///
+/// Bytes are read up to the end of the header only, so that the rest of the
+/// connection, e.g. a TLS handshake, is left untouched. The client address
+/// replaces the peer address of `stream`, unless the proxy sent the
+/// connection on its own behalf (e.g. a health check).
+///
+/// # Arguments
+///
+/// * `stream` - The connection, with a read timeout set.
+pub fn accept(stream: &mut Stream) -> io::Result<()> {
+    let deadline = Instant::now() + HEADER_TIMEOUT;
+    let mut header = Vec::new();
+
+    loop {
+        match parse(&header) {
+            Ok(Some((_, Some(addr)))) => {
+                stream.set_proxied_addr(addr);
+                return Ok(());
+            }
+            Ok(Some((_, None))) => return Ok(()),
+            Ok(None) => {}
+            Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+        }
+
+        let mut chunk = vec![0; wanted(&header) - header.len()];
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => header.extend_from_slice(&chunk[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                if Instant::now() >= deadline || shutdown::is_requested() {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Get the length the header has at least, given its first bytes.
+fn wanted(header: &[u8]) -> usize {
+    if header.len() < 8 {
+        8
+    } else if header.starts_with(b"PROXY") {
+        // Lines are read a byte at a time, not to read past their end
+        header.len() + 1
+    } else if header.len() < 16 {
+        16
+    } else {
+        16 + u16::from_be_bytes([header[14], header[15]]) as usize
+    }
+}
+
+/// Parse a PROXY header at the start of `buf`.
+///
+/// Returns `None` while the header is incomplete, otherwise its length and
+/// the address of the client, `None` when the proxy does not forward a client.
+///
+/// # Arguments
+///
+/// * `buf` - Bytes received at the start of the connection.
+pub fn parse(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    if buf.first() == Some(&b'P') {
+        parse_v1(buf)
+    } else if buf.first() == Some(&b'\r') {
+        parse_v2(buf)
+    } else if buf.is_empty() {
+        Ok(None)
+    } else {
+        Err("Missing PROXY protocol header".to_string())
+    }
+}
+
+/// Parse a header of version 1, e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+fn parse_v1(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    let prefix_len = buf.len().min(6);
+    if buf[..prefix_len] != b"PROXY "[..prefix_len] {
+        return Err("Missing PROXY protocol header".to_string());
+    }
+
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Err("PROXY protocol header too long".to_string()),
+        None => return Ok(None),
+    };
+    let len = end + 2;
+
+    let line = std::str::from_utf8(&buf[..end]).map_err(|_| "Invalid PROXY protocol header".to_string())?;
+    let fields = line.split(' ').collect::<Vec<&str>>();
+    let invalid = || format!("Invalid PROXY protocol header: {}", line);
+
+    match fields.get(1) {
+        Some(&"UNKNOWN") => Ok(Some((len, None))),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip = match fields[1] {
+                "TCP4" => fields[2].parse::<Ipv4Addr>().map(IpAddr::V4).map_err(|_| invalid())?,
+                _ => fields[2].parse::<Ipv6Addr>().map(IpAddr::V6).map_err(|_| invalid())?,
+            };
+            let port = fields[4].parse::<u16>().map_err(|_| invalid())?;
+            Ok(Some((len, Some(SocketAddr::new(ip, port)))))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parse a header of version 2, whose addresses follow a fixed 16 bytes part.
+fn parse_v2(buf: &[u8]) -> Result<Option<(usize, Option<SocketAddr>)>, String> {
+    let prefix_len = buf.len().min(V2_SIGNATURE.len());
+    if buf[..prefix_len] != V2_SIGNATURE[..prefix_len] {
+        return Err("Missing PROXY protocol header".to_string());
+    }
+    if buf.len() < 16 {
+        return Ok(None);
+    }
+
+    let (version, command, family) = (buf[12] >> 4, buf[12] & 0x0f, buf[13]);
+    if version != 2 {
+        return Err(format!("Unsupported PROXY protocol version {}", version));
+    }
+
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addresses = &buf[16..len];
+
+    match command {
+        // LOCAL: the connection comes from the proxy itself
+        0x0 => Ok(Some((len, None))),
+        // PROXY: the source address of the client comes first
+        0x1 => match family {
+            0x11 if addresses.len() >= 12 => {
+                let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+                Ok(Some((len, Some(SocketAddr::new(IpAddr::V4(ip), port)))))
+            }
+            0x21 if addresses.len() >= 36 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addresses[..16]);
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Ok(Some((len, Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))))
+            }
+            // Unix sockets and unspecified families carry no usable address
+            0x00 | 0x31 | 0x32 => Ok(Some((len, None))),
+            _ => Err(format!("Invalid PROXY protocol address family {:#04x}", family)),
+        },
+        _ => Err(format!("Invalid PROXY protocol command {:#x}", command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header length and client address expected from `parse`, `None` for an incomplete header.
+    type Expected = Option<(usize, Option<&'static str>)>;
+
+    /// Build a version 2 header with the given command byte, address family and address block.
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1_headers() {
+        let cases: [(&[u8], Expected); 7] = [
+            (b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n", Some((42, Some("192.0.2.1:56324")))),
+            (b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n", Some((45, Some("[2001:db8::1]:4000")))),
+            (b"PROXY UNKNOWN\r\n", Some((15, None))),
+            (b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n", Some((35, None))),
+            (b"PROXY TCP4 192.0.2.1 192.0.2.2", None),
+            (b"PROX", None),
+            (b"", None),
+        ];
+        for (buf, expected) in cases {
+            let expected = expected.map(|(len, addr)| (len, addr.map(|a| a.parse::<SocketAddr>().unwrap())));
+            assert_eq!(parse(buf), Ok(expected), "{}", String::from_utf8_lossy(buf));
+        }
+    }
+
+    #[test]
+    fn v1_malformed_headers() {
+        let too_long = [b"PROXY TCP4 ".as_slice(), &[b'1'; V1_MAX_LEN]].concat();
+        let cases: [&[u8]; 10] = [
+            b"GET / HTTP/1.1\r\n",
+            b"PRONTO TCP4\r\n",
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 65536 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n",
+            b"PROXY TCP4  192.0.2.1 192.0.2.2 56324 443\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.\xff 192.0.2.2 56324 443\r\n",
+            &too_long,
+        ];
+        for buf in cases {
+            assert!(parse(buf).is_err(), "{}", String::from_utf8_lossy(buf));
+        }
+    }
+
+    #[test]
+    fn v2_headers() {
+        let ipv4 = [192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        let mut ipv6 = [0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        let with_tlv = [&ipv4[..], &[0x04, 0x00, 0x01, 0x00]].concat();
+
+        let cases: [(Vec<u8>, Expected); 7] = [
+            (v2(0x21, 0x11, &ipv4), Some((28, Some("192.0.2.1:56324")))),
+            (v2(0x21, 0x21, &ipv6), Some((52, Some("[2001:db8::1]:4000")))),
+            (v2(0x21, 0x11, &with_tlv), Some((32, Some("192.0.2.1:56324")))),
+            (v2(0x20, 0x00, &[]), Some((16, None))),
+            (v2(0x21, 0x31, &[0; 216]), Some((232, None))),
+            (v2(0x21, 0x11, &ipv4)[..20].to_vec(), None),
+            (V2_SIGNATURE[..5].to_vec(), None),
+        ];
+        for (buf, expected) in cases {
+            let expected = expected.map(|(len, addr)| (len, addr.map(|a| a.parse::<SocketAddr>().unwrap())));
+            assert_eq!(parse(&buf), Ok(expected), "{:?}", buf);
+        }
+    }
+
+    #[test]
+    fn v2_malformed_headers() {
+        let cases: [Vec<u8>; 6] = [
+            b"\r\n\r\n\0\r\nQUIX\n\x21\x11\x00\x00".to_vec(),
+            v2(0x11, 0x11, &[0; 12]),
+            v2(0x22, 0x11, &[0; 12]),
+            v2(0x21, 0x11, &[0; 4]),
+            v2(0x21, 0x21, &[0; 12]),
+            v2(0x21, 0x51, &[0; 12]),
+        ];
+        for buf in cases {
+            assert!(parse(&buf).is_err(), "{:?}", buf);
+        }
+    }
+}
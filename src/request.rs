@@ -3,6 +3,11 @@
 //! This file defines the `Request` struct and its methods.
 //! It is responsible for parsing incoming HTTP requests into structured data.
 
+use std::net::{IpAddr, SocketAddr};
+
+use crate::forwarded;
+use crate::jwt::Claims;
+use crate::tls::PeerIdentity;
 use crate::utils::{HTTPMethod, HTTPVersion, RequestParseError};
 
 /// `Request` struct represents an HTTP request.
@@ -22,25 +27,53 @@ pub struct Request {
     /// The headers of the request
     pub headers: Vec<(String, String)>,
 
-    /// The body of the request
-    pub body: String,
+    /// The body of the request, as received
+    pub body: Vec<u8>,
+
+    /// The identity of the client certificate, if one was verified
+    pub peer_identity: Option<PeerIdentity>,
+
+    /// The address of the client, as given by a trusted proxy with the PROXY protocol
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Whether the request was received over TLS
+    pub secure: bool,
+
+    /// The user authenticated by the credentials of the request, if a rule required them
+    pub user: Option<String>,
+
+    /// The claims of the JWT the user was authenticated with, if any
+    pub claims: Option<Claims>,
 }
 
 impl Request{
-    /// Create a new `Request` object from a string.
+    /// Create a new `Request` object from the bytes of a request.
     ///
-    /// Reads and parse the incoming HTTP request string.
+    /// Reads and parse the incoming HTTP request.
     /// Checks the validity of the request line, headers, and body.
+    /// The body is kept as is, it may be binary.
     ///
     /// # Arguments
     ///
-    /// * `request` - The incoming HTTP request string.
-    pub fn from_str(request: String) -> Result<Self, RequestParseError>{
-        // Separate the blocks for the request line, headers and body
-        let blocks = request.split("\r\n").collect::<Vec<&str>>();
+    /// * `request` - The bytes of the incoming HTTP request.
+    pub fn from_bytes(request: &[u8]) -> Result<Self, RequestParseError>{
+        // Separate the head (request line and headers) from the body
+        let (head, body) = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(i) => (String::from_utf8_lossy(&request[..i]), request[i + 4..].to_vec()),
+            None => (String::from_utf8_lossy(request), Vec::new()),
+        };
+
+        // Separate the blocks for the request line and headers
+        let blocks = head.split("\r\n").collect::<Vec<&str>>();
+
+        // A bare CR or LF would end the line when forwarded, e.g. to an upstream
+        if !blocks.iter().all(|block| is_valid_field(block)) {
+            tracing::debug!("Invalid character in the request head");
+            return Err(RequestParseError::InvalidHeader)
+        }
 
         if blocks.is_empty() {
-            println!("Empty request");
+            tracing::debug!("Empty request");
             return Err(RequestParseError::InvalidRequestLine)
         }
 
@@ -48,7 +81,7 @@ impl Request{
         let request_line = blocks[0].split_whitespace().collect::<Vec<&str>>();
 
         if request_line.len() != 3 {
-            println!("Invalid request line");
+            tracing::debug!("Invalid request line");
             return Err(RequestParseError::InvalidRequestLine)
         }
 
@@ -61,7 +94,7 @@ impl Request{
         let method = match method{
             Some(m) => m,
             None => {
-                println!("Invalid method");
+                tracing::debug!("Invalid method");
                 return Err(RequestParseError::InvalidMethod)
             }
         };
@@ -70,7 +103,7 @@ impl Request{
         let target = request_line[1].to_string();
 
         if target.is_empty() {
-            println!("Invalid target");
+            tracing::debug!("Invalid target");
             return Err(RequestParseError::InvalidRequestLine)
         }
 
@@ -82,26 +115,23 @@ impl Request{
         let http_version = match http_version {
             Some(v) => v,
             None => {
-                println!("Invalid version");
+                tracing::debug!("Invalid version");
                 return Err(RequestParseError::InvalidVersion)
             }
         };
 
-        // Get the headers and body
+        // Get the headers
         let mut headers = Vec::new();
-        let mut body = String::new();
-
-        for i in 1..blocks.len() {
-            let header = blocks[i].split(": ").collect::<Vec<&str>>();
-            if header.len() == 2 {
-                headers.push((header[0].to_string(), header[1].to_string()));
-            } else if header.len() == 1 {
-                body = blocks[i].to_string();
-            }
-        }
 
-        if headers.is_empty() {
-            return Err(RequestParseError::InvalidHeader)
+        // Requests without headers are valid, a missing Host is answered by the routes
+        for line in blocks.iter().skip(1) {
+            match line.split_once(':') {
+                Some((key, value)) if is_token(key) => headers.push((key.to_string(), value.trim().to_string())),
+                _ => {
+                    tracing::debug!("Invalid header line");
+                    return Err(RequestParseError::InvalidHeader)
+                }
+            }
         }
 
         // Create the request object
@@ -111,7 +141,194 @@ impl Request{
             http_version,
             headers,
             body,
+            peer_identity: None,
+            peer_addr: None,
+            secure: false,
+            user: None,
+            claims: None,
         })
 
     }
-}
\ No newline at end of file
+
+    /// Get the value of the first header named `name`, ignoring case.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the header.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.trim())
+    }
+
+    /// Get the values of all the headers named `name`, ignoring case, in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the header.
+    pub fn header_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+
+    /// Get the address of the client, behind the trusted proxies.
+    ///
+    /// Falls back to the peer address when no trusted proxy forwarded the request.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        forwarded::origin(self).ip
+    }
+
+    /// Get the scheme used by the client, "http" or "https", behind the trusted proxies.
+    pub fn scheme(&self) -> String {
+        forwarded::origin(self).scheme
+    }
+
+    /// Get the host requested by the client, behind the trusted proxies.
+    pub fn host(&self) -> Option<String> {
+        forwarded::origin(self).host
+    }
+
+    /// Check if the target and every header of the request are valid fields, with token names.
+    pub fn has_valid_fields(&self) -> bool {
+        is_valid_field(&self.target) && self.headers.iter().all(|(k, v)| is_token(k) && is_valid_field(v))
+    }
+
+    /// Get the length of the first complete request held in a buffer.
+    ///
+    /// A request is complete once the empty line closing its headers has been
+    /// received, followed by as many body bytes as announced by `Content-Length`.
+    /// Returns `None` while more data is needed.
+    ///
+    /// Chunked bodies are not supported, so any `Transfer-Encoding` is refused
+    /// rather than reading its chunks as the next request, and so are invalid
+    /// or conflicting `Content-Length` headers.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - Bytes received so far on the connection.
+    pub fn message_len(buffer: &[u8]) -> Result<Option<usize>, RequestParseError> {
+        let head_len = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(i) => i + 4,
+            None => return Ok(None),
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_len]);
+
+        let mut content_length = None;
+        for (name, value) in head.split("\r\n").skip(1).filter_map(|line| line.split_once(':')) {
+            // A name such as "Content-Length " would be framed differently by other servers
+            if !is_token(name) {
+                return Err(RequestParseError::InvalidHeader);
+            }
+            if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                tracing::debug!("Unsupported transfer encoding: {}", value.trim());
+                return Err(RequestParseError::UnsupportedTransferEncoding);
+            }
+            if !name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            // Repeated values, in one header or several, must all agree
+            for length in value.split(',').map(str::trim) {
+                let length = Some(length)
+                    .filter(|l| !l.is_empty() && l.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|l| l.parse::<usize>().ok())
+                    .ok_or(RequestParseError::InvalidContentLength)?;
+                if content_length.is_some_and(|known| known != length) {
+                    return Err(RequestParseError::InvalidContentLength);
+                }
+                content_length = Some(length);
+            }
+        }
+
+        let total = head_len
+            .checked_add(content_length.unwrap_or(0))
+            .ok_or(RequestParseError::InvalidContentLength)?;
+        Ok((buffer.len() >= total).then_some(total))
+    }
+}
+
+/// Check if a header name is a token (RFC 9110), without whitespace or separators.
+///
+/// # Arguments
+///
+/// * `name` - The header name to check.
+pub fn is_token(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Check if a field of a request, e.g. a header value, can be written on one line.
+///
+/// CR, LF and NUL are refused, as they could end the line and inject headers.
+///
+/// # Arguments
+///
+/// * `field` - The name, value or target to check.
+pub fn is_valid_field(field: &str) -> bool {
+    !field.contains(['\r', '\n', '\0'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_len_frames_requests() {
+        let cases: [(&[u8], Option<usize>); 6] = [
+            (b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", Some(27)),
+            (b"GET / HTTP/1.1\r\nHost: a\r\n", None),
+            (b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nab", Some(40)),
+            (b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nab", None),
+            (b"POST / HTTP/1.1\r\ncontent-length: 2\r\nContent-Length: 2\r\n\r\nabGET", Some(59)),
+            (b"POST / HTTP/1.1\r\nContent-Length: 2, 2\r\n\r\nab", Some(43)),
+        ];
+        for (buffer, expected) in cases {
+            assert_eq!(Request::message_len(buffer).ok(), Some(expected), "{}", String::from_utf8_lossy(buffer));
+        }
+    }
+
+    #[test]
+    fn message_len_refuses_ambiguous_framing() {
+        let cases: [(&[u8], u16); 6] = [
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n", 501),
+            (b"POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n", 501),
+            (b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\n", 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n", 400),
+        ];
+        for (buffer, status) in cases {
+            match Request::message_len(buffer) {
+                Err(e) => assert_eq!(e.status_code(), status, "{}", String::from_utf8_lossy(buffer)),
+                Ok(len) => panic!("{} framed as {:?}", String::from_utf8_lossy(buffer), len),
+            }
+        }
+    }
+
+    #[test]
+    fn header_names_must_be_tokens() {
+        let cases: [&[u8]; 4] = [
+            b"POST / HTTP/1.1\r\nContent-Length : 5\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\n Content-Length: 5\r\n\r\nhello",
+            b"GET / HTTP/1.1\r\nX A: b\r\n\r\n",
+            b"GET / HTTP/1.1\r\n: b\r\n\r\n",
+        ];
+        for buffer in cases {
+            assert!(Request::message_len(buffer).is_err(), "{}", String::from_utf8_lossy(buffer));
+            assert!(Request::from_bytes(buffer).is_err(), "{}", String::from_utf8_lossy(buffer));
+        }
+        assert!(Request::from_bytes(b"GET / HTTP/1.1\r\nX-Custom_1.a~: b\r\n\r\n").is_ok());
+    }
+
+    #[test]
+    fn from_bytes_refuses_line_breaks_in_fields() {
+        let cases: [&[u8]; 3] = [
+            b"GET / HTTP/1.1\r\nX-A: a\nInjected: 1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX-A: a\rb\r\n\r\n",
+            b"GET /a\0b HTTP/1.1\r\nHost: a\r\n\r\n",
+        ];
+        for buffer in cases {
+            assert!(Request::from_bytes(buffer).is_err(), "{}", String::from_utf8_lossy(buffer));
+        }
+        let request = Request::from_bytes(b"POST / HTTP/1.1\r\nHost: a\r\n\r\n\xff\x00").unwrap();
+        assert_eq!(request.body, b"\xff\x00");
+    }
+}
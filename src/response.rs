@@ -8,12 +8,20 @@
 extern crate flate2;
 
 use std::vec;
-use std::io::Write;
+use std::io::{self, Write};
 use flate2::Compression;
 
+use crate::cgi;
+use crate::connection::Connection;
 use crate::request::Request;
-use crate::files::{get_file_content, create_file};
-use crate::utils::{HTTPVersion, HTTP_RESPONSE_CODES, COMPRESSION_ALGORITHMS, HTTPMethod};
+use crate::files::{can_access, can_write, get_file_content, create_file, resolve_file, tail_file, tail_file_events};
+use crate::health;
+use crate::metrics;
+use crate::sse;
+use crate::websocket;
+use crate::utils::{HTTPVersion, HTTP_RESPONSE_CODES, COMPRESSION_ALGORITHMS, CompressionPolicy, HTTPMethod, ServerParams};
+use crate::upstream;
+use crate::vhost;
 
 /// `Response` struct represents an HTTP response.
 /// 
@@ -30,6 +38,45 @@ pub struct Response{
 
     /// The body of the response
     body: Option<Vec<u8>>,
+
+    /// Takes over the connection once the response is sent
+    takeover: Option<Takeover>,
+
+    /// Produces the body over time, instead of a body known in advance
+    streaming_body: Option<StreamingBody>,
+
+    /// User the request was authenticated as, for the access log
+    user: Option<String>,
+}
+
+/// Serves a connection taken over by a route, e.g. after a protocol upgrade.
+///
+/// It receives the client's connection and the bytes already received after the request.
+pub type Takeover = Box<dyn FnOnce(&mut dyn Connection, Vec<u8>) + Send>;
+
+/// Writes the body of a streaming response, each flush is sent to the client immediately.
+pub type StreamingBody = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+/// Writes each chunk of a body with the HTTP/1.1 chunked transfer encoding.
+struct ChunkedWriter<'a> {
+    stream: &'a mut dyn Write,
+}
+
+impl Write for ChunkedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if !buf.is_empty() {
+            let mut chunk = format!("{:x}\r\n", buf.len()).into_bytes();
+            chunk.extend_from_slice(buf);
+            chunk.extend_from_slice(b"\r\n");
+            self.stream.write_all(&chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
 }
 
 impl Response{
@@ -44,8 +91,11 @@ impl Response{
     /// * `status_code` - The HTTP status code (e.g., 200, 404).
     /// * `body` - The body of the response (e.g., "Hello World!").
     pub fn new(request: Request, content_type: Option<String>, status_code: u16, body: Option<String>) -> Self {
+        let compression = vhost::select(&request).compression;
+
         // Use the HTTP version from the  request
         let http_version = request.http_version;
+        let user = request.user.clone();
 
         // Check if a body is provided
         match body{
@@ -54,8 +104,12 @@ impl Response{
                 let mut headers = vec![];
                 let mut raw_body = b.into_bytes();
 
-                // Check if an "Accept-Encoding" header is present
-                if let Some((_, v)) = request.headers.iter().find(|(k, _)| k == "Accept-Encoding") {
+                // Check if an "Accept-Encoding" header is present, unless compression is disabled for the host
+                let accept_encoding = request.headers
+                    .iter()
+                    .find(|(k, _)| k == "Accept-Encoding")
+                    .filter(|_| compression != CompressionPolicy::Off);
+                if let Some((_, v)) = accept_encoding {
                     for encoding in v.split(',') {
                         let encoding = encoding.trim();
                         // Check if a special encoding is requested
@@ -73,9 +127,14 @@ impl Response{
                                             status_code: 500,
                                             headers: vec![],
                                             body: None,
+                                            takeover: None,
+                                            streaming_body: None,
+                                            user,
                                         }
                                     }
                                 };
+                                metrics::record_compression(content_length.parse().unwrap_or(0), raw_body.len());
+
                                 // Compute the new content length
                                 content_length = raw_body.len().to_string();
                                 break;
@@ -102,20 +161,54 @@ impl Response{
                     status_code,
                     headers,
                     body: Some(raw_body),
+                    takeover: None,
+                    streaming_body: None,
+                    user,
                 }
             },
             // If no body was provided
             None => {
-                return Self {
+                let mut headers = vec![];
+
+                // Check if the "Connection: close" header is present to add it to the response
+                if request.headers.contains(&("Connection".to_string(), "close".to_string())) {
+                    headers.push(("Connection".to_string(), "close".to_string()));
+                }
+
+                // An explicit empty length keeps the connection usable for the next request
+                headers.push(("Content-Length".to_string(), "0".to_string()));
+
+                Self {
                     http_version,
                     status_code,
-                    headers: vec![],
+                    headers,
                     body: None,
+                    takeover: None,
+                    streaming_body: None,
+                    user,
                 }
             }
         }
     }
 
+    /// Create an empty response closing the connection, e.g. to a request that could not be parsed.
+    ///
+    /// # Arguments
+    ///
+    /// * `http_version` - The HTTP version of the connection.
+    /// * `status_code` - The HTTP status code (e.g., 400).
+    pub fn rejected(http_version: HTTPVersion, status_code: u16) -> Self {
+        Self {
+            http_version,
+            status_code,
+            headers: vec![("Connection".to_string(), "close".to_string()), ("Content-Length".to_string(), "0".to_string())],
+            body: None,
+            takeover: None,
+            streaming_body: None,
+            user: None,
+        }
+    }
+
     /// Parse the incoming request and generate a response.
     ///
     /// Check the request target and method to determine the appropriate response.
@@ -124,52 +217,116 @@ impl Response{
     ///
     /// * `request` - The incoming HTTP request.
     pub fn parse_request(request: Request) -> Self {
+        // HTTP/1.1 requests must name their host
+        if matches!(request.http_version, HTTPVersion::Http1_1) && request.header("Host").is_none() {
+            return Response::new(request, None, 400, None);
+        }
+        let site = vhost::select(&request);
+
+        // For the metrics endpoint, return the metrics of the server
+        if metrics::is_endpoint(&request) {
+            metrics::respond(request)
+        }
+        // For the health check endpoints, tell if the server is alive and ready
+        else if request.target == "/healthz" {
+            health::healthz(request)
+        }
+        else if request.target == "/readyz" {
+            health::readyz(request)
+        }
+        // For the proxy routes, forward the request to the upstream server, whatever the virtual host
+        else if let Some(route) = upstream::route(&request.target) {
+            upstream::forward(request, &route)
+        }
+        // For the CGI routes, run the program answering the request, whatever the virtual host
+        else if let Some(route) = cgi::route(&request.target) {
+            cgi::respond(request, &route)
+        }
+        // For the routes the virtual host does not serve, return a 404 Not Found response
+        else if !site.serves(Response::route(&request.target)) {
+            Response::new(request, None, 404, None)
+        }
         // For the "/echo" endpoint, echo back the content
-        if request.target.starts_with("/echo/"){
+        else if request.target.starts_with("/echo/"){
             let content  = request.target.split("/").collect::<Vec<&str>>()[2..].join("/");
-            if content.len() > 0 {
-                return Response::new(request, Some("text/plain".to_string()), 200, Some(content));
+            if !content.is_empty() {
+                Response::new(request, Some("text/plain".to_string()), 200, Some(content))
             }
             else{
-                return Response::new(request, None,  404, None);
+                Response::new(request, None,  404, None)
             }
         }
         // For the "/files" endpoint, handle file creation and retrieval
         else if request.target.starts_with("/files/"){
             let content  = request.target.split("/").collect::<Vec<&str>>()[2..].join("/");
-            if content.len() > 0 {
+            // Check that the user may access the file
+            if !content.is_empty() && !can_access(request.claims.as_ref(), &content) {
+                Response::new(request, None, 403, None)
+            }
+            else if !content.is_empty() {
                 // Check if the request method is POST for file creation
                 if request.method == HTTPMethod::POST{
+                    // Check that the client is allowed to write files
+                    if !can_write(request.peer_identity.as_ref()) {
+                        return Response::new(request, None, 403, None);
+                    }
+
                     // Create the file with the provided content
-                    match create_file(content, request.body.clone()) {
+                    match create_file(&site.files_root, content, request.body.clone()) {
                         Ok(_)  => {
-                            return Response::new(request, None, 201, None);
+                            Response::new(request, None, 201, None)
                         }
                         Err(_) => {
-                            return Response::new(request, None, 500, None);
+                            Response::new(request, None, 500, None)
                         }
                     }
                 }
                 // Else (GET method), handle file retrieval
                 else{
                     // Retrieve the file content
-                    match get_file_content(content) {
+                    match get_file_content(&site.files_root, content) {
                         Some(s) => {
-                            return Response::new(request, Some("application/octet-stream".to_string()), 200, Some(s));
+                            Response::new(request, Some("application/octet-stream".to_string()), 200, Some(s))
                         }
                         None => {
-                            return Response::new(request, None, 404, None);
+                            Response::new(request, None, 404, None)
                         }
                     }
                 }
             }
             else{
-                return Response::new(request, None, 404, None);
+                Response::new(request, None, 404, None)
+            }
+        }
+        // For the "/ws/echo" endpoint, echo back every WebSocket message
+        else if request.target == "/ws/echo"{
+            websocket::upgrade(request, websocket::echo)
+        }
+        // For the "/ws/tail" endpoint, stream the lines appended to a file over a WebSocket
+        else if request.target.starts_with("/ws/tail/"){
+            let content  = request.target.split("/").collect::<Vec<&str>>()[3..].join("/");
+            if !can_access(request.claims.as_ref(), &content) {
+                return Response::new(request, None, 403, None);
+            }
+            match resolve_file(&site.files_root, &content) {
+                Some(path) => websocket::upgrade(request, move |ws| tail_file(ws, path)),
+                None => Response::new(request, None, 404, None),
+            }
+        }
+        // For the "/sse/tail" endpoint, stream the lines appended to a file as Server-Sent Events
+        else if request.target.starts_with("/sse/tail/"){
+            let content  = request.target.split("/").collect::<Vec<&str>>()[3..].join("/");
+            if !can_access(request.claims.as_ref(), &content) {
+                return Response::new(request, None, 403, None);
+            }
+            match resolve_file(&site.files_root, &content) {
+                Some(path) => sse::stream(request, move |events| tail_file_events(events, path)),
+                None => Response::new(request, None, 404, None),
             }
         }
         // For the "/hello" endpoint, return a "Hello World!" message
         else if request.target == "/hello"{
-            return Response::new(request, Some("text/plain".to_string()), 200, Some("Hello World!".to_string()));
+            Response::new(request, Some("text/plain".to_string()), 200, Some("Hello World!".to_string()))
         }
         // For the "/user-agent" endpoint, return the User-Agent header provided by the client
         else if request.target == "/user-agent"{
@@ -181,21 +338,161 @@ impl Response{
             match user_agent {
                 Some(ref ua) => {
                     // If the User-Agent header is present, return it
-                    return Response::new(request, Some("text/plain".to_string()), 200, Some(ua.clone()));
+                    Response::new(request, Some("text/plain".to_string()), 200, Some(ua.clone()))
                 }
                 None => {
                     // If the User-Agent header is not present, return a 400 Bad Request response
-                    return Response::new(request, None, 400, None);
+                    Response::new(request, None, 400, None)
                 }
-            };
+            }
         }
         // For an invalid target, return a 404 Not Found response
         else if request.target != "/" {
-            return Response::new(request, None, 404, None);
+            Response::new(request, None, 404, None)
         }
         // For the root endpoing, return a 200 OK response without content
         else{
-            return Response::new(request, None, 200, None);
+            Response::new(request, None, 200, None)
+        }
+    }
+
+    /// Get the route answering a target, as a pattern grouping its targets.
+    ///
+    /// # Arguments
+    ///
+    /// * `target` - Target of the request (e.g. "/echo/abc").
+    pub fn route(target: &str) -> &'static str {
+        let params = ServerParams::current();
+        if params.metrics.port.is_none() && target == params.metrics.path {
+            "/metrics"
+        } else if params.upstream.routes.iter().any(|route| target.starts_with(&route.prefix)) {
+            "proxy"
+        } else if params.cgi.routes.iter().any(|route| target.starts_with(&route.prefix)) {
+            "cgi"
+        } else if target.starts_with("/echo/") {
+            "/echo/{string}"
+        } else if target.starts_with("/files/") {
+            "/files/{filename}"
+        } else if target.starts_with("/ws/tail/") {
+            "/ws/tail/{filename}"
+        } else if target.starts_with("/sse/tail/") {
+            "/sse/tail/{filename}"
+        } else {
+            match target {
+                "/" => "/",
+                "/hello" => "/hello",
+                "/healthz" => "/healthz",
+                "/readyz" => "/readyz",
+                "/user-agent" => "/user-agent",
+                "/ws/echo" => "/ws/echo",
+                _ => "unmatched",
+            }
+        }
+    }
+
+    /// Get the status code of the response.
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    /// Get the user the request was authenticated as, if an auth rule covered it.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Get the body of the response, empty if there is none.
+    pub fn body(&self) -> &[u8] {
+        self.body.as_deref().unwrap_or_default()
+    }
+
+    /// Create a response whose body is produced over time.
+    ///
+    /// The body is sent with the chunked transfer encoding on HTTP/1.1, and
+    /// until the connection closes on HTTP/1.0.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The incoming HTTP request.
+    /// * `content_type` - The content type of the response (e.g., "text/event-stream").
+    /// * `status_code` - The HTTP status code (e.g., 200).
+    /// * `body` - Writes the body, the response ends when it returns.
+    pub fn streaming<F>(request: Request, content_type: &str, status_code: u16, body: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        let mut response = Response::new(request, None, status_code, None);
+        response.headers.retain(|(k, _)| k != "Content-Length");
+        response.headers.push(("Content-Type".to_string(), content_type.to_string()));
+
+        match response.http_version {
+            HTTPVersion::Http1_1 => response.headers.push(("Transfer-Encoding".to_string(), "chunked".to_string())),
+            HTTPVersion::Http1_0 => response.close_connection(),
+            HTTPVersion::Http2_0 => {}
+        }
+
+        response.streaming_body = Some(Box::new(body));
+        response
+    }
+
+    /// Check if the body of the response is produced over time.
+    pub fn is_streaming(&self) -> bool {
+        self.streaming_body.is_some()
+    }
+
+    /// Produce the body of a streaming response, written after the response head.
+    ///
+    /// Does nothing for other responses.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Where the body is written.
+    pub fn write_streaming_body(&mut self, stream: &mut dyn Write) -> io::Result<()> {
+        let body = match self.streaming_body.take() {
+            Some(b) => b,
+            None => return Ok(()),
+        };
+
+        if !matches!(self.http_version, HTTPVersion::Http1_1) {
+            return body(stream);
+        }
+
+        // The last chunk is empty
+        let mut writer = ChunkedWriter { stream };
+        body(&mut writer)?;
+        writer.stream.write_all(b"0\r\n\r\n")?;
+        writer.stream.flush()
+    }
+
+    /// Take over the connection once the response is sent.
+    ///
+    /// The connection stops serving requests and is handed to `takeover`,
+    /// then closed when it returns.
+    ///
+    /// # Arguments
+    ///
+    /// * `takeover` - Serves the connection, with the bytes already received after the request.
+    pub fn take_over<F>(mut self, takeover: F) -> Self
+    where
+        F: FnOnce(&mut dyn Connection, Vec<u8>) + Send + 'static,
+    {
+        self.takeover = Some(Box::new(takeover));
+        self
+    }
+
+    /// Remove the takeover of the connection, if the route asked for one.
+    pub fn takeover(&mut self) -> Option<Takeover> {
+        self.takeover.take()
+    }
+
+    /// Check if the connection must be closed after this response.
+    pub fn closes_connection(&self) -> bool {
+        self.headers.contains(&("Connection".to_string(), "close".to_string()))
+    }
+
+    /// Ask the client to close the connection after this response.
+    pub fn close_connection(&mut self) {
+        if !self.closes_connection() {
+            self.headers.insert(0, ("Connection".to_string(), "close".to_string()));
         }
     }
 
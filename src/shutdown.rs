@@ -0,0 +1,127 @@
+//! shutdown.rs
+//!
+//! Graceful shutdown of the server.
+//! Listens for SIGTERM and SIGINT, records when the shutdown was requested and
+//! lets the runtimes wait for their active connections up to a deadline.
+//!
+//! SIGUSR2 upgrades the server: a new process is started on the same listening
+//! sockets, then the current one shuts down gracefully once the new one is ready.
+
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::OnceCell;
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR2};
+use signal_hook::iterator::Signals;
+
+use crate::listener;
+
+/// Set once a shutdown signal has been received.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Moment the shutdown was requested, used to compute the draining deadline.
+static REQUESTED_AT: OnceCell<Instant> = OnceCell::new();
+
+/// Check if the server is shutting down.
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Mark the server as shutting down.
+pub fn request() {
+    let _ = REQUESTED_AT.set(Instant::now());
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Get the deadline for active connections to finish.
+///
+/// Returns `None` while no shutdown has been requested.
+///
+/// # Arguments
+///
+/// * `timeout` - Time given to active connections once the shutdown starts.
+pub fn deadline(timeout: Duration) -> Option<Instant> {
+    REQUESTED_AT.get().map(|at| *at + timeout)
+}
+
+/// Start a thread waiting for SIGTERM, SIGINT and SIGUSR2.
+///
+/// The first termination signal requests a graceful shutdown and calls `wake`
+/// so the runtime can stop accepting connections. A second one exits immediately.
+/// SIGUSR2 hands the listening sockets to a new process before shutting down,
+/// and the server keeps running if the new process fails to start.
+///
+/// # Arguments
+///
+/// * `listener_fds` - File descriptors of the listening sockets.
+/// * `wake` - Interrupts the runtime waiting for new connections.
+pub fn listen<F>(listener_fds: Vec<RawFd>, wake: F) -> io::Result<()>
+where
+    F: Fn() + Send + 'static,
+{
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGUSR2])?;
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGUSR2 {
+                // The listening sockets are closed once the shutdown started
+                if is_requested() {
+                    continue;
+                }
+
+                match listener::spawn_successor(&listener_fds) {
+                    Ok(pid) => {
+                        tracing::info!("Started new server process {}, shutting down", pid);
+                        request();
+                        wake();
+                    }
+                    Err(e) => tracing::error!("Error starting new server process: {}", e),
+                }
+                continue;
+            }
+
+            if is_requested() {
+                tracing::warn!("Received signal {} again, exiting immediately", signal);
+                std::process::exit(1);
+            }
+
+            tracing::info!("Received signal {}, shutting down", signal);
+            request();
+            wake();
+        }
+    });
+
+    Ok(())
+}
+
+/// Wait for the active connections to finish.
+///
+/// Returns `true` if every connection finished before the deadline.
+///
+/// # Arguments
+///
+/// * `timeout` - Time given to active connections once the shutdown starts.
+/// * `active` - Returns the number of connections still being served.
+pub fn drain<F>(timeout: Duration, active: F) -> bool
+where
+    F: Fn() -> usize,
+{
+    let deadline = deadline(timeout).unwrap_or_else(|| Instant::now() + timeout);
+
+    loop {
+        let remaining = active();
+        if remaining == 0 {
+            return true;
+        }
+
+        if Instant::now() >= deadline {
+            tracing::warn!("Shutdown deadline reached with {} active connections", remaining);
+            return false;
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,140 @@
+//! sse.rs
+//!
+//! Server-Sent Events (`text/event-stream`) responses.
+//! Routes answer with `stream`, and the given handler then emits events for as
+//! long as it wants. Each event is flushed to the client as soon as it is sent.
+//!
+//! Comments are sent as heartbeats while no event is emitted, so proxies do not
+//! close the connection and disconnected clients are noticed.
+
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::shutdown;
+
+/// Interval of the heartbeat comments sent while no event is emitted.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Longest sleep between checks for a shutdown while waiting.
+const WAIT_SLICE: Duration = Duration::from_millis(100);
+
+/// An event stream open with a client.
+pub struct EventStream<'a> {
+    writer: &'a mut dyn Write,
+
+    /// Identifier of the last event received by the client before reconnecting
+    last_event_id: Option<String>,
+
+    /// Moment something was last sent to the client
+    last_write: Instant,
+}
+
+/// Answer a request with an event stream.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `handler` - Emits the events, the stream ends when it returns.
+pub fn stream<H>(request: Request, handler: H) -> Response
+where
+    H: FnOnce(&mut EventStream) -> io::Result<()> + Send + 'static,
+{
+    let last_event_id = request
+        .headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("Last-Event-ID"))
+        .map(|(_, v)| v.trim().to_string())
+        .filter(|id| !id.is_empty());
+
+    let mut response = Response::streaming(request, "text/event-stream", 200, move |writer| {
+        let mut events = EventStream {
+            writer,
+            last_event_id,
+            last_write: Instant::now(),
+        };
+
+        // Send the head right away, clients wait for it before showing the stream as open
+        events.comment("stream opened")?;
+        handler(&mut events)
+    });
+    response.headers.push(("Cache-Control".to_string(), "no-cache".to_string()));
+    response
+}
+
+impl EventStream<'_> {
+    /// Identifier of the last event received by the client, sent when it reconnects.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Send an event to the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the event, sent back as `Last-Event-ID` when the client reconnects.
+    /// * `event` - Type of the event, "message" if none is given.
+    /// * `data` - Data of the event, it may span several lines.
+    pub fn send(&mut self, id: Option<&str>, event: Option<&str>, data: &str) -> io::Result<()> {
+        let mut message = String::new();
+        if let Some(id) = id {
+            message.push_str(&format!("id: {}\n", single_line(id)));
+        }
+        if let Some(event) = event {
+            message.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        for line in data.split('\n') {
+            message.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        message.push('\n');
+
+        self.write(message.as_bytes())
+    }
+
+    /// Send a comment, ignored by clients.
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        self.write(format!(": {}\n\n", single_line(text)).as_bytes())
+    }
+
+    /// Wait before sending the next event, sending heartbeats meanwhile.
+    ///
+    /// Returns `false` once the server is shutting down, the handler should
+    /// then return to end the stream. Fails if the client is gone.
+    ///
+    /// # Arguments
+    ///
+    /// * `duration` - Time to wait.
+    pub fn wait(&mut self, duration: Duration) -> io::Result<bool> {
+        let until = Instant::now() + duration;
+        loop {
+            if shutdown::is_requested() {
+                return Ok(false);
+            }
+
+            if self.last_write.elapsed() >= HEARTBEAT_INTERVAL {
+                self.comment("heartbeat")?;
+            }
+
+            let now = Instant::now();
+            if now >= until {
+                return Ok(true);
+            }
+            thread::sleep((until - now).min(WAIT_SLICE));
+        }
+    }
+
+    /// Write and flush raw bytes of the stream.
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.writer.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+/// Keep the first line of a field, line breaks would end it.
+fn single_line(value: &str) -> &str {
+    value.split(['\r', '\n']).next().unwrap_or_default()
+}
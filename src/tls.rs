@@ -0,0 +1,351 @@
+//! tls.rs
+//!
+//! HTTPS support of the server, built on `rustls`.
+//! Loads the certificates, selects one by SNI for each handshake, reloads them
+//! when the files change and answers the optional HTTP to HTTPS redirect listener.
+//!
+//! Clients can also be authenticated by certificate, the identity of verified
+//! certificates is then exposed on each `Request`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::CryptoProvider;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConnection, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use x509_parser::extensions::GeneralName;
+
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::ClientAuth;
+
+/// Paths of a PEM certificate chain and its private key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CertPaths {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl CertPaths {
+    /// Parse a `CERT,KEY` pair of paths.
+    pub fn from_str(paths: &str) -> Option<CertPaths> {
+        let (cert, key) = paths.split_once(',')?;
+        if cert.is_empty() || key.is_empty() {
+            return None;
+        }
+
+        Some(CertPaths {
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+        })
+    }
+}
+
+/// TLS parameters of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsParams {
+    /// Certificate used when no SNI entry matches
+    pub default_cert: Option<CertPaths>,
+
+    /// Certificates selected by server name, wildcards such as `*.example.com` are allowed
+    pub sni_certs: Vec<(String, CertPaths)>,
+
+    /// Interval at which the certificate files are checked for changes
+    pub reload_interval: Duration,
+
+    /// Port of the plaintext listener redirecting to HTTPS
+    pub redirect_port: Option<u16>,
+
+    /// PEM bundle of the authorities signing client certificates
+    pub client_ca: Option<PathBuf>,
+
+    /// Whether clients must present a certificate
+    pub client_auth: ClientAuth,
+}
+
+/// Identity of a client certificate verified during the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct PeerIdentity {
+    /// Distinguished name of the subject (e.g. "CN=client, O=Example")
+    pub subject: String,
+
+    /// Common name of the subject
+    pub common_name: Option<String>,
+
+    /// DNS names, e-mails, URIs and IP addresses of the subject alternative names
+    pub sans: Vec<String>,
+}
+
+impl PeerIdentity {
+    /// Check if the certificate was issued to `name`, as subject, common name or alternative name.
+    pub fn matches(&self, name: &str) -> bool {
+        self.subject == name || self.common_name.as_deref() == Some(name) || self.sans.iter().any(|san| san == name)
+    }
+}
+
+/// Certificates currently served.
+#[derive(Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Selects the certificate of each handshake from the server name sent by the client.
+struct CertResolver {
+    current: RwLock<Certificates>,
+}
+
+impl fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertResolver").finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.current.read().ok()?;
+
+        if let Some(name) = client_hello.server_name() {
+            let name = name.to_ascii_lowercase();
+            if let Some(cert) = certs.by_name.get(&name) {
+                return Some(cert.clone());
+            }
+
+            // A wildcard covers a single label
+            if let Some((_, parent)) = name.split_once('.') {
+                if let Some(cert) = certs.by_name.get(&format!("*.{}", parent)) {
+                    return Some(cert.clone());
+                }
+            }
+        }
+
+        certs.default.clone()
+    }
+}
+
+/// Build the TLS configuration of the server.
+///
+/// The certificates are loaded immediately, then a thread reloads them
+/// whenever one of the files changes.
+///
+/// # Arguments
+///
+/// * `params` - TLS parameters of the server.
+pub fn server_config(params: &TlsParams) -> Result<Arc<ServerConfig>, String> {
+    let resolver = Arc::new(CertResolver {
+        current: RwLock::new(load_certificates(params)?),
+    });
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Invalid TLS configuration: {}", e))?;
+
+    let builder = match client_verifier(params, provider)? {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(resolver.clone());
+
+    // Prefer HTTP/2, clients without ALPN fall back to HTTP/1.1
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    watch_certificates(params.clone(), resolver);
+
+    Ok(Arc::new(config))
+}
+
+/// Build the verifier of client certificates, if client authentication is enabled.
+fn client_verifier(params: &TlsParams, provider: Arc<CryptoProvider>) -> Result<Option<Arc<dyn ClientCertVerifier>>, String> {
+    let ca_path = match (&params.client_ca, params.client_auth) {
+        (Some(path), ClientAuth::Optional | ClientAuth::Required) => path,
+        _ => return Ok(None),
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in load_chain(ca_path)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("Invalid client CA certificate in {}: {}", ca_path.display(), e))?;
+    }
+
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    if params.client_auth == ClientAuth::Optional {
+        builder = builder.allow_unauthenticated();
+    }
+
+    builder
+        .build()
+        .map(Some)
+        .map_err(|e| format!("Invalid client authentication configuration: {}", e))
+}
+
+/// Get the identity of the client certificate verified on a connection.
+///
+/// Returns `None` if the client did not present a certificate.
+///
+/// # Arguments
+///
+/// * `connection` - The TLS connection of the client.
+pub fn peer_identity(connection: &ServerConnection) -> Option<PeerIdentity> {
+    let cert = connection.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+
+    let common_name = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string());
+
+    let mut sans = Vec::new();
+    if let Ok(Some(extension)) = cert.subject_alternative_name() {
+        for name in &extension.value.general_names {
+            match name {
+                GeneralName::DNSName(n) | GeneralName::RFC822Name(n) | GeneralName::URI(n) => sans.push(n.to_string()),
+                GeneralName::IPAddress(bytes) => {
+                    let ip = match bytes.len() {
+                        4 => <[u8; 4]>::try_from(*bytes).ok().map(IpAddr::from),
+                        16 => <[u8; 16]>::try_from(*bytes).ok().map(IpAddr::from),
+                        _ => None,
+                    };
+                    sans.extend(ip.map(|ip| ip.to_string()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Some(PeerIdentity {
+        subject: cert.subject().to_string(),
+        common_name,
+        sans,
+    })
+}
+
+/// Load every certificate listed in the parameters.
+fn load_certificates(params: &TlsParams) -> Result<Certificates, String> {
+    let mut certs = Certificates::default();
+
+    if let Some(paths) = &params.default_cert {
+        certs.default = Some(Arc::new(load_certified_key(paths)?));
+    }
+
+    for (name, paths) in &params.sni_certs {
+        certs.by_name.insert(name.to_ascii_lowercase(), Arc::new(load_certified_key(paths)?));
+    }
+
+    Ok(certs)
+}
+
+/// Load the certificates of a PEM file.
+fn load_chain(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let chain = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read certificate {}: {}", path.display(), e))?;
+
+    if chain.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()));
+    }
+    Ok(chain)
+}
+
+/// Load a certificate chain and its private key from PEM files.
+fn load_certified_key(paths: &CertPaths) -> Result<CertifiedKey, String> {
+    let chain = load_chain(&paths.cert)?;
+
+    let key = PrivateKeyDer::from_pem_file(&paths.key)
+        .map_err(|e| format!("Cannot read private key {}: {}", paths.key.display(), e))?;
+
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|e| format!("Unsupported private key {}: {}", paths.key.display(), e))?;
+
+    Ok(CertifiedKey::new(chain, signing_key))
+}
+
+/// Start a thread reloading the certificates when their files change.
+///
+/// If the new files cannot be loaded, the previous certificates are kept.
+fn watch_certificates(params: TlsParams, resolver: Arc<CertResolver>) {
+    let paths = params
+        .default_cert
+        .iter()
+        .chain(params.sni_certs.iter().map(|(_, p)| p))
+        .flat_map(|p| [p.cert.clone(), p.key.clone()])
+        .collect::<Vec<PathBuf>>();
+
+    let modified = move || {
+        paths
+            .iter()
+            .map(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok())
+            .collect::<Vec<Option<SystemTime>>>()
+    };
+
+    thread::spawn(move || {
+        let mut last = modified();
+        loop {
+            thread::sleep(params.reload_interval);
+
+            let current = modified();
+            if current == last {
+                continue;
+            }
+            last = current;
+
+            match load_certificates(&params) {
+                Ok(certs) => {
+                    if let Ok(mut guard) = resolver.current.write() {
+                        *guard = certs;
+                        tracing::info!("TLS certificates reloaded");
+                    }
+                }
+                Err(e) => tracing::error!("Error reloading TLS certificates, keeping the previous ones: {}", e),
+            }
+        }
+    });
+}
+
+/// Redirect a plaintext request to the same resource over HTTPS.
+///
+/// The host is the one requested by the client, as forwarded by trusted proxies.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `https_port` - Port of the HTTPS listener.
+pub fn redirect(request: Request, https_port: u16) -> Response {
+    let host = request.host().map(|h| strip_port(&h).to_string());
+
+    let host = match host {
+        Some(h) if !h.is_empty() => h,
+        _ => return Response::new(request, None, 400, None),
+    };
+
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, request.target)
+    } else {
+        format!("https://{}:{}{}", host, https_port, request.target)
+    };
+
+    let mut response = Response::new(request, None, 301, None);
+    response.headers.push(("Location".to_string(), location));
+    response
+}
+
+/// Remove the port from a `Host` header value, keeping IPv6 brackets.
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    }
+}
@@ -0,0 +1,602 @@
+//! upstream.rs
+//!
+//! Reverse proxy routes, forwarding requests to backend services.
+//! A route mounts an upstream server under a path prefix (e.g. `/api/` to
+//! `127.0.0.1:9000`). Requests are sent to it over HTTP/1.1 without their
+//! hop-by-hop headers and with `X-Forwarded-*` headers describing the client,
+//! and the response is streamed back as it arrives.
+//!
+//! Connections to the upstreams are kept alive and reused by later requests.
+//! Upstreams that cannot be reached or answer invalid responses are reported
+//! with 502, and the ones that do not answer in time with 504.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::balancer::{self, Lease, UpstreamGroup};
+use crate::forwarded;
+use crate::request::Request;
+use crate::response::Response;
+use crate::utils::{HTTPMethod, ServerParams};
+
+/// Headers describing a single connection, never forwarded (RFC 9110, section 7.6.1).
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+/// Idle connections kept at most for each upstream.
+const MAX_IDLE_CONNECTIONS: usize = 32;
+
+/// Time after which an idle upstream connection is not reused, as the upstream may have closed it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest response head accepted from an upstream.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Reverse proxy parameters of the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UpstreamParams {
+    /// Routes forwarded to upstreams, the longest matching prefix wins
+    pub routes: Vec<ProxyRoute>,
+
+    /// Groups of servers that routes can forward to by name
+    pub groups: Vec<UpstreamGroup>,
+
+    /// Time given to connect to an upstream
+    pub connect_timeout: Duration,
+
+    /// Time given to an upstream to accept each write and send each read
+    pub timeout: Duration,
+}
+
+/// Path prefix forwarded to an upstream server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyRoute {
+    /// Prefix of the targets of the route (e.g. "/api/")
+    pub prefix: String,
+
+    /// Address of the upstream server, `HOST:PORT`, or name of an upstream group
+    pub upstream: String,
+
+    /// Path replacing the prefix in the forwarded targets, kept as is if `None`
+    pub path: Option<String>,
+}
+
+impl ProxyRoute {
+    /// Parse a `PREFIX=HOST:PORT[/PATH]` or `PREFIX=GROUP[/PATH]` route.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The route, e.g. "/api/=127.0.0.1:9000", "/api/=127.0.0.1:9000/v1/" or "/api/=backend".
+    pub fn from_str(entry: &str) -> Option<ProxyRoute> {
+        let (prefix, target) = entry.split_once('=')?;
+        if !prefix.starts_with('/') {
+            return None;
+        }
+
+        let (upstream, path) = match target.find('/') {
+            Some(i) => (&target[..i], Some(target[i..].to_string())),
+            None => (target, None),
+        };
+        let valid = match upstream.rsplit_once(':') {
+            Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+            None => !upstream.is_empty(),
+        };
+        if !valid {
+            return None;
+        }
+
+        Some(ProxyRoute {
+            prefix: prefix.to_string(),
+            upstream: upstream.to_string(),
+            path,
+        })
+    }
+
+    /// Get the target forwarded to the upstream for a request target.
+    fn upstream_target(&self, target: &str) -> String {
+        match &self.path {
+            Some(path) => format!("{}{}", path, &target[self.prefix.len()..]),
+            None => target.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ProxyRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}{}", self.prefix, self.upstream, self.path.as_deref().unwrap_or_default())
+    }
+}
+
+/// Connection to an upstream, buffered to read the response heads.
+type UpstreamConnection = BufReader<TcpStream>;
+
+/// Idle connections by upstream address, with the time they were released.
+type IdleConnections = HashMap<String, Vec<(UpstreamConnection, Instant)>>;
+
+static IDLE: Lazy<Mutex<IdleConnections>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Error forwarding a request, answered with 502 or 504.
+enum ProxyError {
+    /// The upstream could not be connected to, the request was not sent
+    Unreachable { reason: String, timed_out: bool },
+
+    /// The upstream could not be reached or answered an invalid response
+    BadGateway(String),
+
+    /// The upstream did not answer in time
+    Timeout(String),
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => ProxyError::Timeout(e.to_string()),
+            _ => ProxyError::BadGateway(e.to_string()),
+        }
+    }
+}
+
+/// Server that answered a request, the head of its response, the connection to read the body from and the lease of a group server.
+type Exchange = (String, ResponseHead, UpstreamConnection, Option<Lease>);
+
+/// Head of an upstream response.
+struct ResponseHead {
+    status: u16,
+    headers: Vec<(String, String)>,
+
+    /// Whether the connection can be reused once the body is read
+    keep_alive: bool,
+}
+
+/// Framing of an upstream response body.
+enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Get the proxy route of a request target, if one matches.
+///
+/// # Arguments
+///
+/// * `target` - Target of the request (e.g. "/api/users").
+pub fn route(target: &str) -> Option<ProxyRoute> {
+    ServerParams::current()
+        .upstream
+        .routes
+        .iter()
+        .filter(|route| target.starts_with(&route.prefix))
+        .max_by_key(|route| route.prefix.len())
+        .cloned()
+}
+
+/// Forward a request to the upstream of its route and stream back the response.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+/// * `route` - The proxy route matching the request target.
+pub fn forward(request: Request, route: &ProxyRoute) -> Response {
+    let head = match upstream_request(&request, route) {
+        Some(head) => head,
+        None => {
+            tracing::warn!("Refusing to forward a request with CR, LF or NUL in its fields to {}", route.upstream);
+            return Response::new(request, None, 400, None);
+        }
+    };
+    let params = ServerParams::current();
+
+    let result = match params.upstream.groups.iter().find(|group| group.name == route.upstream) {
+        Some(group) => forward_balanced(group, &request, &head),
+        None => exchange(&route.upstream, &head, &request.body, is_replayable(&request))
+            .map(|(head, connection)| (route.upstream.clone(), head, connection, None))
+            .map_err(|e| (route.upstream.clone(), e)),
+    };
+
+    match result {
+        Ok((server, head, connection, lease)) => respond(request, &server, head, connection, lease),
+        Err((server, ProxyError::Unreachable { reason, timed_out })) => {
+            tracing::warn!("Cannot connect to upstream {}: {}", server, reason);
+            Response::new(request, None, if timed_out { 504 } else { 502 }, None)
+        }
+        Err((server, ProxyError::BadGateway(e))) => {
+            tracing::warn!("Error forwarding to upstream {}: {}", server, e);
+            Response::new(request, None, 502, None)
+        }
+        Err((server, ProxyError::Timeout(e))) => {
+            tracing::warn!("Upstream {} timed out: {}", server, e);
+            Response::new(request, None, 504, None)
+        }
+    }
+}
+
+/// Forward a request to a server of a group, trying the next one while servers cannot be connected to.
+///
+/// Returns the server that answered, or the last one tried with its error.
+fn forward_balanced(group: &UpstreamGroup, request: &Request, head: &[u8]) -> Result<Exchange, (String, ProxyError)> {
+    let mut tried = Vec::new();
+    let mut last_error = None;
+
+    while let Some(lease) = balancer::pick(group, request, &tried) {
+        match exchange(&lease.server, head, &request.body, is_replayable(request)) {
+            Ok((response, connection)) => {
+                balancer::report(group, &lease.server, true);
+                return Ok((lease.server.clone(), response, connection, Some(lease)));
+            }
+            Err(e) => {
+                balancer::report(group, &lease.server, false);
+                let retry = matches!(e, ProxyError::Unreachable { .. });
+                tried.push(lease.server.clone());
+                if !retry {
+                    return Err((lease.server.clone(), e));
+                }
+                last_error = Some((lease.server.clone(), e));
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| (group.name.clone(), ProxyError::BadGateway("No healthy server in the group".to_string()))))
+}
+
+/// Build the request line and headers sent to the upstream.
+///
+/// Returns `None` if a field would break out of its line, e.g. to inject a header.
+fn upstream_request(request: &Request, route: &ProxyRoute) -> Option<Vec<u8>> {
+    if !request.has_valid_fields() {
+        return None;
+    }
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method.to_str(), route.upstream_target(&request.target));
+
+    // Headers named by Connection are hop-by-hop too
+    let connection_headers = request
+        .header_values("Connection")
+        .flat_map(|v| v.split(','))
+        .map(|h| h.trim().to_string())
+        .collect::<Vec<String>>();
+    let skipped = |name: &str| {
+        HOP_BY_HOP_HEADERS.iter().chain(["Content-Length", "X-Forwarded-For", "X-Forwarded-Proto", "X-Forwarded-Host"].iter()).any(|h| h.eq_ignore_ascii_case(name))
+            || connection_headers.iter().any(|h| h.eq_ignore_ascii_case(name))
+    };
+
+    for (name, value) in request.headers.iter().filter(|(name, _)| !skipped(name)) {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    // Extend the chain of trusted proxies with the peer, other clients cannot set it
+    let peer = request.peer_addr.map(|a| a.ip());
+    let mut chain = match peer {
+        Some(ip) if forwarded::is_trusted(&ip) => request.header_values("X-Forwarded-For").map(str::to_string).collect(),
+        _ => Vec::new(),
+    };
+    chain.extend(peer.map(|ip| ip.to_string()));
+    if !chain.is_empty() {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", chain.join(", ")));
+    }
+    head.push_str(&format!("X-Forwarded-Proto: {}\r\n", request.scheme()));
+    if let Some(host) = request.host() {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+    }
+
+    head.push_str(&format!("Content-Length: {}\r\n\r\n", request.body.len()));
+    Some(head.into_bytes())
+}
+
+/// Outcome of sending a request on a connection.
+enum Sent {
+    /// The head of the response
+    Answered(ResponseHead),
+
+    /// The connection was closed before any byte of the request went out
+    Unsent,
+
+    /// The connection was closed after the request went out, without a response
+    Unanswered,
+}
+
+/// Send a request to an upstream and read the head of its response.
+///
+/// A reused connection may have been closed by the upstream in the meantime,
+/// the request is then sent again on a new connection. Once the request went
+/// out, it is only sent again if it can be replayed without side effects.
+///
+/// # Arguments
+///
+/// * `upstream` - Address of the upstream server.
+/// * `head` - Request line and headers sent to the upstream.
+/// * `body` - Body of the request.
+/// * `replayable` - Whether the request may be sent twice, e.g. a GET.
+fn exchange(upstream: &str, head: &[u8], body: &[u8], replayable: bool) -> Result<(ResponseHead, UpstreamConnection), ProxyError> {
+    if let Some(mut connection) = checkout(upstream) {
+        match send(&mut connection, head, body)? {
+            Sent::Answered(response) => return Ok((response, connection)),
+            Sent::Unanswered if !replayable => {
+                return Err(ProxyError::BadGateway("Idle connection closed without a response".to_string()));
+            }
+            _ => tracing::debug!("Idle connection to upstream {} was closed, reconnecting", upstream),
+        }
+    }
+
+    let mut connection = connect(upstream)?;
+    match send(&mut connection, head, body)? {
+        Sent::Answered(response) => Ok((response, connection)),
+        _ => Err(ProxyError::BadGateway("Connection closed without a response".to_string())),
+    }
+}
+
+/// Send a request on a connection and read the head of its response.
+fn send(connection: &mut UpstreamConnection, head: &[u8], body: &[u8]) -> Result<Sent, ProxyError> {
+    let closed = |e: &io::Error| matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::ConnectionReset);
+    let stream = connection.get_mut();
+
+    // Nothing went out if the first write fails
+    let written = match stream.write(head) {
+        Ok(n) => n,
+        Err(e) if closed(&e) => return Ok(Sent::Unsent),
+        Err(e) => return Err(e.into()),
+    };
+    match stream.write_all(&head[written..]).and_then(|_| stream.write_all(body)) {
+        Ok(()) => {}
+        Err(e) if closed(&e) => return Ok(Sent::Unanswered),
+        Err(e) => return Err(e.into()),
+    }
+
+    // Interim responses (e.g. 100 Continue) are skipped, but 101 ends HTTP on the
+    // connection and cannot be relayed since Upgrade is not forwarded
+    loop {
+        match read_head(connection) {
+            Ok(None) => return Ok(Sent::Unanswered),
+            Ok(Some(head)) if head.status == 101 => return Err(ProxyError::BadGateway("Unexpected 101 Switching Protocols".to_string())),
+            Ok(Some(head)) if (100..200).contains(&head.status) => continue,
+            Ok(Some(head)) => return Ok(Sent::Answered(head)),
+            Err(e) if closed(&e) => return Ok(Sent::Unanswered),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Check if a request can be sent again to the upstream without side effects.
+fn is_replayable(request: &Request) -> bool {
+    request.method == HTTPMethod::GET
+}
+
+/// Read the status line and headers of a response.
+fn read_head(connection: &mut UpstreamConnection) -> io::Result<Option<ResponseHead>> {
+    let invalid = |reason: &str| io::Error::new(ErrorKind::InvalidData, format!("Invalid upstream response: {}", reason));
+
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let n = connection.read_line(&mut line)?;
+        if n == 0 {
+            return match lines.is_empty() {
+                true => Ok(None),
+                false => Err(invalid("incomplete head")),
+            };
+        }
+        size += n;
+        if size > MAX_HEAD_SIZE {
+            return Err(invalid("head too large"));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let status_line = lines.remove(0);
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts
+        .next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .filter(|s| (100..600).contains(s) && version.starts_with("HTTP/1."))
+        .ok_or_else(|| invalid(&status_line))?;
+
+    let headers = lines
+        .iter()
+        .map(|line| line.split_once(':').map(|(k, v)| (k.trim().to_string(), v.trim().to_string())))
+        .collect::<Option<Vec<(String, String)>>>()
+        .ok_or_else(|| invalid("malformed header"))?;
+
+    let connection_close = headers
+        .iter()
+        .any(|(k, v)| k.eq_ignore_ascii_case("Connection") && v.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")));
+
+    Ok(Some(ResponseHead {
+        status,
+        headers,
+        keep_alive: version == "HTTP/1.1" && !connection_close,
+    }))
+}
+
+/// Build the response to the client, its body streamed from the upstream.
+fn respond(request: Request, upstream: &str, head: ResponseHead, mut connection: UpstreamConnection, lease: Option<Lease>) -> Response {
+    let header = |name: &str| head.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str());
+
+    let length = if head.status == 204 || head.status == 304 {
+        BodyLength::Empty
+    } else if header("Transfer-Encoding").is_some_and(|te| te.to_ascii_lowercase().contains("chunked")) {
+        BodyLength::Chunked
+    } else {
+        match header("Content-Length").map(|l| l.parse::<u64>()) {
+            Some(Ok(0)) => BodyLength::Empty,
+            Some(Ok(len)) => BodyLength::Fixed(len),
+            Some(Err(_)) => {
+                tracing::warn!("Error forwarding to upstream {}: invalid Content-Length", upstream);
+                return Response::new(request, None, 502, None);
+            }
+            None => BodyLength::UntilClose,
+        }
+    };
+
+    let content_type = header("Content-Type").unwrap_or("application/octet-stream").to_string();
+    let keep_alive = head.keep_alive && !matches!(length, BodyLength::UntilClose);
+    let upstream = upstream.to_string();
+
+    let mut response = match length {
+        BodyLength::Empty => {
+            if keep_alive {
+                checkin(&upstream, connection);
+            }
+            Response::new(request, None, head.status, None)
+        }
+        // The server of a group stays busy until the body is sent
+        length => Response::streaming(request, &content_type, head.status, move |out| {
+            let _lease = lease;
+            copy_body(&mut connection, length, out)?;
+            if keep_alive {
+                checkin(&upstream, connection);
+            }
+            Ok(())
+        }),
+    };
+
+    let connection_headers = head
+        .headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, v)| v.split(',').map(|h| h.trim().to_string()))
+        .collect::<Vec<String>>();
+    for (name, value) in head.headers {
+        let skipped = HOP_BY_HOP_HEADERS.iter().chain(["Content-Length", "Content-Type"].iter()).any(|h| h.eq_ignore_ascii_case(&name))
+            || connection_headers.iter().any(|h| h.eq_ignore_ascii_case(&name));
+        if !skipped {
+            response.headers.push((name, value));
+        }
+    }
+    response
+}
+
+/// Copy a response body from the upstream to the client, flushing each piece as it arrives.
+fn copy_body(connection: &mut UpstreamConnection, length: BodyLength, out: &mut dyn Write) -> io::Result<()> {
+    match length {
+        BodyLength::Empty => Ok(()),
+        BodyLength::Fixed(len) => copy_exact(connection, len, out),
+        BodyLength::UntilClose => copy_exact(connection, u64::MAX, out),
+        BodyLength::Chunked => loop {
+            let mut line = String::new();
+            connection.read_line(&mut line)?;
+            let size = line.trim().split(';').next().and_then(|s| u64::from_str_radix(s.trim(), 16).ok());
+            let size = size.ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Invalid chunk size from upstream"))?;
+
+            if size == 0 {
+                // Trailers are dropped, up to the empty line ending the body
+                loop {
+                    line.clear();
+                    if connection.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                        return Ok(());
+                    }
+                }
+            }
+            copy_exact(connection, size, out)?;
+            line.clear();
+            connection.read_line(&mut line)?;
+        },
+    }
+}
+
+/// Copy `len` bytes, or up to the end of the stream if `len` is `u64::MAX`.
+fn copy_exact(connection: &mut UpstreamConnection, len: u64, out: &mut dyn Write) -> io::Result<()> {
+    let mut remaining = len;
+    let mut buf = [0; 16 * 1024];
+    while remaining > 0 {
+        let wanted = remaining.min(buf.len() as u64) as usize;
+        let n = connection.read(&mut buf[..wanted])?;
+        if n == 0 {
+            return match len {
+                u64::MAX => Ok(()),
+                _ => Err(ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        out.write_all(&buf[..n])?;
+        out.flush()?;
+        if len != u64::MAX {
+            remaining -= n as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Open a connection to an upstream, trying each of its addresses.
+fn connect(upstream: &str) -> Result<UpstreamConnection, ProxyError> {
+    let params = ServerParams::current();
+    let unreachable = |reason: String, timed_out: bool| ProxyError::Unreachable { reason, timed_out };
+    let addresses = upstream
+        .to_socket_addrs()
+        .map_err(|e| unreachable(format!("Cannot resolve {}: {}", upstream, e), false))?
+        .collect::<Vec<SocketAddr>>();
+
+    let mut last_error = unreachable(format!("No address for {}", upstream), false);
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, params.upstream.connect_timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(params.upstream.timeout))?;
+                stream.set_write_timeout(Some(params.upstream.timeout))?;
+                stream.set_nodelay(true)?;
+                return Ok(BufReader::new(stream));
+            }
+            Err(e) => last_error = unreachable(e.to_string(), e.kind() == ErrorKind::TimedOut),
+        }
+    }
+    Err(last_error)
+}
+
+/// Take an idle connection to an upstream, if one was released recently enough.
+fn checkout(upstream: &str) -> Option<UpstreamConnection> {
+    let mut idle = IDLE.lock().unwrap_or_else(|e| e.into_inner());
+    let connections = idle.get_mut(upstream)?;
+    while let Some((connection, released)) = connections.pop() {
+        if released.elapsed() < IDLE_TIMEOUT && is_open(&connection) {
+            return Some(connection);
+        }
+    }
+    None
+}
+
+/// Check if an idle connection is still open and has nothing to read.
+///
+/// Requests that cannot be replayed are then less likely to be sent on a
+/// connection the upstream already closed.
+fn is_open(connection: &UpstreamConnection) -> bool {
+    let stream = connection.get_ref();
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0; 1];
+    let open = matches!(stream.peek(&mut byte), Err(e) if e.kind() == ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+/// Release a connection whose response was fully read, for a later request.
+fn checkin(upstream: &str, connection: UpstreamConnection) {
+    // Bytes past the response mean the connection is out of sync
+    if !connection.buffer().is_empty() {
+        return;
+    }
+
+    let mut idle = IDLE.lock().unwrap_or_else(|e| e.into_inner());
+    let connections = idle.entry(upstream.to_string()).or_default();
+    connections.retain(|(_, released)| released.elapsed() < IDLE_TIMEOUT);
+    if connections.len() < MAX_IDLE_CONNECTIONS {
+        connections.push((connection, Instant::now()));
+    }
+}
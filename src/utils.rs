@@ -2,20 +2,49 @@
 //!
 //! Contains shared constants, helpers, and command line argument parsing
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::access_log::AccessLogParams;
+use crate::auth::{AuthParams, AuthRule};
+use crate::balancer::UpstreamGroup;
+use crate::cgi::{CgiHandler, CgiParams, CgiRoute};
+use crate::config;
+use crate::jwt::JwtParams;
+use crate::listener::ListenAddress;
+use crate::metrics::MetricsParams;
+use crate::pool::{PoolParams, MAX_WORKERS};
+use crate::tls::{CertPaths, TlsParams};
+use crate::upstream::{ProxyRoute, UpstreamParams};
+use crate::vhost::VirtualHost;
 
 /// Stores the HTTP response codes and their meanings.
 /// 
 /// Static variable that is initialized once and can be used throughout the program.
 pub static HTTP_RESPONSE_CODES : Lazy<HashMap<u16, String>> = Lazy::new(|| {
     let mut m = HashMap::new();
+    m.insert(101, "Switching Protocols".to_string());
     m.insert(200, "OK".to_string());
     m.insert(201, "Created".to_string());
+    m.insert(301, "Moved Permanently".to_string());
+    m.insert(302, "Found".to_string());
     m.insert(400, "Bad request".to_string());
+    m.insert(401, "Unauthorized".to_string());
+    m.insert(403, "Forbidden".to_string());
     m.insert(404, "Not Found".to_string());
+    m.insert(426, "Upgrade Required".to_string());
     m.insert(500, "Internal Server Error".to_string());
+    m.insert(501, "Not Implemented".to_string());
+    m.insert(502, "Bad Gateway".to_string());
+    m.insert(503, "Service Unavailable".to_string());
+    m.insert(504, "Gateway Timeout".to_string());
     m
 });
 
@@ -24,17 +53,37 @@ pub static COMPRESSION_ALGORITHMS : Lazy<Vec<&str>> = Lazy::new(|| {
     vec!["gzip", "deflate", "br"]
 });
 
+/// Static table of host names checked before the system resolver.
+const HOSTS_FILE: &str = "/etc/hosts";
+
+/// Maximum size of a single request (request line, headers and body).
+pub const MAX_REQUEST_SIZE: usize = 8 * 1024 * 1024;
+
 /// Error types for parsing HTTP requests.
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum RequestParseError {
     InvalidRequestLine,
     InvalidMethod,
     InvalidVersion,
     InvalidHeader,
+    InvalidContentLength,
+    UnsupportedTransferEncoding,
+}
+
+impl RequestParseError {
+    /// Get the status code answered to a request that failed to parse.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            RequestParseError::UnsupportedTransferEncoding => 501,
+            _ => 400,
+        }
+    }
 }
 
 /// Most common HTTP methods.
 #[derive(Debug, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum HTTPMethod {
     GET,
     POST,
@@ -57,6 +106,16 @@ impl HTTPMethod {
             _ => None,
         }
     }
+
+    /// Transforms an `HTTPMethod` into a String.
+    pub fn to_str(&self) -> &'static str {
+        match self {
+            HTTPMethod::GET => "GET",
+            HTTPMethod::POST => "POST",
+            HTTPMethod::PUT => "PUT",
+            HTTPMethod::DELETE => "DELETE",
+        }
+    }
 }
 
 /// Existing HTTP versions.
@@ -64,13 +123,15 @@ impl HTTPMethod {
 pub enum HTTPVersion{
     Http1_0,
     Http1_1,
+    /// Only used for requests received over binary HTTP/2 framing
     Http2_0,
-    Http3_0,
 }
 
 impl HTTPVersion {
     /// Transforms a string into an `HTTPVersion`.
     ///
+    /// Only HTTP/1.x request lines are text, HTTP/2 requests come from `http2`.
+    ///
     /// # Arguments
     ///
     /// * `version` - HTTP version of the request (e.g., "HTTP/1.0", "HTTP/1.1").
@@ -78,8 +139,6 @@ impl HTTPVersion {
         match version {
             "HTTP/1.0" => Some(HTTPVersion::Http1_0),
             "HTTP/1.1" => Some(HTTPVersion::Http1_1),
-            "HTTP/2.0" => Some(HTTPVersion::Http2_0),
-            "HTTP/3.0" => Some(HTTPVersion::Http3_0),
             _ => None,
         }
     }
@@ -89,87 +148,976 @@ impl HTTPVersion {
         match self {
             HTTPVersion::Http1_0 => "HTTP/1.0",
             HTTPVersion::Http1_1 => "HTTP/1.1",
-            HTTPVersion::Http2_0 => "HTTP/2.0",
-            HTTPVersion::Http3_0 => "HTTP/3.0",
+            HTTPVersion::Http2_0 => "HTTP/2",
+        }
+    }
+}
+
+/// Runtimes available to serve client connections.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    /// Blocking sockets, one connection per worker thread of the pool.
+    Threaded,
+    /// Non-blocking sockets multiplexed by a single epoll event loop.
+    Event,
+}
+
+/// Client certificate requirements of the HTTPS listener.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Client certificates are not requested.
+    None,
+    /// Client certificates are requested and verified when presented.
+    Optional,
+    /// Handshakes without a valid client certificate are rejected.
+    Required,
+}
+
+/// Compression of the response bodies.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionPolicy {
+    /// Bodies are compressed with gzip for clients accepting it.
+    Gzip,
+    /// Bodies are never compressed.
+    Off,
+}
+
+/// Formats of the access log lines.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Common Log Format.
+    Common,
+    /// Common Log Format followed by the referer and user-agent.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Levels of the diagnostic messages, from the most to the least severe.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Block of IP addresses, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Parse a block in CIDR notation, a single address being a block of its own.
+    ///
+    /// # Arguments
+    ///
+    /// * `block` - The block to parse (e.g. "192.168.0.0/16", "::1").
+    pub fn from_str(block: &str) -> Option<Cidr> {
+        let (ip, prefix_len) = match block.split_once('/') {
+            Some((ip, len)) => (ip.parse::<IpAddr>().ok()?, Some(len.parse::<u8>().ok()?)),
+            None => (block.parse::<IpAddr>().ok()?, None),
+        };
+
+        let max_len = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return None;
+        }
+        Some(Cidr { network: ip, prefix_len })
+    }
+
+    /// Check if an address belongs to the block.
+    ///
+    /// IPv4 addresses mapped to IPv6 match IPv4 blocks.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
         }
     }
 }
 
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
 /// Command line arguments parser.
-#[derive(Parser, Debug)]
+///
+/// Options missing from the command line are read from the environment, then
+/// from the configuration file, whose keys are the long names of the options.
+#[derive(Parser, Debug, Default, Serialize, Deserialize)]
 #[command(author = "Mathieu Emery", version, about="A Very simple http server")]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
 pub struct Args {
+    // Configuration file
+    #[arg(short, long, env = "HTTP_SERVER_CONFIG", help = "TOML configuration file, overridden by the environment and the arguments")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    // Print the effective configuration and exit
+    #[arg(long, help = "Print the effective configuration as TOML and exit")]
+    #[serde(skip)]
+    print_config: bool,
+
     // Address of the server
-    #[arg(short, long, help = "Address of the server (default: 127.0.0.1)")]
+    #[arg(short, long, env = "HTTP_SERVER_ADDRESS", help = "IP address or hostname of the server, bound on every address it resolves to (default: 127.0.0.1)")]
     address: Option<String>,
     
     // Port to run the server on
-    #[arg(short, long, help = "Port to run the server on (default: 4221)")]
+    #[arg(short, long, env = "HTTP_SERVER_PORT", help = "Port to run the server on (default: 4221)")]
     port: Option<u32>,
 
+    // Addresses to listen on, instead of the address and port
+    #[arg(short, long, value_name = "ADDRESS", value_delimiter = ';', env = "HTTP_SERVER_LISTEN", help = "Listen on IP:PORT, [IPv6]:PORT, HOSTNAME:PORT or unix:PATH[,mode=OCTAL] instead of --address and --port, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    listen: Vec<String>,
+
     // Max amount of threads
-    #[arg(short, long, help = "Maximum amount of threads (default: 10)")]
+    #[arg(short, long, env = "HTTP_SERVER_MAX_THREAD_NUM", help = "Maximum amount of worker threads, or of connections on their own thread with --runtime event, up to 4096 (default: 10)")]
     max_thread_num: Option<usize>,
+
+    // Workers kept when idle, the pool grows on demand above it
+    #[arg(long, env = "HTTP_SERVER_MIN_THREADS", help = "Worker threads kept when idle, the pool grows up to --max-thread-num on demand (default: same as --max-thread-num)")]
+    min_threads: Option<usize>,
+
+    // Connections waiting for a worker
+    #[arg(long, env = "HTTP_SERVER_ACCEPT_QUEUE", help = "Connections waiting for a free worker before new ones are answered with 503 (default: 128)")]
+    accept_queue: Option<usize>,
+
+    // Time before idle workers above the minimum stop
+    #[arg(long, env = "HTTP_SERVER_THREAD_IDLE_TIMEOUT", help = "Seconds after which idle worker threads above --min-threads stop (default: 60)")]
+    thread_idle_timeout: Option<u64>,
+
+    // Runtime used to serve connections
+    #[arg(short, long, value_enum, env = "HTTP_SERVER_RUNTIME", help = "Connection runtime: threaded or event (default: threaded)")]
+    runtime: Option<Runtime>,
+
+    // Time given to active connections to finish on shutdown
+    #[arg(short, long, env = "HTTP_SERVER_SHUTDOWN_TIMEOUT", help = "Seconds given to active connections to finish on shutdown (default: 30)")]
+    shutdown_timeout: Option<u64>,
+
+    // Time a connection may stay silent between requests
+    #[arg(long, env = "HTTP_SERVER_KEEP_ALIVE_TIMEOUT", help = "Seconds after which connections waiting for a request without receiving anything are closed (default: 60)")]
+    keep_alive_timeout: Option<u64>,
+
+    // Certificate chain served over HTTPS
+    #[arg(long, env = "HTTP_SERVER_TLS_CERT", help = "PEM certificate chain, enables HTTPS")]
+    tls_cert: Option<PathBuf>,
+
+    // Private key of the certificate
+    #[arg(long, env = "HTTP_SERVER_TLS_KEY", help = "PEM private key of the certificate")]
+    tls_key: Option<PathBuf>,
+
+    // Certificates selected by server name
+    #[arg(long, value_name = "HOST=CERT,KEY", value_delimiter = ';', env = "HTTP_SERVER_TLS_SNI", help = "Certificate for a server name (SNI), can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tls_sni: Vec<String>,
+
+    // Interval at which the certificate files are checked for changes
+    #[arg(long, env = "HTTP_SERVER_TLS_RELOAD_INTERVAL", help = "Seconds between checks for certificate changes (default: 10)")]
+    tls_reload_interval: Option<u64>,
+
+    // Plaintext port redirecting to HTTPS
+    #[arg(long, env = "HTTP_SERVER_HTTPS_REDIRECT_PORT", help = "Port of a plaintext listener redirecting to HTTPS")]
+    https_redirect_port: Option<u16>,
+
+    // Authorities signing client certificates
+    #[arg(long, env = "HTTP_SERVER_TLS_CLIENT_CA", help = "PEM bundle of the CAs signing client certificates")]
+    tls_client_ca: Option<PathBuf>,
+
+    // Client certificate requirements
+    #[arg(long, value_enum, env = "HTTP_SERVER_TLS_CLIENT_AUTH", help = "Client certificate requirements: none, optional or required (default: optional with --tls-client-ca)")]
+    tls_client_auth: Option<ClientAuth>,
+
+    // Compression of the response bodies
+    #[arg(long, value_enum, env = "HTTP_SERVER_COMPRESSION", help = "Compression of response bodies accepting it: gzip or off (default: gzip)")]
+    compression: Option<CompressionPolicy>,
+
+    // Directory served by the /files/ endpoint
+    #[arg(short, long, env = "HTTP_SERVER_DIRECTORY", help = "Directory served by the /files/ endpoint (default: current directory)")]
+    directory: Option<PathBuf>,
+
+    // Sites served by host name
+    #[arg(long, value_name = "HOST[,KEY=VALUE...]", value_delimiter = ';', env = "HTTP_SERVER_VHOST", help = "Virtual host with its root, routes, compression, tls-cert and tls-key settings, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    vhost: Vec<String>,
+
+    // Path prefixes forwarded to backend services
+    #[arg(long, value_name = "PREFIX=HOST:PORT[/PATH]", value_delimiter = ';', env = "HTTP_SERVER_PROXY_ROUTE", help = "Forward the requests whose target starts with PREFIX to an upstream server, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    proxy_route: Vec<String>,
+
+    // Groups of servers sharing the requests of proxy routes
+    #[arg(long, value_name = "NAME,servers=HOST:PORT|...", value_delimiter = ';', env = "HTTP_SERVER_UPSTREAM_GROUP", help = "Group of upstream servers, with its strategy, max-fails, fail-timeout, health-path and health-interval settings, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    upstream_group: Vec<String>,
+
+    // Time given to connect to an upstream
+    #[arg(long, env = "HTTP_SERVER_PROXY_CONNECT_TIMEOUT", help = "Seconds given to connect to an upstream server (default: 5)")]
+    proxy_connect_timeout: Option<u64>,
+
+    // Time given to an upstream to answer
+    #[arg(long, env = "HTTP_SERVER_PROXY_TIMEOUT", help = "Seconds an upstream server may stay silent while answering (default: 60)")]
+    proxy_timeout: Option<u64>,
+
+    // Directories of programs answering requests
+    #[arg(long, value_name = "PREFIX=DIR", value_delimiter = ';', env = "HTTP_SERVER_CGI", help = "Run the CGI programs of DIR for the requests whose target starts with PREFIX, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    cgi: Vec<String>,
+
+    // FastCGI applications answering requests
+    #[arg(long, value_name = "PREFIX=ADDRESS[,root=DIR]", value_delimiter = ';', env = "HTTP_SERVER_FASTCGI", help = "Send the requests whose target starts with PREFIX to a FastCGI application at HOST:PORT or unix:PATH, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fastcgi: Vec<String>,
+
+    // Time given to CGI programs to answer
+    #[arg(long, env = "HTTP_SERVER_CGI_TIMEOUT", help = "Seconds given to a CGI program to answer, or a FastCGI application to send each read (default: 30)")]
+    cgi_timeout: Option<u64>,
+
+    // Site serving the requests for other hosts
+    #[arg(long, value_name = "HOST", env = "HTTP_SERVER_DEFAULT_VHOST", help = "Virtual host answering requests for unknown hosts (default: the server-wide options)")]
+    default_vhost: Option<String>,
+
+    // Restrict file creation to authenticated clients
+    #[arg(long, env = "HTTP_SERVER_FILES_WRITE_CLIENT_CERT", help = "Only accept POST /files/ from clients with a verified certificate")]
+    #[serde(skip_serializing_if = "is_false")]
+    files_write_client_cert: bool,
+
+    // Identities allowed to create files
+    #[arg(long, value_name = "NAME", value_delimiter = ';', env = "HTTP_SERVER_FILES_WRITE_ALLOWED", help = "Client certificate name (CN or SAN) allowed to POST /files/, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    files_write_allowed: Vec<String>,
+
+    // Users allowed to authenticate with HTTP Basic credentials
+    #[arg(long, value_name = "FILE", env = "HTTP_SERVER_HTPASSWD", help = "File of USER:HASH lines with bcrypt or argon2 password hashes, for Basic authentication")]
+    htpasswd: Option<PathBuf>,
+
+    // Tokens allowed to authenticate as Bearer tokens
+    #[arg(long, value_name = "FILE", env = "HTTP_SERVER_AUTH_TOKENS", help = "File of NAME:TOKEN lines, for Bearer authentication")]
+    auth_tokens: Option<PathBuf>,
+
+    // Routes protected by authentication
+    #[arg(long, value_name = "PREFIX[,KEY=VALUE...]", value_delimiter = ';', env = "HTTP_SERVER_AUTH_RULE", help = "Require authentication for the targets starting with PREFIX, with its methods, access and users settings, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    auth_rule: Vec<String>,
+
+    // Realm of the authentication challenges
+    #[arg(long, value_name = "REALM", env = "HTTP_SERVER_AUTH_REALM", help = "Realm announced in the WWW-Authenticate challenges (default: http-server)")]
+    auth_realm: Option<String>,
+
+    // Secret signing the HS256 JWTs
+    #[arg(long, value_name = "FILE", env = "HTTP_SERVER_JWT_SECRET", help = "File of the secret of HS256 JWTs sent as Bearer tokens")]
+    jwt_secret: Option<PathBuf>,
+
+    // Public keys of the RS256 and ES256 JWTs
+    #[arg(long, value_name = "FILE", env = "HTTP_SERVER_JWT_KEY", help = "PEM public key or JWKS file of RS256 and ES256 JWTs sent as Bearer tokens")]
+    jwt_key: Option<PathBuf>,
+
+    // Issuer of the JWTs
+    #[arg(long, value_name = "ISSUER", env = "HTTP_SERVER_JWT_ISSUER", help = "Required iss claim of the JWTs")]
+    jwt_issuer: Option<String>,
+
+    // Audiences of the JWTs
+    #[arg(long, value_name = "AUDIENCE", value_delimiter = ';', env = "HTTP_SERVER_JWT_AUDIENCE", help = "Accepted aud claim of the JWTs, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    jwt_audience: Vec<String>,
+
+    // Clock skew tolerated on the JWT times
+    #[arg(long, env = "HTTP_SERVER_JWT_LEEWAY", help = "Seconds of clock skew tolerated on the exp and nbf claims of the JWTs (default: 60)")]
+    jwt_leeway: Option<u64>,
+
+    // Claim naming the user of a JWT
+    #[arg(long, value_name = "CLAIM", env = "HTTP_SERVER_JWT_USER_CLAIM", help = "Claim of the JWTs naming their user (default: sub)")]
+    jwt_user_claim: Option<String>,
+
+    // Directories of the files reserved to each user
+    #[arg(long, value_name = "CLAIM", env = "HTTP_SERVER_FILES_PREFIX_CLAIM", help = "Restrict the files routes to JWT users, each to the directory named by a claim of its token")]
+    files_prefix_claim: Option<String>,
+
+    // Path of the metrics endpoint
+    #[arg(long, value_name = "PATH", env = "HTTP_SERVER_METRICS_PATH", help = "Path of the Prometheus metrics endpoint (default: /metrics)")]
+    metrics_path: Option<String>,
+
+    // Proxies sending the PROXY protocol header
+    #[arg(long, value_name = "CIDR", value_delimiter = ';', env = "HTTP_SERVER_PROXY_PROTOCOL", help = "Read a PROXY protocol header (v1 or v2) from connections of peers in CIDR, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    proxy_protocol: Vec<String>,
+
+    // Proxies whose forwarding headers are trusted
+    #[arg(long, value_name = "CIDR", value_delimiter = ';', env = "HTTP_SERVER_TRUSTED_PROXY", help = "Read the client address, scheme and host from the Forwarded and X-Forwarded-* headers of peers in CIDR, can be repeated")]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    trusted_proxy: Vec<String>,
+
+    // Port of the admin listener serving the metrics
+    #[arg(long, env = "HTTP_SERVER_METRICS_PORT", help = "Serve the metrics endpoint on this port only, instead of the server port")]
+    metrics_port: Option<u16>,
+
+    // Most verbose level of the diagnostic messages
+    #[arg(long, value_enum, env = "HTTP_SERVER_LOG_LEVEL", help = "Diagnostic log level: error, warn, info, debug or trace (default: info)")]
+    log_level: Option<LogLevel>,
+
+    // Destination of the access log
+    #[arg(long, value_name = "PATH", env = "HTTP_SERVER_ACCESS_LOG", help = "Write an access log line per request to PATH, or to the standard output with -")]
+    access_log: Option<String>,
+
+    // Format of the access log
+    #[arg(long, value_enum, env = "HTTP_SERVER_ACCESS_LOG_FORMAT", help = "Access log format: common, combined or json (default: combined)")]
+    access_log_format: Option<LogFormat>,
+
+    // Size above which the access log file is rotated
+    #[arg(long, value_name = "MB", env = "HTTP_SERVER_ACCESS_LOG_MAX_SIZE", help = "Size in megabytes above which the access log file is rotated (default: 100)")]
+    access_log_max_size: Option<u64>,
+
+    // Number of rotated access log files kept
+    #[arg(long, env = "HTTP_SERVER_ACCESS_LOG_MAX_FILES", help = "Number of rotated access log files kept (default: 5)")]
+    access_log_max_files: Option<usize>,
 }
 
 impl Args{
     /// Parses the arguments into a `ServerParams` struct.
+    ///
+    /// The arguments are layered over the configuration file, if one is given.
+    /// With `--print-config`, the effective configuration is printed and the
+    /// process exits.
     pub fn parse_params() -> Result<ServerParams, String> {
-        let args = Args::parse();
+        let cli = Args::parse();
+        let params = Self::layered_params(&cli)?;
 
-        // Get the maximum number of threads available on the system
-        let max_threads = num_cpus::get();
-        if max_threads == 0 {
-            return Err("No CPU detected".to_string());
+        if cli.print_config {
+            print!("{}", config::render(&Args::effective(&params))?);
+            std::process::exit(0);
         }
+        Ok(params)
+    }
+
+    /// Parses the arguments, environment and configuration file again.
+    ///
+    /// Unlike `parse_params`, errors are returned instead of exiting.
+    pub fn reload_params() -> Result<ServerParams, String> {
+        let cli = Args::try_parse().map_err(|e| e.to_string())?;
+        Self::layered_params(&cli)
+    }
+
+    /// Layers the arguments over the configuration file and validates them.
+    fn layered_params(cli: &Args) -> Result<ServerParams, String> {
+        let args: Args = config::layered(cli, cli.config.as_deref())?;
+        let mut params = Self::into_params(args)?;
+        params.config_file = cli.config.clone();
+        Ok(params)
+    }
 
-        let mut num_threads = args.max_thread_num.unwrap_or(10);
+    /// Validates the options and applies the defaults of the missing ones.
+    fn into_params(args: Args) -> Result<ServerParams, String> {
+        // Workers mostly wait on the network, so their number is not tied to the CPUs
+        let max_threads = args.max_thread_num.unwrap_or(10);
+        if max_threads == 0 || max_threads > MAX_WORKERS {
+            return Err(format!("The maximum number of threads must be between 1 and {}", MAX_WORKERS));
+        }
 
-        // Set the number of threads to the minimum of the user-specified value and the maximum available
-        if num_threads > max_threads{
-            eprintln!("Warning: Maximum number of threads is set to the maximum available: {}", max_threads);
-            num_threads = max_threads;
+        let min_threads = args.min_threads.unwrap_or(max_threads);
+        if min_threads > max_threads {
+            return Err("--min-threads cannot exceed --max-thread-num".to_string());
         }
 
-        let address = args.address.unwrap_or("127.0.0.1".to_string());
+        let pool = PoolParams {
+            min_threads,
+            max_threads,
+            accept_queue: args.accept_queue.unwrap_or(128),
+            idle_timeout: Duration::from_secs(args.thread_idle_timeout.unwrap_or(60).max(1)),
+        };
+
+        let listen = if args.listen.is_empty() {
+            let address = args.address.unwrap_or("127.0.0.1".to_string());
+            let port = args.port.unwrap_or(4221);
+
+            // Validate the port number
+            if port > 65535 {
+                return Err("Port number must be between 0 and 65535".to_string());
+            }
 
-        // Validate the address
-        if !Self::check_address(&address) {
-            return Err("Invalid address".to_string());
+            // Validate the address, listening on every address of a hostname
+            Self::check_address(&address)?
+                .into_iter()
+                .map(|ip| ListenAddress::Tcp(SocketAddr::new(ip, port as u16)))
+                .collect()
+        } else {
+            if args.address.is_some() || args.port.is_some() {
+                return Err("--listen cannot be combined with --address or --port".to_string());
+            }
+
+            let mut listen = Vec::new();
+            for entry in &args.listen {
+                match ListenAddress::parse(entry) {
+                    Ok(address) => listen.push(address),
+                    // Hostnames are resolved like --address
+                    Err(e) => {
+                        let (host, port) = entry
+                            .rsplit_once(':')
+                            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+                            .filter(|(host, _)| !host.is_empty() && !host.starts_with('[') && !host.contains(':'))
+                            .ok_or(e)?;
+                        listen.extend(Self::check_address(host)?.into_iter().map(|ip| ListenAddress::Tcp(SocketAddr::new(ip, port))));
+                    }
+                }
+            }
+            listen
+        };
+        let tcp_ports = listen.iter().filter_map(ListenAddress::port).collect::<Vec<u16>>();
+
+        let runtime = args.runtime.unwrap_or(Runtime::Threaded);
+
+        // Validate the TLS parameters
+        let default_cert = match (args.tls_cert, args.tls_key) {
+            (Some(cert), Some(key)) => Some(CertPaths { cert, key }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+        };
+
+        let mut sni_certs = Vec::new();
+        for entry in &args.tls_sni {
+            let (host, paths) = entry
+                .split_once('=')
+                .and_then(|(host, paths)| Some((host.to_string(), CertPaths::from_str(paths)?)))
+                .ok_or_else(|| format!("Invalid --tls-sni entry (expected HOST=CERT,KEY): {}", entry))?;
+            sni_certs.push((host, paths));
         }
 
-        let port = args.port.unwrap_or(4221);
+        // Virtual hosts inherit the server-wide settings they do not set
+        let files_root = args.directory.unwrap_or(PathBuf::from("./"));
+        let compression = args.compression.unwrap_or(CompressionPolicy::Gzip);
+        let mut vhosts: Vec<VirtualHost> = Vec::new();
+        for entry in &args.vhost {
+            let vhost = VirtualHost::parse(entry, &files_root, compression)?;
+            if vhosts.iter().any(|v| v.name == vhost.name) {
+                return Err(format!("Duplicate --vhost entry for {}", vhost.name));
+            }
+            if let Some(cert) = &vhost.cert {
+                sni_certs.push((vhost.name.clone(), cert.clone()));
+            }
+            vhosts.push(vhost);
+        }
+
+        let default_vhost = args.default_vhost.map(|name| name.to_ascii_lowercase());
+        if default_vhost.as_ref().is_some_and(|name| !vhosts.iter().any(|v| &v.name == name)) {
+            return Err("--default-vhost must name one of the --vhost entries".to_string());
+        }
 
-        // Validate the port number
-        if port > 65535 {
-            return Err("Port number must be between 0 and 65535".to_string());
+        let mut proxy_routes = Vec::new();
+        for entry in &args.proxy_route {
+            proxy_routes.push(ProxyRoute::from_str(entry).ok_or_else(|| format!("Invalid --proxy-route entry (expected /PREFIX=HOST:PORT[/PATH]): {}", entry))?);
+        }
+        let mut upstream_groups: Vec<UpstreamGroup> = Vec::new();
+        for entry in &args.upstream_group {
+            let group = UpstreamGroup::parse(entry)?;
+            if upstream_groups.iter().any(|g| g.name == group.name) {
+                return Err(format!("Duplicate --upstream-group entry for {}", group.name));
+            }
+            upstream_groups.push(group);
+        }
+        // Routes name a group when their upstream has no port
+        if let Some(route) = proxy_routes.iter().find(|r| !r.upstream.contains(':') && !upstream_groups.iter().any(|g| g.name == r.upstream)) {
+            return Err(format!("Unknown upstream group {} in --proxy-route {}", route.upstream, route));
+        }
+
+        let upstream = UpstreamParams {
+            routes: proxy_routes,
+            groups: upstream_groups,
+            connect_timeout: Duration::from_secs(args.proxy_connect_timeout.unwrap_or(5).max(1)),
+            timeout: Duration::from_secs(args.proxy_timeout.unwrap_or(60).max(1)),
+        };
+
+        let mut cgi_routes: Vec<CgiRoute> = Vec::new();
+        let programs = args.cgi.iter().map(|entry| CgiRoute::programs(entry).ok_or_else(|| format!("Invalid --cgi entry (expected /PREFIX=DIR): {}", entry)));
+        let applications = args.fastcgi.iter().map(|entry| {
+            CgiRoute::fastcgi(entry).ok_or_else(|| format!("Invalid --fastcgi entry (expected /PREFIX=HOST:PORT or /PREFIX=unix:PATH, with an optional ,root=DIR): {}", entry))
+        });
+        for route in programs.chain(applications) {
+            let route = route?;
+            if cgi_routes.iter().any(|r| r.prefix == route.prefix) {
+                return Err(format!("Duplicate CGI route for {}", route.prefix));
+            }
+            cgi_routes.push(route);
+        }
+        let cgi = CgiParams {
+            routes: cgi_routes,
+            timeout: Duration::from_secs(args.cgi_timeout.unwrap_or(30).max(1)),
+        };
+
+        let client_auth = match (&args.tls_client_ca, args.tls_client_auth) {
+            (_, Some(ClientAuth::None)) | (None, None) => ClientAuth::None,
+            (Some(_), Some(auth)) => auth,
+            (Some(_), None) => ClientAuth::Optional,
+            (None, Some(_)) => return Err("--tls-client-auth requires --tls-client-ca".to_string()),
+        };
+
+        let tls = if default_cert.is_some() || !sni_certs.is_empty() {
+            if runtime != Runtime::Threaded {
+                return Err("HTTPS is only supported by the threaded runtime".to_string());
+            }
+            if args.https_redirect_port.is_some() && tcp_ports.is_empty() {
+                return Err("--https-redirect-port requires a TCP listen address".to_string());
+            }
+
+            Some(TlsParams {
+                default_cert,
+                sni_certs,
+                reload_interval: Duration::from_secs(args.tls_reload_interval.unwrap_or(10).max(1)),
+                redirect_port: args.https_redirect_port,
+                client_ca: args.tls_client_ca,
+                client_auth,
+            })
+        } else {
+            if args.https_redirect_port.is_some() || args.tls_client_ca.is_some() {
+                return Err("--https-redirect-port and --tls-client-ca require HTTPS to be enabled".to_string());
+            }
+            None
+        };
+
+        // Writes restricted by certificate need clients to be able to present one
+        let files_write_client_cert = args.files_write_client_cert || !args.files_write_allowed.is_empty();
+        if files_write_client_cert && client_auth == ClientAuth::None {
+            return Err("Restricting POST /files/ to client certificates requires --tls-client-ca".to_string());
+        }
+
+        let mut auth_rules = Vec::new();
+        for entry in &args.auth_rule {
+            auth_rules.push(AuthRule::parse(entry)?);
+        }
+
+        // Without a JWT, every request to the files routes would be forbidden
+        if args.files_prefix_claim.is_some() {
+            for route in ["/files/", "/ws/tail/", "/sse/tail/"] {
+                if !auth_rules.iter().any(|rule| route.starts_with(&rule.prefix) && rule.methods.is_empty() && !rule.public) {
+                    return Err(format!("--files-prefix-claim requires an --auth-rule covering every method of {}", route));
+                }
+            }
+        }
+        let jwt = match (args.jwt_secret, args.jwt_key) {
+            (None, None) => {
+                if args.jwt_issuer.is_some() || !args.jwt_audience.is_empty() || args.jwt_leeway.is_some() || args.jwt_user_claim.is_some() || args.files_prefix_claim.is_some() {
+                    return Err("The JWT options require --jwt-secret or --jwt-key".to_string());
+                }
+                None
+            }
+            (secret, key) => Some(JwtParams::load(
+                secret,
+                key,
+                args.jwt_issuer,
+                args.jwt_audience,
+                args.jwt_leeway.unwrap_or(60),
+                args.jwt_user_claim.unwrap_or("sub".to_string()),
+            )?),
+        };
+        let auth = AuthParams::load(args.auth_realm.unwrap_or("http-server".to_string()), args.htpasswd, args.auth_tokens, jwt, auth_rules)?;
+
+        if args.access_log.is_none() && (args.access_log_format.is_some() || args.access_log_max_size.is_some() || args.access_log_max_files.is_some()) {
+            return Err("The access log options require --access-log".to_string());
+        }
+
+        let access_log = args.access_log.map(|path| AccessLogParams {
+            path: (path != "-").then(|| PathBuf::from(path)),
+            format: args.access_log_format.unwrap_or(LogFormat::Combined),
+            max_size: args.access_log_max_size.unwrap_or(100).max(1) * 1024 * 1024,
+            max_files: args.access_log_max_files.unwrap_or(5),
+        });
+
+        let mut proxy_protocol = Vec::new();
+        for block in &args.proxy_protocol {
+            proxy_protocol.push(Cidr::from_str(block).ok_or_else(|| format!("Invalid --proxy-protocol block (expected IP or IP/LEN): {}", block))?);
+        }
+
+        let mut trusted_proxies = Vec::new();
+        for block in &args.trusted_proxy {
+            trusted_proxies.push(Cidr::from_str(block).ok_or_else(|| format!("Invalid --trusted-proxy block (expected IP or IP/LEN): {}", block))?);
+        }
+
+        let metrics_path = args.metrics_path.unwrap_or("/metrics".to_string());
+        if !metrics_path.starts_with('/') {
+            return Err("The metrics path must start with /".to_string());
+        }
+
+        if args.metrics_port.is_some_and(|p| tcp_ports.contains(&p) || Some(p) == args.https_redirect_port) {
+            return Err("The metrics port must differ from the other ports".to_string());
+        }
+        if args.metrics_port.is_some() && tcp_ports.is_empty() {
+            return Err("--metrics-port requires a TCP listen address".to_string());
         }
 
         Ok(ServerParams{
-            address,
-            port,
-            nb_threads: num_threads,
+            listen,
+            pool,
+            runtime,
+            shutdown_timeout: Duration::from_secs(args.shutdown_timeout.unwrap_or(30)),
+            keep_alive_timeout: Duration::from_secs(args.keep_alive_timeout.unwrap_or(60).max(1)),
+            tls,
+            config_file: None,
+            compression,
+            files_root,
+            vhosts,
+            default_vhost,
+            upstream,
+            cgi,
+            files_write_client_cert,
+            files_write_allowed: args.files_write_allowed,
+            files_prefix_claim: args.files_prefix_claim,
+            auth,
+            proxy_protocol,
+            trusted_proxies,
+            metrics: MetricsParams { path: metrics_path, port: args.metrics_port },
+            log_level: args.log_level.unwrap_or(LogLevel::Info),
+            access_log,
         })
     }
 
-    /// Check if the given address is valid.
-    fn check_address(address: &str) -> bool {
-        // Check if the address is a valid IP address or hostname
-        // This is a simple check, you might want to use a more robust method
-        address.parse::<std::net::IpAddr>().is_ok() || address.parse::<std::net::SocketAddr>().is_ok()
+    /// Get the options resulting in the given parameters, defaults included.
+    fn effective(params: &ServerParams) -> Args {
+        let tls = params.tls.as_ref();
+        let cert_paths = |paths: &CertPaths| format!("{},{}", paths.cert.display(), paths.key.display());
+        let access_log = params.access_log.as_ref();
+        let jwt = params.auth.jwt.as_ref();
+
+        Args {
+            listen: params.listen.iter().map(ListenAddress::to_string).collect(),
+            max_thread_num: Some(params.pool.max_threads),
+            min_threads: Some(params.pool.min_threads),
+            accept_queue: Some(params.pool.accept_queue),
+            thread_idle_timeout: Some(params.pool.idle_timeout.as_secs()),
+            runtime: Some(params.runtime),
+            shutdown_timeout: Some(params.shutdown_timeout.as_secs()),
+            keep_alive_timeout: Some(params.keep_alive_timeout.as_secs()),
+            tls_cert: tls.and_then(|t| t.default_cert.as_ref()).map(|c| c.cert.clone()),
+            tls_key: tls.and_then(|t| t.default_cert.as_ref()).map(|c| c.key.clone()),
+            tls_sni: tls
+                .map(|t| {
+                    // Certificates of the virtual hosts are listed with them
+                    t.sni_certs
+                        .iter()
+                        .filter(|(host, paths)| !params.vhosts.iter().any(|v| &v.name == host && v.cert.as_ref() == Some(paths)))
+                        .map(|(host, paths)| format!("{}={}", host, cert_paths(paths)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            tls_reload_interval: tls.map(|t| t.reload_interval.as_secs()),
+            https_redirect_port: tls.and_then(|t| t.redirect_port),
+            tls_client_ca: tls.and_then(|t| t.client_ca.clone()),
+            tls_client_auth: tls.map(|t| t.client_auth),
+            compression: Some(params.compression),
+            directory: Some(params.files_root.clone()),
+            vhost: params.vhosts.iter().map(VirtualHost::to_string).collect(),
+            default_vhost: params.default_vhost.clone(),
+            proxy_route: params.upstream.routes.iter().map(ProxyRoute::to_string).collect(),
+            upstream_group: params.upstream.groups.iter().map(UpstreamGroup::to_string).collect(),
+            proxy_connect_timeout: Some(params.upstream.connect_timeout.as_secs()),
+            proxy_timeout: Some(params.upstream.timeout.as_secs()),
+            cgi: params.cgi.routes.iter().filter(|r| matches!(r.handler, CgiHandler::Programs(_))).map(CgiRoute::to_string).collect(),
+            fastcgi: params.cgi.routes.iter().filter(|r| matches!(r.handler, CgiHandler::FastCgi { .. })).map(CgiRoute::to_string).collect(),
+            cgi_timeout: Some(params.cgi.timeout.as_secs()),
+            files_write_client_cert: params.files_write_client_cert,
+            files_write_allowed: params.files_write_allowed.clone(),
+            htpasswd: params.auth.htpasswd.clone(),
+            auth_tokens: params.auth.tokens_file.clone(),
+            auth_rule: params.auth.rules.iter().map(AuthRule::to_string).collect(),
+            auth_realm: Some(params.auth.realm.clone()),
+            jwt_secret: jwt.and_then(|j| j.secret_file.clone()),
+            jwt_key: jwt.and_then(|j| j.key_file.clone()),
+            jwt_issuer: jwt.and_then(|j| j.issuer.clone()),
+            jwt_audience: jwt.map(|j| j.audience.clone()).unwrap_or_default(),
+            jwt_leeway: jwt.map(|j| j.leeway),
+            jwt_user_claim: jwt.map(|j| j.user_claim.clone()),
+            files_prefix_claim: params.files_prefix_claim.clone(),
+            proxy_protocol: params.proxy_protocol.iter().map(Cidr::to_string).collect(),
+            trusted_proxy: params.trusted_proxies.iter().map(Cidr::to_string).collect(),
+            metrics_path: Some(params.metrics.path.clone()),
+            metrics_port: params.metrics.port,
+            log_level: Some(params.log_level),
+            access_log: access_log.map(|a| a.path.as_ref().map_or("-".to_string(), |p| p.display().to_string())),
+            access_log_format: access_log.map(|a| a.format),
+            access_log_max_size: access_log.map(|a| a.max_size / (1024 * 1024)),
+            access_log_max_files: access_log.map(|a| a.max_files),
+            ..Args::default()
+        }
+    }
+
+    /// Check if the given address is an IP address or a known hostname.
+    ///
+    /// `localhost` and the names of the hosts file are resolved without DNS,
+    /// other names through the system resolver. Returns every IP address the
+    /// name resolves to, without the IPv6 ones when IPv6 is disabled on the
+    /// host, as long as an IPv4 one is left.
+    fn check_address(address: &str) -> Result<Vec<IpAddr>, String> {
+        if let Ok(ip) = address.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        let name = address.trim_end_matches('.').to_ascii_lowercase();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+            return Err(format!("Invalid address: {}", address));
+        }
+
+        // Names under localhost always resolve to the loopback (RFC 6761)
+        let mut ips = if name == "localhost" || name.ends_with(".localhost") {
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)]
+        } else {
+            let hosts = hosts_file_lookup(&name);
+            if hosts.is_empty() {
+                let resolved = (name.as_str(), 0)
+                    .to_socket_addrs()
+                    .map_err(|e| format!("Cannot resolve address {}: {}", address, e))?;
+                let mut ips = Vec::new();
+                for ip in resolved.map(|a| a.ip()) {
+                    if !ips.contains(&ip) {
+                        ips.push(ip);
+                    }
+                }
+                ips
+            } else {
+                hosts
+            }
+        };
+        if ips.is_empty() {
+            return Err(format!("Cannot resolve address {}: no IP address found", address));
+        }
+
+        // Binding the IPv6 addresses would fail the startup on hosts without IPv6
+        if ips.iter().any(IpAddr::is_ipv4) && !ipv6_available() {
+            ips.retain(IpAddr::is_ipv4);
+        }
+        Ok(ips)
+    }
+}
+
+/// Check if the host supports IPv6, by binding a socket to its loopback address.
+fn ipv6_available() -> bool {
+    UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_ok()
+}
+
+/// Get the IP addresses of a name in the hosts file, in the order of the file.
+///
+/// # Arguments
+///
+/// * `name` - Lowercase hostname to look up.
+fn hosts_file_lookup(name: &str) -> Vec<IpAddr> {
+    let content = fs::read_to_string(HOSTS_FILE).unwrap_or_default();
+
+    let mut ips = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+            Some(ip) => ip,
+            None => continue,
+        };
+        if fields.any(|alias| alias.trim_end_matches('.').eq_ignore_ascii_case(name)) && !ips.contains(&ip) {
+            ips.push(ip);
+        }
     }
+    ips
+}
+
+/// Tells serde to leave out flags that are not set.
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 /// Parameters for the server.
 pub struct ServerParams{
-    address: String,
-    port: u32,
-    pub nb_threads: usize,
+    listen: Vec<ListenAddress>,
+    pub pool: PoolParams,
+    pub runtime: Runtime,
+    pub shutdown_timeout: Duration,
+    pub keep_alive_timeout: Duration,
+    pub tls: Option<TlsParams>,
+    pub config_file: Option<PathBuf>,
+    pub compression: CompressionPolicy,
+    pub files_root: PathBuf,
+    pub vhosts: Vec<VirtualHost>,
+    pub default_vhost: Option<String>,
+    pub upstream: UpstreamParams,
+    pub cgi: CgiParams,
+    pub files_write_client_cert: bool,
+    pub files_write_allowed: Vec<String>,
+    pub files_prefix_claim: Option<String>,
+    pub auth: AuthParams,
+    pub proxy_protocol: Vec<Cidr>,
+    pub trusted_proxies: Vec<Cidr>,
+    pub metrics: MetricsParams,
+    pub log_level: LogLevel,
+    pub access_log: Option<AccessLogParams>,
 }
 
+/// Parameters of the running server, replaced when the configuration is reloaded.
+static SERVER_PARAMS: RwLock<Option<Arc<ServerParams>>> = RwLock::new(None);
+
 impl ServerParams {
-    /// Returns the address and port of the server as a string.
-    pub fn get_address(&self) -> String {
-        format!("{}:{}", self.address, self.port)
+    /// Makes the parameters available to the whole server.
+    ///
+    /// Requests being served keep the parameters they already got, the
+    /// following ones get the new parameters.
+    ///
+    /// Returns the shared parameters, later available through `ServerParams::current`.
+    pub fn install(self) -> Arc<ServerParams> {
+        let params = Arc::new(self);
+        let mut current = match SERVER_PARAMS.write() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *current = Some(params.clone());
+        params
+    }
+
+    /// Returns the parameters of the running server.
+    pub fn current() -> Arc<ServerParams> {
+        let current = match SERVER_PARAMS.read() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        current.clone().expect("server parameters are installed at startup")
     }
-}
\ No newline at end of file
+
+    /// Keep the parameters that cannot change without a restart.
+    ///
+    /// Listeners, workers, TLS and logging are set up once at startup, so
+    /// only the other parameters of a reloaded configuration are applied.
+    ///
+    /// Returns the parameters to install and the names of the options whose
+    /// change was ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `running` - Parameters of the running server.
+    pub fn reloaded(self, running: &ServerParams) -> (ServerParams, Vec<&'static str>) {
+        let mut ignored = Vec::new();
+        if self.listen != running.listen {
+            ignored.push("listen");
+        }
+        if self.pool != running.pool {
+            ignored.push("worker pool");
+        }
+        if self.runtime != running.runtime {
+            ignored.push("runtime");
+        }
+        if self.shutdown_timeout != running.shutdown_timeout {
+            ignored.push("shutdown-timeout");
+        }
+        if self.tls != running.tls {
+            ignored.push("tls");
+        }
+        if self.metrics.port != running.metrics.port {
+            ignored.push("metrics-port");
+        }
+        if self.log_level != running.log_level {
+            ignored.push("log-level");
+        }
+        if self.access_log != running.access_log {
+            ignored.push("access-log");
+        }
+
+        let params = ServerParams {
+            listen: running.listen.clone(),
+            pool: running.pool.clone(),
+            runtime: running.runtime,
+            shutdown_timeout: running.shutdown_timeout,
+            tls: running.tls.clone(),
+            metrics: MetricsParams { port: running.metrics.port, ..self.metrics },
+            log_level: running.log_level,
+            access_log: running.access_log.clone(),
+            ..self
+        };
+        (params, ignored)
+    }
+
+    /// Returns the addresses the server listens on.
+    pub fn get_addresses(&self) -> &[ListenAddress] {
+        &self.listen
+    }
+
+    /// Returns the port of the first TCP address of the server.
+    pub fn get_port(&self) -> Option<u16> {
+        self.listen.iter().find_map(ListenAddress::port)
+    }
+
+    /// Returns the addresses of the HTTPS redirect listeners, if enabled.
+    ///
+    /// The redirect port is bound on the IP of each TCP address of the server.
+    pub fn get_redirect_addresses(&self) -> Vec<ListenAddress> {
+        match self.tls.as_ref().and_then(|t| t.redirect_port) {
+            Some(port) => self.with_port(port),
+            None => Vec::new(),
+        }
+    }
+
+    /// Get the addresses of the admin listeners serving the metrics, if enabled.
+    ///
+    /// The admin port is bound on the IP of each TCP address of the server.
+    pub fn get_admin_addresses(&self) -> Vec<ListenAddress> {
+        match self.metrics.port {
+            Some(port) => self.with_port(port),
+            None => Vec::new(),
+        }
+    }
+
+    /// Get the TCP addresses of the server with another port, without duplicates.
+    fn with_port(&self, port: u16) -> Vec<ListenAddress> {
+        let mut addresses = Vec::new();
+        for address in self.listen.iter().filter_map(|a| a.with_port(port)) {
+            if !addresses.contains(&address) {
+                addresses.push(address);
+            }
+        }
+        addresses
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_blocks_contain_their_addresses() {
+        let cases = [
+            ("10.0.0.0/8", "10.255.1.2", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("192.168.1.7", "192.168.1.7", true),
+            ("192.168.1.7", "192.168.1.8", false),
+            ("192.168.1.7/24", "192.168.1.200", true),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "2001:db8::1", false),
+            ("2001:db8::/32", "2001:db8:ffff::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("::/0", "::1", true),
+            // IPv4 addresses mapped to IPv6 match IPv4 blocks only
+            ("10.0.0.0/8", "::ffff:10.1.2.3", true),
+            ("::ffff:0:0/96", "::ffff:10.1.2.3", false),
+            ("::1", "127.0.0.1", false),
+        ];
+        for (block, ip, expected) in cases {
+            let cidr = Cidr::from_str(block).expect(block);
+            assert_eq!(cidr.contains(&ip.parse().unwrap()), expected, "{} in {}", ip, block);
+        }
+    }
+
+    #[test]
+    fn malformed_cidr_blocks() {
+        let cases = ["", "10.0.0.0/", "10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/-1", "10.0.0.0/8/8", "example.com", " 10.0.0.0/8"];
+        for block in cases {
+            assert_eq!(Cidr::from_str(block), None, "{}", block);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! vhost.rs
+//!
+//! Virtual hosts, to serve several sites from one server.
+//! Each request is answered by the site named by its `Host` header, with its
+//! own files directory, routes, compression and certificate. Names can be
+//! exact (`example.com`) or cover every subdomain (`*.example.com`).
+//!
+//! Requests for other hosts are answered by the default virtual host if one
+//! is chosen, otherwise by the server-wide options.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::request::Request;
+use crate::tls::CertPaths;
+use crate::utils::{CompressionPolicy, ServerParams};
+
+/// Routes that can be enabled per virtual host, with the pattern of their targets.
+///
+/// The health checks and the metrics endpoint belong to the server and are always served.
+/// So do the `--proxy-route`, `--cgi` and `--fastcgi` prefixes: they are server-wide,
+/// matched before the virtual host is consulted, and answered for every host.
+pub const ROUTES: &[(&str, &str)] = &[
+    ("root", "/"),
+    ("hello", "/hello"),
+    ("echo", "/echo/{string}"),
+    ("user-agent", "/user-agent"),
+    ("files", "/files/{filename}"),
+    ("ws-echo", "/ws/echo"),
+    ("ws-tail", "/ws/tail/{filename}"),
+    ("sse-tail", "/sse/tail/{filename}"),
+];
+
+/// Site served for a host name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VirtualHost {
+    /// Host name, lowercase, or `*.` followed by a parent domain
+    pub name: String,
+
+    /// Directory served by the `/files/` endpoint
+    pub files_root: PathBuf,
+
+    /// Names of the routes served, from `ROUTES`
+    pub routes: Vec<String>,
+
+    /// Compression of the response bodies
+    pub compression: CompressionPolicy,
+
+    /// Certificate selected by SNI for the name
+    pub cert: Option<CertPaths>,
+}
+
+impl VirtualHost {
+    /// Parse a virtual host entry, e.g. `example.com,root=/srv/example,routes=root|files,compression=off`.
+    ///
+    /// Settings missing from the entry are taken from the server-wide options.
+    ///
+    /// # Arguments
+    ///
+    /// * `entry` - The host name followed by its settings, separated by commas.
+    /// * `files_root` - Files directory of the server.
+    /// * `compression` - Compression of the server.
+    pub fn parse(entry: &str, files_root: &Path, compression: CompressionPolicy) -> Result<VirtualHost, String> {
+        let invalid = |reason: &str| format!("Invalid --vhost entry {}: {}", entry, reason);
+
+        let mut settings = entry.split(',');
+        let name = settings.next().unwrap_or_default().trim().trim_end_matches('.').to_ascii_lowercase();
+        let domain = name.strip_prefix("*.").unwrap_or(&name);
+        if domain.is_empty() || !domain.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+            return Err(invalid("expected a host name or *.DOMAIN first"));
+        }
+
+        let mut vhost = VirtualHost {
+            name,
+            files_root: files_root.to_path_buf(),
+            routes: ROUTES.iter().map(|(route, _)| route.to_string()).collect(),
+            compression,
+            cert: None,
+        };
+        let (mut cert, mut key) = (None, None);
+
+        for setting in settings {
+            let (key_name, value) = setting.split_once('=').ok_or_else(|| invalid("expected KEY=VALUE settings"))?;
+            match key_name.trim() {
+                "root" => vhost.files_root = PathBuf::from(value),
+                "routes" => {
+                    vhost.routes = value.split('|').filter(|r| !r.is_empty()).map(str::to_string).collect();
+                    if let Some(unknown) = vhost.routes.iter().find(|r| !ROUTES.iter().any(|(route, _)| route == r)) {
+                        return Err(invalid(&format!("unknown route {}", unknown)));
+                    }
+                }
+                "compression" => {
+                    vhost.compression = match value {
+                        "gzip" => CompressionPolicy::Gzip,
+                        "off" => CompressionPolicy::Off,
+                        _ => return Err(invalid("compression must be gzip or off")),
+                    }
+                }
+                "tls-cert" => cert = Some(PathBuf::from(value)),
+                "tls-key" => key = Some(PathBuf::from(value)),
+                other => return Err(invalid(&format!("unknown setting {}", other))),
+            }
+        }
+
+        vhost.cert = match (cert, key) {
+            (Some(cert), Some(key)) => Some(CertPaths { cert, key }),
+            (None, None) => None,
+            _ => return Err(invalid("tls-cert and tls-key must be given together")),
+        };
+        Ok(vhost)
+    }
+
+    /// Check if the virtual host is named after `host`, a lowercase name without port.
+    fn matches(&self, host: &str) -> bool {
+        match self.name.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => self.name == host,
+        }
+    }
+
+    /// Check if the virtual host serves the route of a target pattern, as given by `Response::route`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - Pattern of the route (e.g. "/echo/{string}").
+    pub fn serves(&self, pattern: &str) -> bool {
+        match ROUTES.iter().find(|(_, p)| *p == pattern) {
+            Some((route, _)) => self.routes.iter().any(|r| r == route),
+            None => true,
+        }
+    }
+}
+
+impl fmt::Display for VirtualHost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let compression = match self.compression {
+            CompressionPolicy::Gzip => "gzip",
+            CompressionPolicy::Off => "off",
+        };
+        write!(f, "{},root={},routes={},compression={}", self.name, self.files_root.display(), self.routes.join("|"), compression)?;
+        if let Some(cert) = &self.cert {
+            write!(f, ",tls-cert={},tls-key={}", cert.cert.display(), cert.key.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the virtual host answering a request.
+///
+/// Exact names are preferred to wildcards, and longer wildcards to shorter
+/// ones. Without a match, the default virtual host or the server-wide
+/// options answer the request.
+///
+/// # Arguments
+///
+/// * `request` - The incoming HTTP request.
+pub fn select(request: &Request) -> VirtualHost {
+    let params = ServerParams::current();
+    let host = request.host().map(|h| normalize(&h)).unwrap_or_default();
+
+    let matched = params
+        .vhosts
+        .iter()
+        .filter(|vhost| vhost.matches(&host))
+        .max_by_key(|vhost| (!vhost.name.starts_with("*."), vhost.name.len()));
+
+    let default = || params.default_vhost.as_ref().and_then(|name| params.vhosts.iter().find(|vhost| &vhost.name == name));
+
+    match matched.or_else(default) {
+        Some(vhost) => vhost.clone(),
+        None => VirtualHost {
+            name: host,
+            files_root: params.files_root.clone(),
+            routes: ROUTES.iter().map(|(route, _)| route.to_string()).collect(),
+            compression: params.compression,
+            cert: None,
+        },
+    }
+}
+
+/// Lowercase a host and remove its port and trailing dot (e.g. "Example.COM.:8080" to "example.com").
+fn normalize(host: &str) -> String {
+    let name = match host.strip_prefix('[') {
+        // IPv6 literals keep their brackets
+        Some(rest) => rest.split_once(']').map_or(host.to_string(), |(ip, _)| format!("[{}]", ip)),
+        None => host.split(':').next().unwrap_or_default().to_string(),
+    };
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,546 @@
+//! websocket.rs
+//!
+//! WebSocket support of the server (RFC 6455).
+//! Routes answer with `upgrade`, which validates the opening handshake and
+//! takes over the connection once the `101 Switching Protocols` response is sent.
+//!
+//! The `WebSocket` given to the route reassembles fragmented messages, answers
+//! pings, runs the close handshake and compresses messages with
+//! permessage-deflate (RFC 7692) when the client offers it.
+
+use std::io::{self, ErrorKind, Write};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
+use sha1::{Digest, Sha1};
+
+use crate::connection::Connection;
+use crate::request::Request;
+use crate::response::Response;
+use crate::shutdown;
+use crate::utils::{HTTPMethod, HTTPVersion, MAX_REQUEST_SIZE};
+
+/// Appended to the client key to compute `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Only supported version of the protocol.
+const VERSION: &str = "13";
+
+// Opcodes
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+// Close codes
+const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+pub const INTERNAL_ERROR: u16 = 1011;
+
+/// Trailer removed from compressed messages and restored before inflating them.
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Time given to the client to answer a close frame sent by the server.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A complete message received from or sent to the client.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Result of waiting on a WebSocket.
+#[derive(Debug)]
+pub enum Event {
+    /// The client sent a complete message
+    Message(Message),
+
+    /// Nothing was received for a while, lets routes do periodic work
+    Idle,
+
+    /// The connection is closed, nothing can be sent anymore
+    Closed,
+}
+
+/// A frame received from the client.
+struct Frame {
+    fin: bool,
+    compressed: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// A WebSocket connection with a client.
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Connection,
+
+    /// Bytes received but not yet consumed as frames
+    buffer: Vec<u8>,
+
+    /// Whether permessage-deflate was negotiated
+    deflate: bool,
+
+    /// Opcode, compression flag and payload of a fragmented message being received
+    fragments: Option<(u8, bool, Vec<u8>)>,
+
+    /// Moment the server sent its close frame
+    close_sent: Option<Instant>,
+
+    /// Set once the close handshake finished or the connection failed
+    closed: bool,
+}
+
+/// Answer a WebSocket opening handshake.
+///
+/// If the request is a valid handshake, the response switches protocols and
+/// `handler` is then called with the connection. Otherwise the response is an error.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP/1.1 request asking for the upgrade.
+/// * `handler` - Serves the WebSocket once the handshake is complete.
+pub fn upgrade<H>(request: Request, handler: H) -> Response
+where
+    H: FnOnce(&mut WebSocket) + Send + 'static,
+{
+    let header = |name: &str| {
+        request
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+
+    if request.method != HTTPMethod::GET
+        || !matches!(request.http_version, HTTPVersion::Http1_1)
+        || !has_token("Upgrade", "websocket")
+        || !has_token("Connection", "upgrade")
+    {
+        return Response::new(request, None, 400, None);
+    }
+
+    // Ask the client to retry with the supported version
+    if header("Sec-WebSocket-Version").as_deref().map(str::trim) != Some(VERSION) {
+        let mut response = Response::new(request, None, 426, None);
+        response.headers.push(("Sec-WebSocket-Version".to_string(), VERSION.to_string()));
+        return response;
+    }
+
+    // The key must be 16 random bytes encoded in base64
+    let key = match header("Sec-WebSocket-Key") {
+        Some(k) if STANDARD.decode(k.trim()).is_ok_and(|k| k.len() == 16) => k.trim().to_string(),
+        _ => return Response::new(request, None, 400, None),
+    };
+
+    let deflate = header("Sec-WebSocket-Extensions").is_some_and(|e| accepts_deflate(&e));
+
+    let mut response = Response::new(request, None, 101, None);
+    response.headers.retain(|(k, _)| k != "Content-Length" && k != "Connection");
+    response.headers.push(("Upgrade".to_string(), "websocket".to_string()));
+    response.headers.push(("Connection".to_string(), "Upgrade".to_string()));
+    response.headers.push(("Sec-WebSocket-Accept".to_string(), accept_key(&key)));
+    if deflate {
+        response.headers.push((
+            "Sec-WebSocket-Extensions".to_string(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover".to_string(),
+        ));
+    }
+
+    response.take_over(move |stream, buffer| {
+        let mut websocket = WebSocket {
+            stream,
+            buffer,
+            deflate,
+            fragments: None,
+            close_sent: None,
+            closed: false,
+        };
+        handler(&mut websocket);
+
+        // Close properly if the handler returned with the connection still open
+        websocket.close(NORMAL_CLOSURE, "");
+    })
+}
+
+/// Compute the `Sec-WebSocket-Accept` value of a client key.
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Check if one of the extensions offered by the client is a usable permessage-deflate.
+///
+/// Each message is compressed without context takeover, and the server always
+/// uses the largest window, so offers restricting the server window are declined.
+fn accepts_deflate(extensions: &str) -> bool {
+    extensions.split(',').any(|offer| {
+        let mut params = offer.split(';').map(str::trim);
+        params.next() == Some("permessage-deflate")
+            && params.all(|p| {
+                let (name, value) = p.split_once('=').map_or((p, None), |(n, v)| (n.trim(), Some(v.trim().trim_matches('"'))));
+                match name {
+                    "server_no_context_takeover" | "client_no_context_takeover" | "client_max_window_bits" => true,
+                    "server_max_window_bits" => value == Some("15"),
+                    _ => false,
+                }
+            })
+    })
+}
+
+/// Echo every message back to the client.
+///
+/// # Arguments
+///
+/// * `websocket` - The client's WebSocket.
+pub fn echo(websocket: &mut WebSocket) {
+    loop {
+        match websocket.next() {
+            Ok(Event::Message(message)) => {
+                if websocket.send(&message).is_err() {
+                    return;
+                }
+            }
+            Ok(Event::Idle) => {}
+            Ok(Event::Closed) | Err(_) => return,
+        }
+    }
+}
+
+impl WebSocket<'_> {
+    /// Wait for the next message from the client.
+    ///
+    /// Control frames are handled internally. Returns `Event::Idle` when the
+    /// connection stays quiet for a while, so handlers can do periodic work.
+    /// Once the server is shutting down, the connection is closed with
+    /// "going away".
+    pub fn next(&mut self) -> io::Result<Event> {
+        loop {
+            if self.closed {
+                return Ok(Event::Closed);
+            }
+
+            if let Some(frame) = self.read_frame() {
+                if let Some(event) = self.handle_frame(frame)? {
+                    return Ok(event);
+                }
+                continue;
+            }
+
+            let mut chunk = [0; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                    if shutdown::is_requested() && self.close_sent.is_none() {
+                        self.close(GOING_AWAY, "Server shutting down");
+                    }
+
+                    // Give up on clients that do not answer the close frame
+                    if self.close_sent.is_some_and(|at| at.elapsed() >= CLOSE_TIMEOUT) {
+                        self.closed = true;
+                        continue;
+                    }
+                    return Ok(Event::Idle);
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self.closed = true;
+                }
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Send a message to the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The message to send.
+    pub fn send(&mut self, message: &Message) -> io::Result<()> {
+        if self.close_sent.is_some() || self.closed {
+            return Err(io::Error::new(ErrorKind::NotConnected, "WebSocket is closing"));
+        }
+
+        let (opcode, payload) = match message {
+            Message::Text(text) => (TEXT, text.as_bytes()),
+            Message::Binary(data) => (BINARY, data.as_slice()),
+        };
+
+        if self.deflate {
+            let compressed = compress(payload)?;
+            return self.write_frame(opcode, true, &compressed);
+        }
+        self.write_frame(opcode, false, payload)
+    }
+
+    /// Start the close handshake.
+    ///
+    /// Nothing is sent if the connection is already closing.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - Status code explaining why the connection is closed.
+    /// * `reason` - Short explanation for the client.
+    pub fn close(&mut self, code: u16, reason: &str) {
+        if self.close_sent.is_some() || self.closed {
+            return;
+        }
+        self.close_sent = Some(Instant::now());
+
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        if self.write_frame(CLOSE, false, &payload).is_err() {
+            self.closed = true;
+        }
+    }
+
+    /// Parse the next frame held in the buffer.
+    ///
+    /// Returns `None` until a complete frame has been received.
+    fn read_frame(&mut self) -> Option<Frame> {
+        if self.buffer.len() < 2 {
+            return None;
+        }
+
+        let (first, second) = (self.buffer[0], self.buffer[1]);
+        let mut offset = 2;
+        let len = match second & 0x7f {
+            126 => {
+                if self.buffer.len() < 4 {
+                    return None;
+                }
+                offset = 4;
+                u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as u64
+            }
+            127 => {
+                if self.buffer.len() < 10 {
+                    return None;
+                }
+                offset = 10;
+                u64::from_be_bytes(self.buffer[2..10].try_into().unwrap_or_default())
+            }
+            n => n as u64,
+        };
+
+        // Frames sent by clients must be masked
+        if second & 0x80 == 0 {
+            self.fail(PROTOCOL_ERROR, "Unmasked frame");
+            return None;
+        }
+        if len > MAX_REQUEST_SIZE as u64 {
+            self.fail(MESSAGE_TOO_BIG, "Message too big");
+            return None;
+        }
+
+        let len = len as usize;
+        if self.buffer.len() < offset + 4 + len {
+            return None;
+        }
+
+        let mask = [self.buffer[offset], self.buffer[offset + 1], self.buffer[offset + 2], self.buffer[offset + 3]];
+        let mut payload = self.buffer[offset + 4..offset + 4 + len].to_vec();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        self.buffer.drain(..offset + 4 + len);
+
+        // RSV1 marks compressed messages, the other reserved bits are never used
+        if first & 0x30 != 0 || (first & 0x40 != 0 && !self.deflate) {
+            self.fail(PROTOCOL_ERROR, "Reserved bits set");
+            return None;
+        }
+
+        Some(Frame {
+            fin: first & 0x80 != 0,
+            compressed: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            payload,
+        })
+    }
+
+    /// Handle a frame sent by the client.
+    ///
+    /// Returns the event to report, if the frame completes a message or closes the connection.
+    fn handle_frame(&mut self, frame: Frame) -> io::Result<Option<Event>> {
+        // Control frames are never fragmented nor compressed
+        let control = matches!(frame.opcode, CLOSE | PING | PONG);
+        if control && (!frame.fin || frame.compressed || frame.payload.len() > 125) {
+            self.fail(PROTOCOL_ERROR, "Invalid control frame");
+            return Ok(Some(Event::Closed));
+        }
+
+        match frame.opcode {
+            PING => {
+                if self.close_sent.is_none() {
+                    self.write_frame(PONG, false, &frame.payload)?;
+                }
+                Ok(None)
+            }
+            PONG => Ok(None),
+            CLOSE => {
+                // Answer with the same code, unless the server started the handshake
+                if self.close_sent.is_none() {
+                    let payload = frame.payload.get(..2).unwrap_or_default().to_vec();
+                    let _ = self.write_frame(CLOSE, false, &payload);
+                }
+                self.closed = true;
+                Ok(Some(Event::Closed))
+            }
+            TEXT | BINARY => {
+                if self.fragments.is_some() {
+                    self.fail(PROTOCOL_ERROR, "Expected a continuation frame");
+                    return Ok(Some(Event::Closed));
+                }
+                if frame.fin {
+                    return self.complete(frame.opcode, frame.compressed, frame.payload);
+                }
+                self.fragments = Some((frame.opcode, frame.compressed, frame.payload));
+                Ok(None)
+            }
+            CONTINUATION => {
+                let (opcode, compressed, mut payload) = match self.fragments.take() {
+                    Some(fragments) if !frame.compressed => fragments,
+                    _ => {
+                        self.fail(PROTOCOL_ERROR, "Unexpected continuation frame");
+                        return Ok(Some(Event::Closed));
+                    }
+                };
+
+                payload.extend_from_slice(&frame.payload);
+                if payload.len() > MAX_REQUEST_SIZE {
+                    self.fail(MESSAGE_TOO_BIG, "Message too big");
+                    return Ok(Some(Event::Closed));
+                }
+
+                if frame.fin {
+                    return self.complete(opcode, compressed, payload);
+                }
+                self.fragments = Some((opcode, compressed, payload));
+                Ok(None)
+            }
+            _ => {
+                self.fail(PROTOCOL_ERROR, "Unknown opcode");
+                Ok(Some(Event::Closed))
+            }
+        }
+    }
+
+    /// Build a message from its complete payload.
+    fn complete(&mut self, opcode: u8, compressed: bool, payload: Vec<u8>) -> io::Result<Option<Event>> {
+        let payload = if compressed {
+            match decompress(&payload) {
+                Some(p) => p,
+                None => {
+                    self.fail(INVALID_PAYLOAD, "Invalid compressed message");
+                    return Ok(Some(Event::Closed));
+                }
+            }
+        } else {
+            payload
+        };
+
+        // Messages received after the server started closing are dropped
+        if self.close_sent.is_some() {
+            return Ok(None);
+        }
+
+        if opcode == BINARY {
+            return Ok(Some(Event::Message(Message::Binary(payload))));
+        }
+
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Some(Event::Message(Message::Text(text)))),
+            Err(_) => {
+                self.fail(INVALID_PAYLOAD, "Invalid UTF-8 in text message");
+                Ok(Some(Event::Closed))
+            }
+        }
+    }
+
+    /// Close the connection after a protocol violation of the client.
+    fn fail(&mut self, code: u16, reason: &str) {
+        tracing::debug!("Closing WebSocket: {}", reason);
+        self.close(code, reason);
+        self.closed = true;
+        self.buffer.clear();
+    }
+
+    /// Write an unfragmented frame to the client.
+    fn write_frame(&mut self, opcode: u8, compressed: bool, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode | if compressed { 0x40 } else { 0 }];
+
+        // Frames sent by the server are never masked
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+/// Compress the payload of a message with permessage-deflate.
+fn compress(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(payload)?;
+    encoder.flush()?;
+
+    // A sync flush ends with an empty block that the receiver adds back
+    let mut compressed = encoder.get_ref().clone();
+    if compressed.ends_with(&DEFLATE_TRAILER) {
+        compressed.truncate(compressed.len() - DEFLATE_TRAILER.len());
+    }
+    Ok(compressed)
+}
+
+/// Decompress the payload of a message compressed with permessage-deflate.
+///
+/// Returns `None` if the data is invalid or inflates beyond the size limit.
+fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut input = payload.to_vec();
+    input.extend_from_slice(&DEFLATE_TRAILER);
+
+    let mut decompress = Decompress::new(false);
+    let mut output = Vec::with_capacity(payload.len() * 4);
+    loop {
+        let consumed = decompress.total_in() as usize;
+        if output.len() == output.capacity() {
+            output.reserve(output.len().max(4096));
+        }
+
+        let status = decompress.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync).ok()?;
+        if output.len() > MAX_REQUEST_SIZE {
+            return None;
+        }
+        if status == Status::StreamEnd || decompress.total_in() as usize == input.len() && output.len() < output.capacity() {
+            return Some(output);
+        }
+    }
+}